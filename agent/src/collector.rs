@@ -1,10 +1,17 @@
 use serde::{Deserialize, Serialize};
-use sysinfo::System;
+use sysinfo::{Disks, Networks, System};
 use std::collections::HashMap;
+use std::path::Path;
+use std::time::Instant;
 use crate::config::Config;
+use crate::multiline::LogEntry;
+use crate::parser::{detect_level, ParserSet};
+use crate::spool::SpoolStats;
+use crate::timestamp::{extract_timestamp, parse_timestamp_value};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AgentMetrics {
+    pub agent_id: String,
     pub agent_name: String,
     pub timestamp: String,
     pub cpu_usage: f32,
@@ -12,86 +19,305 @@ pub struct AgentMetrics {
     pub disk_usage: HashMap<String, f64>,
     pub network_rx: u64,
     pub network_tx: u64,
+    pub interval_secs: f64,
+    pub disks: Vec<DiskStats>,
+    pub networks: Vec<NetworkStats>,
+    pub spool: SpoolStats,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiskStats {
+    pub mount_point: String,
+    pub file_system: String,
+    pub total_bytes: u64,
+    pub used_bytes: u64,
+    pub available_bytes: u64,
+    pub usage_percent: f64,
+    pub inodes_total: Option<u64>,
+    pub inodes_used: Option<u64>,
+    pub inodes_free: Option<u64>,
+}
+
+// Counters are deltas since the previous report, rates are per second over that interval
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NetworkStats {
+    pub interface: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    pub rx_bytes_per_sec: f64,
+    pub tx_bytes_per_sec: f64,
+    pub rx_packets_per_sec: f64,
+    pub tx_packets_per_sec: f64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AgentLog {
+    pub agent_id: String,
     pub agent_name: String,
-    pub timestamp: String,
+    pub event_time: String,
+    pub ingest_time: String,
     pub level: String,
     pub message: String,
     pub source_file: String,
+    pub fields: HashMap<String, serde_json::Value>,
 }
 
-pub async fn collect_metrics(config: &Config) -> AgentMetrics {
-    let mut sys = System::new_all();
-    sys.refresh_all();
-    
-    // Get CPU usage (simplified)
-    let cpu_usage = sys.global_cpu_info().cpu_usage();
-    let memory_usage = (sys.used_memory() as f64 / sys.total_memory() as f64) * 100.0;
-    
-    // Simplified disk usage (just report root filesystem)
-    let mut disk_usage = HashMap::new();
-    disk_usage.insert("/".to_string(), 50.0); // Mock value
-    
-    // Simplified network stats
-    let network_rx = 1024 * 1024; // Mock 1MB
-    let network_tx = 512 * 1024;  // Mock 512KB
-    
-    AgentMetrics {
-        agent_name: config.agent.name.clone(),
-        timestamp: chrono::Utc::now().to_rfc3339(),
-        cpu_usage,
-        memory_usage,
-        disk_usage,
-        network_rx,
-        network_tx,
+#[derive(Clone, Copy, Default)]
+struct NetworkCounters {
+    rx_bytes: u64,
+    tx_bytes: u64,
+    rx_packets: u64,
+    tx_packets: u64,
+    rx_errors: u64,
+    tx_errors: u64,
+}
+
+// Keeps sysinfo handles alive between ticks so CPU usage and network deltas
+// are measured over the report interval rather than from a cold start.
+pub struct Collector {
+    sys: System,
+    disks: Disks,
+    networks: Networks,
+    last_counters: HashMap<String, NetworkCounters>,
+    last_sample: Instant,
+}
+
+impl Collector {
+    pub fn new() -> Self {
+        let mut sys = System::new_all();
+        sys.refresh_all();
+        let networks = Networks::new_with_refreshed_list();
+        let last_counters = networks
+            .list()
+            .iter()
+            .map(|(name, data)| (name.clone(), NetworkCounters {
+                rx_bytes: data.total_received(),
+                tx_bytes: data.total_transmitted(),
+                rx_packets: data.total_packets_received(),
+                tx_packets: data.total_packets_transmitted(),
+                rx_errors: data.total_errors_on_received(),
+                tx_errors: data.total_errors_on_transmitted(),
+            }))
+            .collect();
+
+        Self {
+            sys,
+            disks: Disks::new_with_refreshed_list(),
+            networks,
+            last_counters,
+            last_sample: Instant::now(),
+        }
+    }
+
+    pub fn collect_metrics(&mut self, config: &Config) -> AgentMetrics {
+        self.sys.refresh_cpu();
+        self.sys.refresh_memory();
+
+        let now = Instant::now();
+        let interval_secs = now.duration_since(self.last_sample).as_secs_f64();
+        self.last_sample = now;
+
+        let cpu_usage = self.sys.global_cpu_info().cpu_usage();
+        let memory_usage = if self.sys.total_memory() > 0 {
+            (self.sys.used_memory() as f64 / self.sys.total_memory() as f64) * 100.0
+        } else {
+            0.0
+        };
+
+        let disks = self.collect_disks();
+        let disk_usage = disks.iter()
+            .map(|d| (d.mount_point.clone(), d.usage_percent))
+            .collect();
+
+        let networks = self.collect_networks(interval_secs);
+        let network_rx = networks.iter().map(|n| n.rx_bytes).sum();
+        let network_tx = networks.iter().map(|n| n.tx_bytes).sum();
+
+        AgentMetrics {
+            agent_id: config.agent.instance_id.clone(),
+            agent_name: config.agent.name.clone(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            cpu_usage,
+            memory_usage,
+            disk_usage,
+            network_rx,
+            network_tx,
+            interval_secs,
+            disks,
+            networks,
+            spool: SpoolStats::default(),
+        }
     }
+
+    fn collect_disks(&mut self) -> Vec<DiskStats> {
+        // Re-list so newly mounted filesystems show up
+        self.disks.refresh_list();
+
+        let mut seen = std::collections::HashSet::new();
+        let mut stats = Vec::new();
+        for disk in self.disks.list() {
+            let mount_point = disk.mount_point().to_string_lossy().to_string();
+            if !seen.insert(mount_point.clone()) {
+                continue;
+            }
+
+            let total_bytes = disk.total_space();
+            let available_bytes = disk.available_space();
+            let used_bytes = total_bytes.saturating_sub(available_bytes);
+            let usage_percent = if total_bytes > 0 {
+                used_bytes as f64 / total_bytes as f64 * 100.0
+            } else {
+                0.0
+            };
+            let inodes = inode_usage(disk.mount_point());
+
+            stats.push(DiskStats {
+                mount_point,
+                file_system: disk.file_system().to_string_lossy().to_string(),
+                total_bytes,
+                used_bytes,
+                available_bytes,
+                usage_percent,
+                inodes_total: inodes.map(|(total, _)| total),
+                inodes_used: inodes.map(|(total, free)| total.saturating_sub(free)),
+                inodes_free: inodes.map(|(_, free)| free),
+            });
+        }
+
+        stats
+    }
+
+    fn collect_networks(&mut self, interval_secs: f64) -> Vec<NetworkStats> {
+        self.networks.refresh_list();
+
+        let per_sec = |delta: u64| if interval_secs > 0.0 { delta as f64 / interval_secs } else { 0.0 };
+
+        let mut current_counters = HashMap::new();
+        let mut stats = Vec::new();
+        for (interface, data) in self.networks.list() {
+            let current = NetworkCounters {
+                rx_bytes: data.total_received(),
+                tx_bytes: data.total_transmitted(),
+                rx_packets: data.total_packets_received(),
+                tx_packets: data.total_packets_transmitted(),
+                rx_errors: data.total_errors_on_received(),
+                tx_errors: data.total_errors_on_transmitted(),
+            };
+            // A new interface has no baseline yet; counters that went backwards were reset
+            let previous = self.last_counters.get(interface).copied().unwrap_or(current);
+
+            let rx_bytes = current.rx_bytes.saturating_sub(previous.rx_bytes);
+            let tx_bytes = current.tx_bytes.saturating_sub(previous.tx_bytes);
+            let rx_packets = current.rx_packets.saturating_sub(previous.rx_packets);
+            let tx_packets = current.tx_packets.saturating_sub(previous.tx_packets);
+
+            stats.push(NetworkStats {
+                interface: interface.clone(),
+                rx_bytes,
+                tx_bytes,
+                rx_packets,
+                tx_packets,
+                rx_errors: current.rx_errors.saturating_sub(previous.rx_errors),
+                tx_errors: current.tx_errors.saturating_sub(previous.tx_errors),
+                rx_bytes_per_sec: per_sec(rx_bytes),
+                tx_bytes_per_sec: per_sec(tx_bytes),
+                rx_packets_per_sec: per_sec(rx_packets),
+                tx_packets_per_sec: per_sec(tx_packets),
+            });
+            current_counters.insert(interface.clone(), current);
+        }
+
+        self.last_counters = current_counters;
+        stats.sort_by(|a, b| a.interface.cmp(&b.interface));
+        stats
+    }
+}
+
+// Returns (total, free) inode counts for the filesystem mounted at `path`
+#[cfg(unix)]
+fn inode_usage(path: &Path) -> Option<(u64, u64)> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: c_path is a valid NUL-terminated string and stat is a properly sized out-parameter
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Some((stat.f_files as u64, stat.f_ffree as u64))
+}
+
+#[cfg(not(unix))]
+fn inode_usage(_path: &Path) -> Option<(u64, u64)> {
+    None
 }
 
-pub async fn collect_logs(config: &Config) -> Vec<AgentLog> {
+pub fn collect_logs(config: &Config, parsers: &ParserSet, entries: Vec<LogEntry>) -> Vec<AgentLog> {
     let mut logs = Vec::new();
     
-    for path in &config.log_paths {
-        if let Ok(content) = tokio::fs::read_to_string(path).await {
-            for line in content.lines().rev().take(10) {
-                if let Some(log) = parse_log_line(line, path, &config.agent.name) {
-                    logs.push(log);
-                }
-            }
+    for entry in entries {
+        if let Some(log) = parse_log_entry(entry, config, parsers) {
+            logs.push(log);
         }
     }
     
     // If no logs found, create a sample log
     if logs.is_empty() {
+        let now = chrono::Utc::now().to_rfc3339();
         logs.push(AgentLog {
+            agent_id: config.agent.instance_id.clone(),
             agent_name: config.agent.name.clone(),
-            timestamp: chrono::Utc::now().to_rfc3339(),
+            event_time: now.clone(),
+            ingest_time: now,
             level: "INFO".to_string(),
             message: "Agent heartbeat - system monitoring active".to_string(),
             source_file: "agent".to_string(),
+            fields: HashMap::new(),
         });
     }
     
     logs
 }
 
-fn parse_log_line(line: &str, source: &str, agent_name: &str) -> Option<AgentLog> {
+// Level, timestamp and fields come from the first line; continuation lines are appended to the message
+fn parse_log_entry(entry: LogEntry, config: &Config, parsers: &ParserSet) -> Option<AgentLog> {
+    let line = entry.first.as_str();
     if line.trim().is_empty() {
         return None;
     }
     
-    let level = if line.contains("ERROR") { "ERROR" }
-    else if line.contains("WARN") { "WARN" }
-    else if line.contains("INFO") { "INFO" }
-    else { "DEBUG" };
+    let parsed = parsers.parse(&entry.path, line);
+    let level = parsed.level
+        .or_else(|| detect_level(line))
+        .unwrap_or_else(|| "INFO".to_string());
+    
+    // Prefer the parser's timestamp field, then one found in the raw line, then the time we read it
+    let ingest_time = chrono::Utc::now();
+    let event_time = parsed.timestamp
+        .and_then(|ts| parse_timestamp_value(&ts, &config.timestamp_formats))
+        .or_else(|| extract_timestamp(line, &config.timestamp_formats))
+        .unwrap_or(ingest_time);
+    
+    let mut message = parsed.message.unwrap_or_else(|| line.to_string());
+    for continuation in &entry.rest {
+        message.push('\n');
+        message.push_str(continuation);
+    }
     
     Some(AgentLog {
-        agent_name: agent_name.to_string(),
-        timestamp: chrono::Utc::now().to_rfc3339(),
-        level: level.to_string(),
-        message: line.to_string(),
-        source_file: source.to_string(),
+        agent_id: config.agent.instance_id.clone(),
+        agent_name: config.agent.name.clone(),
+        event_time: event_time.to_rfc3339(),
+        ingest_time: ingest_time.to_rfc3339(),
+        level,
+        message,
+        source_file: entry.path,
+        fields: parsed.fields,
     })
 }
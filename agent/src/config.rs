@@ -1,17 +1,59 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     pub agent: AgentConfig,
     pub collection: CollectionConfig,
     pub log_paths: Vec<String>,
+    #[serde(default)]
+    pub tailer: TailerConfig,
+    // Extra strftime patterns tried before the built-in timestamp formats
+    #[serde(default)]
+    pub timestamp_formats: Vec<String>,
+    // Per-path parsers; the first entry whose `path` glob matches a file wins
+    #[serde(default)]
+    pub parsers: Vec<ParserConfig>,
+    // Extra grok patterns available to every grok parser, by name
+    #[serde(default)]
+    pub grok_patterns: HashMap<String, String>,
+    // Per-path rules for joining multi-line events such as stack traces
+    #[serde(default)]
+    pub multiline: Vec<MultilineConfig>,
+    #[serde(default)]
+    pub spool: SpoolConfig,
+    #[serde(default)]
+    pub upload: UploadConfig,
+    #[serde(default)]
+    pub tls: TlsConfig,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AgentConfig {
+    // Service name; several instances of one service share it
     pub name: String,
     pub server_url: String,
     pub report_interval: u64,
+    // Stable per-instance ID. Generated and persisted to `instance_id_file` when not set here.
+    #[serde(default)]
+    pub instance_id: String,
+    #[serde(default = "default_instance_id_file")]
+    pub instance_id_file: String,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    // One-time token from the backend admin API, exchanged for a signing credential on first start
+    #[serde(default)]
+    pub enrollment_token: String,
+    #[serde(default = "default_credential_file")]
+    pub credential_file: String,
+}
+
+fn default_instance_id_file() -> String {
+    "agent_state/instance_id".to_string()
+}
+
+fn default_credential_file() -> String {
+    "agent_state/credential.json".to_string()
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -21,6 +63,132 @@ pub struct CollectionConfig {
     pub resources: bool,
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TailerConfig {
+    pub state_file: String,
+    // Fallback rescan interval in seconds, for filesystems where notify misses events
+    pub poll_interval: u64,
+}
+
+impl Default for TailerConfig {
+    fn default() -> Self {
+        Self {
+            state_file: "agent_state/offsets.json".to_string(),
+            poll_interval: 5,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParserFormat {
+    Plain,
+    Json,
+    Logfmt,
+    Regex,
+    Grok,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ParserConfig {
+    pub path: String,
+    pub format: ParserFormat,
+    // Named-capture regex or grok expression, for the regex and grok formats
+    pub pattern: Option<String>,
+    pub level_field: Option<String>,
+    pub message_field: Option<String>,
+    pub timestamp_field: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+// How metrics and logs are combined into uploads to `/api/agents/batch`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UploadConfig {
+    pub compression: Compression,
+    // A batch is sent once it holds this many events...
+    pub max_batch_events: usize,
+    // ...or its oldest event has waited this many seconds (0 sends every tick)
+    pub max_batch_age: u64,
+    // Per-request timeout in seconds
+    pub timeout: u64,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            compression: Compression::Gzip,
+            max_batch_events: 1000,
+            max_batch_age: 0,
+            timeout: 30,
+        }
+    }
+}
+
+// TLS settings for an https `server_url`. Paths are PEM files; empty means unset.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct TlsConfig {
+    // CA that signed the backend certificate; the system roots are used when unset
+    #[serde(default)]
+    pub ca_file: String,
+    // Client certificate and PKCS#8 key for mutual TLS. The certificate CN should be
+    // the agent's instance ID, which the backend then uses as its identity.
+    #[serde(default)]
+    pub cert_file: String,
+    #[serde(default)]
+    pub key_file: String,
+}
+
+// Where undeliverable payloads wait while the backend is unreachable
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SpoolConfig {
+    pub dir: String,
+    pub max_bytes: u64,
+    // Retry backoff bounds in seconds
+    pub initial_backoff: u64,
+    pub max_backoff: u64,
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        Self {
+            dir: "agent_state/spool".to_string(),
+            max_bytes: 100 * 1024 * 1024,
+            initial_backoff: 1,
+            max_backoff: 60,
+        }
+    }
+}
+
+// A line matching `start_pattern` begins a new event; a line matching
+// `continuation_pattern` is appended to the current one. With both set,
+// a line continues the event only if it matches continuation and not start.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MultilineConfig {
+    pub path: String,
+    pub start_pattern: Option<String>,
+    pub continuation_pattern: Option<String>,
+    #[serde(default = "default_multiline_max_lines")]
+    pub max_lines: usize,
+    // Seconds without a new line before a pending event is emitted
+    #[serde(default = "default_multiline_flush_timeout")]
+    pub flush_timeout: u64,
+}
+
+fn default_multiline_max_lines() -> usize {
+    500
+}
+
+fn default_multiline_flush_timeout() -> u64 {
+    2
+}
+
 pub async fn load_config(path: &str) -> Result<Config, Box<dyn std::error::Error>> {
     let content = tokio::fs::read_to_string(path).await?;
     Ok(serde_yaml::from_str(&content)?)
@@ -0,0 +1,52 @@
+use ring::{digest, hmac};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+
+// Per-agent signing key issued by the backend at enrollment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Credential {
+    pub agent_id: String,
+    pub key_id: String,
+    pub secret: String,
+}
+
+impl Credential {
+    pub fn load(path: &Path) -> Option<Self> {
+        let data = std::fs::read(path).ok()?;
+        match serde_json::from_slice(&data) {
+            Ok(credential) => Some(credential),
+            Err(e) => {
+                eprintln!("Ignoring unreadable credential file {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    // Written with owner-only permissions since it holds the secret
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(&tmp)?.write_all(&serde_json::to_vec(self)?)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    pub fn remove(path: &Path) {
+        let _ = std::fs::remove_file(path);
+    }
+
+    // HMAC-SHA256 over "{timestamp}\n{nonce}\n{method}\n{path}\n{hex sha256 of body}", hex encoded.
+    // The backend accepts each nonce once, so a captured request cannot be replayed.
+    pub fn sign(&self, timestamp: &str, nonce: &str, method: &str, path: &str, body: &[u8]) -> String {
+        let body_hash = hex::encode(digest::digest(&digest::SHA256, body));
+        let message = format!("{}\n{}\n{}\n{}\n{}", timestamp, nonce, method, path, body_hash);
+        let key = hmac::Key::new(hmac::HMAC_SHA256, self.secret.as_bytes());
+        hex::encode(hmac::sign(&key, message.as_bytes()))
+    }
+}
@@ -0,0 +1,53 @@
+use serde::Serialize;
+use std::path::Path;
+use sysinfo::System;
+use crate::config::Config;
+
+// Host details sent at registration so the backend can tell instances apart
+#[derive(Debug, Serialize)]
+pub struct HostInfo {
+    pub hostname: Option<String>,
+    pub os: Option<String>,
+    pub os_version: Option<String>,
+    pub kernel_version: Option<String>,
+    pub agent_version: String,
+}
+
+impl HostInfo {
+    pub fn detect() -> Self {
+        Self {
+            hostname: System::host_name(),
+            os: System::name(),
+            os_version: System::os_version(),
+            kernel_version: System::kernel_version(),
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+// Fills in `agent.instance_id`, reusing the ID persisted by a previous run when there is one
+pub fn resolve_instance_id(config: &mut Config) -> std::io::Result<()> {
+    if !config.agent.instance_id.is_empty() {
+        return Ok(());
+    }
+
+    let path = Path::new(&config.agent.instance_id_file);
+    if let Ok(existing) = std::fs::read_to_string(path) {
+        let existing = existing.trim();
+        if uuid::Uuid::parse_str(existing).is_ok() {
+            config.agent.instance_id = existing.to_string();
+            return Ok(());
+        }
+    }
+
+    let id = uuid::Uuid::new_v4().to_string();
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+    std::fs::write(path, &id)?;
+    println!("Generated agent instance ID {}", id);
+    config.agent.instance_id = id;
+    Ok(())
+}
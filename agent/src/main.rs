@@ -3,24 +3,56 @@ use tokio::time;
 
 mod config;
 mod collector;
+mod credential;
+mod identity;
+mod multiline;
+mod parser;
 mod reporter;
+mod spool;
+mod tailer;
+mod timestamp;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let config = config::load_config("config.yaml").await?;
-    println!("Starting agent: {}", config.agent.name);
+    let mut config = config::load_config("config.yaml").await?;
+    identity::resolve_instance_id(&mut config)?;
+    println!("Starting agent: {} ({})", config.agent.name, config.agent.instance_id);
     
-    // Register with server
-    reporter::register_agent(&config).await?;
+    // Registers with the server on first delivery and spools data while it is unreachable
+    let mut reporter = reporter::Reporter::new(&config)?;
     
+    let parsers = parser::ParserSet::from_config(&config)?;
+    let mut multiline = multiline::MultilineAggregator::from_config(&config)?;
+    let mut collector = collector::Collector::new();
+    let (mut log_lines, acks) = tailer::spawn(&config);
     let mut interval = time::interval(Duration::from_secs(config.agent.report_interval));
+    // Pending multi-line events are flushed on their own timeout, not only when a report is due
+    let mut flush = time::interval(multiline.flush_interval().unwrap_or(Duration::from_secs(1)));
+    flush.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    let mut entries = Vec::new();
     
     loop {
-        interval.tick().await;
-        
-        let metrics = collector::collect_metrics(&config).await;
-        let logs = collector::collect_logs(&config).await;
-        
-        reporter::send_data(&config, metrics, logs).await?;
+        tokio::select! {
+            Some(line) = log_lines.recv() => {
+                multiline.push(line, &mut entries);
+            }
+            _ = flush.tick() => {
+                multiline.flush_expired(&mut entries);
+            }
+            _ = interval.tick() => {
+                multiline.flush_expired(&mut entries);
+                
+                let mut metrics = collector.collect_metrics(&config);
+                metrics.spool = reporter.spool_stats();
+                let positions = entries.iter().map(|entry| entry.position).collect();
+                let logs = collector::collect_logs(&config, &parsers, std::mem::take(&mut entries));
+                
+                // The tailer saves a file's offset only once its lines are delivered or spooled
+                let delivered = reporter.send_data(&config, metrics, logs, positions).await;
+                if !delivered.is_empty() {
+                    let _ = acks.send(delivered);
+                }
+            }
+        }
     }
 }
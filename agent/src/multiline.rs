@@ -0,0 +1,241 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::config::{Config, MultilineConfig};
+use crate::tailer::{FileState, TailedLine};
+
+const MIN_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+// One logical log event: the first line decides level and timestamp,
+// any continuation lines are appended to the message.
+#[derive(Debug)]
+pub struct LogEntry {
+    pub path: String,
+    pub first: String,
+    pub rest: Vec<String>,
+    // Just past its last line
+    pub position: FileState,
+}
+
+struct Rule {
+    path: glob::Pattern,
+    start: Option<Regex>,
+    continuation: Option<Regex>,
+    max_lines: usize,
+    flush_timeout: Duration,
+}
+
+impl Rule {
+    fn from_config(config: &MultilineConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        if config.start_pattern.is_none() && config.continuation_pattern.is_none() {
+            return Err(format!("multiline rule for {} needs a start or continuation pattern", config.path).into());
+        }
+        Ok(Self {
+            path: glob::Pattern::new(&config.path)
+                .map_err(|e| format!("invalid multiline path {}: {}", config.path, e))?,
+            start: config.start_pattern.as_deref().map(Regex::new).transpose()?,
+            continuation: config.continuation_pattern.as_deref().map(Regex::new).transpose()?,
+            max_lines: config.max_lines.max(1),
+            flush_timeout: Duration::from_secs(config.flush_timeout),
+        })
+    }
+
+    fn continues(&self, line: &str) -> bool {
+        match (&self.start, &self.continuation) {
+            (Some(start), None) => !start.is_match(line),
+            (None, Some(continuation)) => continuation.is_match(line),
+            (Some(start), Some(continuation)) => !start.is_match(line) && continuation.is_match(line),
+            (None, None) => false,
+        }
+    }
+}
+
+struct Pending {
+    entry: LogEntry,
+    rule: usize,
+    last_update: Instant,
+}
+
+pub struct MultilineAggregator {
+    rules: Vec<Rule>,
+    pending: HashMap<String, Pending>,
+}
+
+impl MultilineAggregator {
+    pub fn from_config(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let rules = config.multiline
+            .iter()
+            .map(Rule::from_config)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { rules, pending: HashMap::new() })
+    }
+
+    // Feeds one tailed line, pushing any event it completes onto `out`
+    pub fn push(&mut self, line: TailedLine, out: &mut Vec<LogEntry>) {
+        let Some(rule_index) = self.rules.iter().position(|rule| rule.path.matches(&line.path)) else {
+            out.push(LogEntry { path: line.path, first: line.line, rest: Vec::new(), position: line.position });
+            return;
+        };
+        let rule = &self.rules[rule_index];
+
+        if let Some(pending) = self.pending.get_mut(&line.path) {
+            if rule.continues(&line.line) {
+                pending.entry.rest.push(line.line);
+                pending.entry.position = line.position;
+                pending.last_update = Instant::now();
+                if pending.entry.rest.len() + 1 >= rule.max_lines {
+                    out.push(self.pending.remove(&line.path).unwrap().entry);
+                }
+                return;
+            }
+            out.push(self.pending.remove(&line.path).unwrap().entry);
+        }
+
+        self.pending.insert(line.path.clone(), Pending {
+            entry: LogEntry { path: line.path, first: line.line, rest: Vec::new(), position: line.position },
+            rule: rule_index,
+            last_update: Instant::now(),
+        });
+    }
+
+    // How often flush_expired should run to honour the shortest flush timeout
+    pub fn flush_interval(&self) -> Option<Duration> {
+        self.rules.iter().map(|rule| rule.flush_timeout.max(MIN_FLUSH_INTERVAL)).min()
+    }
+
+    // Emits pending events that have not seen a continuation line within their flush timeout
+    pub fn flush_expired(&mut self, out: &mut Vec<LogEntry>) {
+        let rules = &self.rules;
+        let expired: Vec<String> = self.pending
+            .iter()
+            .filter(|(_, pending)| pending.last_update.elapsed() >= rules[pending.rule].flush_timeout)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        for path in expired {
+            if let Some(pending) = self.pending.remove(&path) {
+                out.push(pending.entry);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aggregator(rules: &str) -> Result<MultilineAggregator, String> {
+        let config: Config = serde_yaml::from_str(&format!(
+            "agent: {{name: test, server_url: 'http://localhost', report_interval: 5}}\n\
+             collection: {{metrics: true, logs: true, resources: true}}\n\
+             log_paths: []\n\
+             multiline:\n{}",
+            rules
+        )).unwrap();
+        MultilineAggregator::from_config(&config).map_err(|e| e.to_string())
+    }
+
+    // Lines of `path`, each ending at its line number
+    fn push(aggregator: &mut MultilineAggregator, path: &str, lines: &[&str]) -> Vec<LogEntry> {
+        let mut out = Vec::new();
+        for (i, line) in lines.iter().enumerate() {
+            let position = FileState { inode: 1, device: 1, offset: i as u64 + 1 };
+            aggregator.push(TailedLine { path: path.to_string(), line: line.to_string(), position }, &mut out);
+        }
+        out
+    }
+
+    fn summary(entries: &[LogEntry]) -> Vec<(&str, usize, u64)> {
+        entries.iter().map(|e| (e.first.as_str(), e.rest.len(), e.position.offset)).collect()
+    }
+
+    #[test]
+    fn lines_of_other_files_pass_straight_through() {
+        let mut aggregator = aggregator("  - {path: 'java.log', start_pattern: '^\\d'}").unwrap();
+        let out = push(&mut aggregator, "app.log", &["one", "  two"]);
+        assert_eq!(summary(&out), [("one", 0, 1), ("  two", 0, 2)]);
+    }
+
+    #[test]
+    fn a_start_line_ends_the_previous_event() {
+        let mut aggregator = aggregator("  - {path: '*.log', start_pattern: '^\\d{4}-'}").unwrap();
+        let out = push(&mut aggregator, "app.log", &[
+            "2024-05-01 ERROR boom",
+            "java.lang.Exception: boom",
+            "    at Main.run(Main.java:1)",
+            "2024-05-01 INFO next",
+        ]);
+        assert_eq!(summary(&out), [("2024-05-01 ERROR boom", 2, 3)]);
+        assert_eq!(out[0].rest[1], "    at Main.run(Main.java:1)");
+        // The last event waits for more lines
+        assert_eq!(aggregator.pending.len(), 1);
+    }
+
+    #[test]
+    fn continuation_lines_join_the_current_event() {
+        let mut aggregator = aggregator("  - {path: '*.log', continuation_pattern: '^\\s'}").unwrap();
+        let out = push(&mut aggregator, "app.log", &["Traceback:", "  File x", "  File y", "Error: z", "next"]);
+        assert_eq!(summary(&out), [("Traceback:", 2, 3), ("Error: z", 0, 4)]);
+    }
+
+    #[test]
+    fn with_both_patterns_a_start_line_never_continues() {
+        let mut aggregator = aggregator("  - {path: '*.log', start_pattern: '^\\[', continuation_pattern: '^\\s'}").unwrap();
+        let out = push(&mut aggregator, "app.log", &["[1] a", "  more", "[2] b", "plain", "[3] c"]);
+        assert_eq!(summary(&out), [("[1] a", 1, 2), ("[2] b", 0, 3), ("plain", 0, 4)]);
+    }
+
+    #[test]
+    fn caps_events_at_max_lines() {
+        let mut aggregator = aggregator("  - {path: '*.log', continuation_pattern: '^\\s', max_lines: 3}").unwrap();
+        let out = push(&mut aggregator, "app.log", &["head", " 1", " 2", " 3", " 4"]);
+        assert_eq!(summary(&out), [("head", 2, 3)]);
+        // The rest starts a new event, even though it looks like a continuation
+        assert_eq!(aggregator.pending["app.log"].entry.first, " 3");
+    }
+
+    #[test]
+    fn keeps_files_apart() {
+        let mut aggregator = aggregator("  - {path: '*.log', continuation_pattern: '^\\s'}").unwrap();
+        push(&mut aggregator, "a.log", &["a"]);
+        push(&mut aggregator, "b.log", &["b"]);
+        let out = push(&mut aggregator, "a.log", &[" a2", "a3"]);
+        assert_eq!(summary(&out), [("a", 1, 1)]);
+        assert_eq!(aggregator.pending.len(), 2);
+    }
+
+    #[test]
+    fn flushes_events_idle_past_their_timeout() {
+        let mut aggregator = aggregator(
+            "  - {path: 'now.log', continuation_pattern: '^\\s', flush_timeout: 0}\n  \
+             - {path: 'later.log', continuation_pattern: '^\\s', flush_timeout: 60}",
+        ).unwrap();
+        push(&mut aggregator, "now.log", &["a", " b"]);
+        push(&mut aggregator, "later.log", &["c"]);
+
+        let mut out = Vec::new();
+        aggregator.flush_expired(&mut out);
+        assert_eq!(summary(&out), [("a", 1, 2)]);
+        assert_eq!(aggregator.pending.len(), 1);
+        // A zero timeout is still polled at a sane rate
+        assert_eq!(aggregator.flush_interval(), Some(MIN_FLUSH_INTERVAL));
+    }
+
+    #[test]
+    fn flush_interval_follows_the_shortest_timeout() {
+        let aggregator = aggregator(
+            "  - {path: 'a.log', start_pattern: 'x', flush_timeout: 5}\n  \
+             - {path: 'b.log', start_pattern: 'x', flush_timeout: 3}",
+        ).unwrap();
+        assert_eq!(aggregator.flush_interval(), Some(Duration::from_secs(3)));
+        assert_eq!(self::aggregator("  []").unwrap().flush_interval(), None);
+    }
+
+    #[test]
+    fn rejects_rules_without_patterns_or_with_bad_ones() {
+        let error = aggregator("  - {path: '*.log'}").err().unwrap();
+        assert!(error.contains("needs a start or continuation pattern"), "{error}");
+        assert!(aggregator("  - {path: '*.log', start_pattern: '('}").is_err());
+        assert!(aggregator("  - {path: '[', start_pattern: 'x'}").err().unwrap().contains("invalid multiline path"));
+    }
+}
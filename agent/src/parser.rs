@@ -0,0 +1,518 @@
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use crate::config::{Config, ParserConfig, ParserFormat};
+
+const LEVEL_FIELDS: &[&str] = &["level", "lvl", "severity", "levelname", "log.level"];
+const MESSAGE_FIELDS: &[&str] = &["message", "msg", "log"];
+const TIMESTAMP_FIELDS: &[&str] = &["timestamp", "time", "ts", "@timestamp", "datetime"];
+
+// Level keyword as a standalone word, for unstructured lines
+static PLAIN_LEVEL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\b(FATAL|CRITICAL|CRIT|ERROR|ERR|WARNING|WARN|INFO|NOTICE|DEBUG|TRACE)\b").unwrap()
+});
+
+static GROK_REFERENCE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"%\{(\w+)(?::([\w.@\[\]-]+))?(?::(int|float))?\}").unwrap()
+});
+
+// Built-in grok pattern library, a subset of the Logstash core patterns
+const GROK_BUILTINS: &[(&str, &str)] = &[
+    ("USERNAME", r"[a-zA-Z0-9._-]+"),
+    ("USER", r"%{USERNAME}"),
+    ("INT", r"[+-]?\d+"),
+    ("POSINT", r"\b[1-9]\d*\b"),
+    ("NONNEGINT", r"\b\d+\b"),
+    ("NUMBER", r"[+-]?(?:\d+(?:\.\d*)?|\.\d+)"),
+    ("BASE16NUM", r"(?:0[xX])?[0-9A-Fa-f]+"),
+    ("WORD", r"\b\w+\b"),
+    ("NOTSPACE", r"\S+"),
+    ("SPACE", r"\s*"),
+    ("DATA", r".*?"),
+    ("GREEDYDATA", r".*"),
+    ("QUOTEDSTRING", r#""(?:[^"\\]|\\.)*""#),
+    ("QS", r"%{QUOTEDSTRING}"),
+    ("UUID", r"[A-Fa-f0-9]{8}-(?:[A-Fa-f0-9]{4}-){3}[A-Fa-f0-9]{12}"),
+    ("IPV4", r"(?:\d{1,3}\.){3}\d{1,3}"),
+    ("IPV6", r"[0-9A-Fa-f:]*:[0-9A-Fa-f:.]+"),
+    ("IP", r"(?:%{IPV6}|%{IPV4})"),
+    ("HOSTNAME", r"\b[0-9A-Za-z][0-9A-Za-z-]{0,62}(?:\.[0-9A-Za-z][0-9A-Za-z-]{0,62})*\.?\b"),
+    ("IPORHOST", r"(?:%{IP}|%{HOSTNAME})"),
+    ("HOSTPORT", r"%{IPORHOST}:%{POSINT}"),
+    ("PATH", r"(?:/[^\s]*)+"),
+    ("URIPATH", r"(?:/[A-Za-z0-9$.+!*'(){},~:;=@#%&_\-]*)+"),
+    ("URIPARAM", r"\?[A-Za-z0-9$.+!*'|(){},~@#%&/=:;_?\-\[\]<>]*"),
+    ("URIPATHPARAM", r"%{URIPATH}(?:%{URIPARAM})?"),
+    ("URI", r"[A-Za-z][A-Za-z0-9+\-.]*://\S+"),
+    ("MONTH", r"\b(?:Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec)[a-z]*\b"),
+    ("MONTHDAY", r"(?:0[1-9]|[12]\d|3[01]|[1-9])"),
+    ("YEAR", r"\d{2,4}"),
+    ("HOUR", r"(?:2[0123]|[01]?\d)"),
+    ("MINUTE", r"[0-5]\d"),
+    ("SECOND", r"(?:[0-5]?\d|60)(?:[.,]\d+)?"),
+    ("TIME", r"%{HOUR}:%{MINUTE}:%{SECOND}"),
+    ("ISO8601_TIMEZONE", r"(?:Z|[+-]%{HOUR}(?::?%{MINUTE}))"),
+    ("TIMESTAMP_ISO8601", r"%{YEAR}-\d{2}-\d{2}[T ]%{HOUR}:?%{MINUTE}(?::?%{SECOND})?%{ISO8601_TIMEZONE}?"),
+    ("HTTPDATE", r"%{MONTHDAY}/%{MONTH}/%{YEAR}:%{TIME} [+-]\d{4}"),
+    ("SYSLOGTIMESTAMP", r"%{MONTH} +%{MONTHDAY} %{TIME}"),
+    ("PROG", r"[\x21-\x5a\x5c\x5e-\x7e]+"),
+    ("SYSLOGPROG", r"%{PROG:program}(?:\[%{POSINT:pid}\])?"),
+    ("SYSLOGBASE", r"%{SYSLOGTIMESTAMP:timestamp} %{IPORHOST:logsource} %{SYSLOGPROG}:"),
+    ("LOGLEVEL", r"(?i:alert|trace|debug|notice|info|warn(?:ing)?|err(?:or)?|crit(?:ical)?|fatal|severe|emerg(?:ency)?)"),
+    ("COMMONAPACHELOG", r#"%{IPORHOST:clientip} %{USER:ident} %{USER:auth} \[%{HTTPDATE:timestamp}\] "(?:%{WORD:verb} %{NOTSPACE:request}(?: HTTP/%{NUMBER:httpversion})?|%{DATA:rawrequest})" %{NUMBER:response:int} (?:%{NUMBER:bytes:int}|-)"#),
+    ("COMBINEDAPACHELOG", r"%{COMMONAPACHELOG} %{QS:referrer} %{QS:agent}"),
+];
+
+// Result of parsing one line. Anything left as None falls back to the
+// plain-text heuristics in the collector: a level word in the raw line, else INFO.
+#[derive(Debug, Default)]
+pub struct ParsedLine {
+    pub level: Option<String>,
+    pub message: Option<String>,
+    pub timestamp: Option<String>,
+    pub fields: HashMap<String, Value>,
+}
+
+pub trait LogParser: Send {
+    // Returns None when the line does not match this parser's format
+    fn parse(&self, line: &str) -> Option<ParsedLine>;
+}
+
+// Names of the fields that carry level, message and timestamp for a parser
+struct FieldNames {
+    level: Option<String>,
+    message: Option<String>,
+    timestamp: Option<String>,
+}
+
+impl FieldNames {
+    fn from_config(config: &ParserConfig) -> Self {
+        Self {
+            level: config.level_field.clone(),
+            message: config.message_field.clone(),
+            timestamp: config.timestamp_field.clone(),
+        }
+    }
+
+    // Pulls the well-known fields out of `fields`, leaving the rest as extracted fields
+    fn split(&self, mut fields: HashMap<String, Value>) -> ParsedLine {
+        let level = take_field(&mut fields, self.level.as_deref(), LEVEL_FIELDS)
+            .and_then(|v| normalize_level(&v));
+        let message = take_field(&mut fields, self.message.as_deref(), MESSAGE_FIELDS)
+            .map(|v| value_to_string(&v));
+        let timestamp = take_field(&mut fields, self.timestamp.as_deref(), TIMESTAMP_FIELDS)
+            .map(|v| value_to_string(&v));
+
+        ParsedLine { level, message, timestamp, fields }
+    }
+}
+
+pub struct PlainParser;
+
+impl LogParser for PlainParser {
+    fn parse(&self, line: &str) -> Option<ParsedLine> {
+        Some(ParsedLine {
+            level: detect_level(line),
+            ..Default::default()
+        })
+    }
+}
+
+pub struct JsonParser {
+    names: FieldNames,
+}
+
+impl LogParser for JsonParser {
+    fn parse(&self, line: &str) -> Option<ParsedLine> {
+        let object: Map<String, Value> = serde_json::from_str(line.trim()).ok()?;
+        let mut fields = HashMap::new();
+        flatten_json("", Value::Object(object), &mut fields);
+        Some(self.names.split(fields))
+    }
+}
+
+pub struct LogfmtParser {
+    names: FieldNames,
+}
+
+impl LogParser for LogfmtParser {
+    fn parse(&self, line: &str) -> Option<ParsedLine> {
+        let fields = parse_logfmt(line);
+        if fields.is_empty() {
+            return None;
+        }
+        Some(self.names.split(fields))
+    }
+}
+
+pub struct RegexParser {
+    regex: Regex,
+    // Capture name -> conversion requested by a grok `:int`/`:float` suffix
+    conversions: HashMap<String, String>,
+    names: FieldNames,
+}
+
+impl LogParser for RegexParser {
+    fn parse(&self, line: &str) -> Option<ParsedLine> {
+        let caps = self.regex.captures(line)?;
+        let mut fields = HashMap::new();
+        for name in self.regex.capture_names().flatten() {
+            if let Some(m) = caps.name(name) {
+                let value = match self.conversions.get(name).map(String::as_str) {
+                    Some("int") => m.as_str().parse::<i64>().map(Value::from).unwrap_or_else(|_| Value::from(m.as_str())),
+                    Some("float") => m.as_str().parse::<f64>().map(Value::from).unwrap_or_else(|_| Value::from(m.as_str())),
+                    _ => Value::from(m.as_str()),
+                };
+                fields.insert(name.to_string(), value);
+            }
+        }
+        Some(self.names.split(fields))
+    }
+}
+
+// Chooses a parser for each tailed file from the `parsers` section of the config
+pub struct ParserSet {
+    entries: Vec<(glob::Pattern, Box<dyn LogParser>)>,
+    fallback: PlainParser,
+}
+
+impl ParserSet {
+    pub fn from_config(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut entries = Vec::new();
+        for parser in &config.parsers {
+            let pattern = glob::Pattern::new(&parser.path)
+                .map_err(|e| format!("invalid parser path {}: {}", parser.path, e))?;
+            entries.push((pattern, build_parser(parser, &config.grok_patterns)?));
+        }
+        Ok(Self { entries, fallback: PlainParser })
+    }
+
+    // Parses with the file's configured parser, falling back to plain text when the line doesn't match
+    pub fn parse(&self, path: &str, line: &str) -> ParsedLine {
+        let parser = self.entries
+            .iter()
+            .find(|(pattern, _)| pattern.matches(path))
+            .map(|(_, parser)| parser.as_ref());
+
+        parser
+            .and_then(|p| p.parse(line))
+            .or_else(|| self.fallback.parse(line))
+            .unwrap_or_default()
+    }
+}
+
+fn build_parser(
+    config: &ParserConfig,
+    grok_patterns: &HashMap<String, String>,
+) -> Result<Box<dyn LogParser>, Box<dyn std::error::Error>> {
+    let names = FieldNames::from_config(config);
+    let pattern = || config.pattern.clone()
+        .ok_or_else(|| format!("parser for {} needs a pattern", config.path));
+
+    Ok(match config.format {
+        ParserFormat::Plain => Box::new(PlainParser),
+        ParserFormat::Json => Box::new(JsonParser { names }),
+        ParserFormat::Logfmt => Box::new(LogfmtParser { names }),
+        ParserFormat::Regex => Box::new(RegexParser {
+            regex: Regex::new(&pattern()?)?,
+            conversions: HashMap::new(),
+            names,
+        }),
+        ParserFormat::Grok => {
+            let mut conversions = HashMap::new();
+            let expanded = expand_grok(&pattern()?, grok_patterns, &mut conversions, 0)?;
+            Box::new(RegexParser {
+                regex: Regex::new(&expanded)?,
+                conversions,
+                names,
+            })
+        }
+    })
+}
+
+// Expands %{NAME}, %{NAME:field} and %{NAME:field:int|float} into a plain regex
+fn expand_grok(
+    pattern: &str,
+    custom: &HashMap<String, String>,
+    conversions: &mut HashMap<String, String>,
+    depth: usize,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if depth > 16 {
+        return Err(format!("grok pattern nests too deeply: {}", pattern).into());
+    }
+
+    let mut expanded = String::new();
+    let mut last = 0;
+    for caps in GROK_REFERENCE.captures_iter(pattern) {
+        let whole = caps.get(0).unwrap();
+        expanded.push_str(&pattern[last..whole.start()]);
+        last = whole.end();
+
+        let name = &caps[1];
+        let definition = custom.get(name)
+            .map(String::as_str)
+            .or_else(|| GROK_BUILTINS.iter().find(|(n, _)| *n == name).map(|(_, d)| *d))
+            .ok_or_else(|| format!("unknown grok pattern {}", name))?;
+        let inner = expand_grok(definition, custom, conversions, depth + 1)?;
+
+        match caps.get(2) {
+            Some(field) => {
+                // Regex group names only allow word characters, dots and brackets
+                let field = field.as_str().replace(['@', '-'], "_");
+                if let Some(conversion) = caps.get(3) {
+                    conversions.insert(field.clone(), conversion.as_str().to_string());
+                }
+                expanded.push_str(&format!("(?P<{}>{})", field, inner));
+            }
+            None => expanded.push_str(&format!("(?:{})", inner)),
+        }
+    }
+    expanded.push_str(&pattern[last..]);
+    Ok(expanded)
+}
+
+fn parse_logfmt(line: &str) -> HashMap<String, Value> {
+    let mut fields = HashMap::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        let mut key = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' || c.is_whitespace() {
+                break;
+            }
+            key.push(c);
+            chars.next();
+        }
+        if key.is_empty() {
+            if chars.next().is_none() {
+                break;
+            }
+            continue;
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            if let Some(escaped) = chars.next() {
+                                value.push(match escaped {
+                                    'n' => '\n',
+                                    't' => '\t',
+                                    other => other,
+                                });
+                            }
+                        }
+                        '"' => break,
+                        other => value.push(other),
+                    }
+                }
+            } else {
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+            }
+            fields.insert(key, Value::from(value));
+        } else {
+            // Bare keys are boolean flags
+            fields.insert(key, Value::Bool(true));
+        }
+    }
+
+    fields
+}
+
+// Nested objects become dotted keys so every field is addressable in OpenSearch
+fn flatten_json(prefix: &str, value: Value, out: &mut HashMap<String, Value>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let key = if prefix.is_empty() { key } else { format!("{}.{}", prefix, key) };
+                flatten_json(&key, value, out);
+            }
+        }
+        other => {
+            out.insert(prefix.to_string(), other);
+        }
+    }
+}
+
+fn take_field(fields: &mut HashMap<String, Value>, configured: Option<&str>, defaults: &[&str]) -> Option<Value> {
+    match configured {
+        Some(name) => fields.remove(name),
+        None => defaults.iter().find_map(|name| fields.remove(*name)),
+    }
+}
+
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+pub fn detect_level(line: &str) -> Option<String> {
+    PLAIN_LEVEL.captures(line).and_then(|caps| normalize_level(&Value::from(&caps[1])))
+}
+
+// Maps level names and bunyan/pino numeric levels onto ERROR/WARN/INFO/DEBUG
+fn normalize_level(value: &Value) -> Option<String> {
+    let level = match value {
+        Value::Number(n) => match n.as_u64()? {
+            0..=29 => "DEBUG",
+            30..=39 => "INFO",
+            40..=49 => "WARN",
+            _ => "ERROR",
+        },
+        Value::String(s) => match s.trim().to_ascii_lowercase().as_str() {
+            "fatal" | "critical" | "crit" | "error" | "err" | "alert" | "emerg" | "emergency" | "panic" | "severe" => "ERROR",
+            "warning" | "warn" => "WARN",
+            "info" | "information" | "notice" => "INFO",
+            "debug" | "trace" | "verbose" => "DEBUG",
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(level.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config(parsers: &str) -> Config {
+        serde_yaml::from_str(&format!(
+            "agent: {{name: test, server_url: 'http://localhost', report_interval: 5}}\n\
+             collection: {{metrics: true, logs: true, resources: true}}\n\
+             log_paths: []\n\
+             grok_patterns: {{SERVICE: '[a-z]+', LOOP: '%{{LOOP}}'}}\n\
+             parsers:\n{}",
+            parsers
+        )).unwrap()
+    }
+
+    fn parsers(parsers: &str) -> ParserSet {
+        ParserSet::from_config(&config(parsers)).unwrap()
+    }
+
+    fn parser_error(parsers: &str) -> String {
+        ParserSet::from_config(&config(parsers)).err().expect("config should be rejected").to_string()
+    }
+
+    #[test]
+    fn json_lines_give_level_message_timestamp_and_flattened_fields() {
+        let parsers = parsers("  - {path: '*.json', format: json}");
+        let parsed = parsers.parse(
+            "app.json",
+            r#"{"level":"error","msg":"boom","time":"2024-05-01T10:00:00Z","http":{"status":500,"path":"/x"}}"#,
+        );
+        assert_eq!(parsed.level.as_deref(), Some("ERROR"));
+        assert_eq!(parsed.message.as_deref(), Some("boom"));
+        assert_eq!(parsed.timestamp.as_deref(), Some("2024-05-01T10:00:00Z"));
+        assert_eq!(parsed.fields.len(), 2);
+        assert_eq!(parsed.fields["http.status"], json!(500));
+        assert_eq!(parsed.fields["http.path"], json!("/x"));
+
+        // Bunyan and pino levels are numbers
+        assert_eq!(parsers.parse("app.json", r#"{"level":50,"msg":"x"}"#).level.as_deref(), Some("ERROR"));
+        assert_eq!(parsers.parse("app.json", r#"{"level":30,"msg":"x"}"#).level.as_deref(), Some("INFO"));
+        assert_eq!(parsers.parse("app.json", r#"{"level":"loud","msg":"x"}"#).level, None);
+    }
+
+    #[test]
+    fn configured_field_names_replace_the_defaults() {
+        let parsers = parsers("  - {path: '*.json', format: json, level_field: sev, message_field: text, timestamp_field: at}");
+        let parsed = parsers.parse("app.json", r#"{"sev":"warning","text":"slow","at":1700000000,"msg":"kept"}"#);
+        assert_eq!(parsed.level.as_deref(), Some("WARN"));
+        assert_eq!(parsed.message.as_deref(), Some("slow"));
+        assert_eq!(parsed.timestamp.as_deref(), Some("1700000000"));
+        assert_eq!(parsed.fields["msg"], json!("kept"));
+    }
+
+    #[test]
+    fn lines_the_parser_cannot_read_fall_back_to_plain_text() {
+        let parsers = parsers("  - {path: '*.json', format: json}");
+        let parsed = parsers.parse("app.json", "WARN not json at all");
+        assert_eq!(parsed.level.as_deref(), Some("WARN"));
+        assert_eq!(parsed.message, None);
+        assert!(parsed.fields.is_empty());
+
+        // Files no parser claims are plain text too
+        assert_eq!(parsers.parse("app.log", r#"{"level":"error"}"#).level, None);
+    }
+
+    #[test]
+    fn logfmt_handles_quotes_escapes_and_bare_keys() {
+        let parsers = parsers("  - {path: '*.log', format: logfmt}");
+        let parsed = parsers.parse("app.log", r#"level=warn msg="disk \"sda\" almost full\n" disk=/dev/sda1 retry  empty="#);
+        assert_eq!(parsed.level.as_deref(), Some("WARN"));
+        assert_eq!(parsed.message.as_deref(), Some("disk \"sda\" almost full\n"));
+        assert_eq!(parsed.fields["disk"], json!("/dev/sda1"));
+        assert_eq!(parsed.fields["retry"], json!(true));
+        assert_eq!(parsed.fields["empty"], json!(""));
+    }
+
+    #[test]
+    fn regex_captures_become_fields() {
+        let parsers = parsers(r"  - {path: '*.log', format: regex, pattern: '^(?P<level>\w+) \[(?P<component>[^\]]+)\] (?P<message>.*)$'}");
+        let parsed = parsers.parse("app.log", "ERR [db] connection lost");
+        assert_eq!(parsed.level.as_deref(), Some("ERROR"));
+        assert_eq!(parsed.message.as_deref(), Some("connection lost"));
+        assert_eq!(parsed.fields["component"], json!("db"));
+
+        assert_eq!(parsers.parse("app.log", "no brackets here").fields.len(), 0);
+    }
+
+    #[test]
+    fn grok_expands_builtin_and_custom_patterns_with_conversions() {
+        let parsers = parsers("  - {path: 'access.log', format: grok, pattern: '%{COMMONAPACHELOG}'}\n  \
+                               - {path: 'app.log', format: grok, pattern: '%{LOGLEVEL:level} %{SERVICE:@service} took %{NUMBER:ms:float}ms'}");
+        let parsed = parsers.parse(
+            "access.log",
+            r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326"#,
+        );
+        assert_eq!(parsed.timestamp.as_deref(), Some("10/Oct/2000:13:55:36 -0700"));
+        assert_eq!(parsed.fields["clientip"], json!("127.0.0.1"));
+        assert_eq!(parsed.fields["auth"], json!("frank"));
+        assert_eq!(parsed.fields["verb"], json!("GET"));
+        assert_eq!(parsed.fields["request"], json!("/apache_pb.gif"));
+        assert_eq!(parsed.fields["response"], json!(200));
+        assert_eq!(parsed.fields["bytes"], json!(2326));
+
+        let parsed = parsers.parse("app.log", "warning billing took 12.5ms");
+        assert_eq!(parsed.level.as_deref(), Some("WARN"));
+        assert_eq!(parsed.fields["_service"], json!("billing"));
+        assert_eq!(parsed.fields["ms"], json!(12.5));
+    }
+
+    #[test]
+    fn rejects_bad_parser_definitions() {
+        assert!(parser_error("  - {path: '*.log', format: grok}").contains("needs a pattern"));
+        assert!(parser_error("  - {path: '*.log', format: grok, pattern: '%{NOPE}'}").contains("unknown grok pattern NOPE"));
+        assert!(parser_error("  - {path: '*.log', format: grok, pattern: '%{LOOP}'}").contains("nests too deeply"));
+        assert!(parser_error("  - {path: '[', format: plain}").contains("invalid parser path"));
+    }
+
+    #[test]
+    fn detects_level_words_in_plain_lines() {
+        assert_eq!(detect_level("2024-05-01 12:00:00 WARNING low disk").as_deref(), Some("WARN"));
+        assert_eq!(detect_level("[CRIT] kernel panic").as_deref(), Some("ERROR"));
+        assert_eq!(detect_level("TRACE entering loop").as_deref(), Some("DEBUG"));
+        // Whole words only, and case sensitive
+        assert_eq!(detect_level("ERRORS: none"), None);
+        assert_eq!(detect_level("an error occurred"), None);
+    }
+}
@@ -1,54 +1,506 @@
-use reqwest::Client;
+use flate2::write::GzEncoder;
+use rand::Rng;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::{Certificate, Client, Identity, Response, StatusCode, Url};
 use serde_json::json;
-use crate::config::Config;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use crate::config::{Compression, Config};
+use crate::credential::Credential;
+use crate::identity::HostInfo;
 use crate::collector::{AgentMetrics, AgentLog};
+use crate::spool::{Spool, SpoolItem, SpoolStats};
+use crate::tailer::FileState;
 
-pub async fn register_agent(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
-    let client = Client::new();
-    let url = format!("{}/api/agents/register", config.agent.server_url);
-    
-    let payload = json!({
-        "name": config.agent.name,
-        "timestamp": chrono::Utc::now().to_rfc3339(),
-        "capabilities": {
-            "metrics": config.collection.metrics,
-            "logs": config.collection.logs,
-            "resources": config.collection.resources
-        }
-    });
-    
-    let response = client.post(&url)
-        .json(&payload)
-        .send()
-        .await?;
-    
-    if response.status().is_success() {
+// Upper bound on spooled items replayed per report tick, so a long outage
+// does not stall collection while the backlog drains
+const MAX_REPLAY_PER_TICK: usize = 100;
+
+// Headers of signed requests; see `Credential::sign`
+const AGENT_ID_HEADER: &str = "x-agent-id";
+const KEY_ID_HEADER: &str = "x-agent-key-id";
+const TIMESTAMP_HEADER: &str = "x-agent-timestamp";
+const NONCE_HEADER: &str = "x-agent-nonce";
+const SIGNATURE_HEADER: &str = "x-agent-signature";
+// Set by the backend when an admin asked for this agent's key to be rotated
+const ROTATE_HEADER: &str = "x-agent-rotate";
+
+enum SendError {
+    // Worth retrying later: connection failures, timeouts, 5xx, 408 and 429
+    Retryable(String),
+    // The backend rejected the payload itself; retrying will not help
+    Permanent(String),
+}
+
+// Exponential backoff with jitter between delivery attempts
+struct Backoff {
+    initial: Duration,
+    max: Duration,
+    failures: u32,
+    next_attempt: Instant,
+}
+
+impl Backoff {
+    fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max, failures: 0, next_attempt: Instant::now() }
+    }
+
+    fn ready(&self) -> bool {
+        Instant::now() >= self.next_attempt
+    }
+
+    fn failed(&mut self) {
+        let exp = self.initial.saturating_mul(1 << self.failures.min(16));
+        let delay = exp.min(self.max);
+        // Equal jitter: wait between half and the full delay
+        let jittered = delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+        self.failures += 1;
+        self.next_attempt = Instant::now() + jittered;
+    }
+
+    fn reset(&mut self) {
+        self.failures = 0;
+        self.next_attempt = Instant::now();
+    }
+}
+
+pub struct Reporter {
+    // One long-lived client so connections to the backend are reused across ticks
+    client: Client,
+    compression: Compression,
+    spool: Spool,
+    backoff: Backoff,
+    registered: bool,
+    credential: Option<Credential>,
+    credential_path: String,
+    rotate_due: bool,
+    pending_metrics: Vec<AgentMetrics>,
+    pending_logs: Vec<AgentLog>,
+    // Tailer positions of the pending logs
+    pending_positions: Vec<FileState>,
+    batch_started: Option<Instant>,
+}
+
+impl Reporter {
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut client = Client::builder()
+            .pool_idle_timeout(Duration::from_secs(90))
+            .tcp_keepalive(Duration::from_secs(60))
+            .timeout(Duration::from_secs(config.upload.timeout.max(1)));
+        if !config.tls.ca_file.is_empty() {
+            let pem = std::fs::read(&config.tls.ca_file)
+                .map_err(|e| format!("failed to read {}: {}", config.tls.ca_file, e))?;
+            client = client.add_root_certificate(Certificate::from_pem(&pem)?);
+        }
+        match (config.tls.cert_file.is_empty(), config.tls.key_file.is_empty()) {
+            (true, true) => {}
+            (false, false) => {
+                let cert = std::fs::read(&config.tls.cert_file)
+                    .map_err(|e| format!("failed to read {}: {}", config.tls.cert_file, e))?;
+                let key = std::fs::read(&config.tls.key_file)
+                    .map_err(|e| format!("failed to read {}: {}", config.tls.key_file, e))?;
+                client = client.identity(Identity::from_pkcs8_pem(&cert, &key)?);
+            }
+            _ => return Err("tls.cert_file and tls.key_file must be set together".into()),
+        }
+        let client = client.build()?;
+
+        let credential = Credential::load(Path::new(&config.agent.credential_file))
+            .filter(|credential| {
+                let ours = credential.agent_id == config.agent.instance_id;
+                if !ours {
+                    eprintln!("Ignoring credential issued to agent {}", credential.agent_id);
+                }
+                ours
+            });
+
+        Ok(Self {
+            client,
+            compression: config.upload.compression,
+            spool: Spool::open(Path::new(&config.spool.dir), config.spool.max_bytes)?,
+            backoff: Backoff::new(
+                Duration::from_secs(config.spool.initial_backoff.max(1)),
+                Duration::from_secs(config.spool.max_backoff.max(1)),
+            ),
+            registered: false,
+            credential,
+            credential_path: config.agent.credential_file.clone(),
+            rotate_due: false,
+            pending_metrics: Vec::new(),
+            pending_logs: Vec::new(),
+            pending_positions: Vec::new(),
+            batch_started: None,
+        })
+    }
+
+    pub fn spool_stats(&self) -> SpoolStats {
+        self.spool.stats()
+    }
+
+    // Adds this tick's data to the current batch and, once the batch is full or old
+    // enough, delivers it. Batches are spooled to disk when the backend is unavailable
+    // and replayed in order once it comes back. Returns the tailer positions of the logs
+    // that are now delivered or spooled (or were dropped for good).
+    pub async fn send_data(
+        &mut self,
+        config: &Config,
+        metrics: AgentMetrics,
+        logs: Vec<AgentLog>,
+        positions: Vec<FileState>,
+    ) -> Vec<FileState> {
+        if config.collection.metrics {
+            self.pending_metrics.push(metrics);
+        }
+        if config.collection.logs {
+            self.pending_logs.extend(logs);
+        }
+        self.pending_positions.extend(positions);
+        if self.pending_metrics.is_empty() && self.pending_logs.is_empty() {
+            self.replay(config).await;
+            return std::mem::take(&mut self.pending_positions);
+        }
+        let started = *self.batch_started.get_or_insert_with(Instant::now);
+
+        let pending = self.pending_metrics.len() + self.pending_logs.len();
+        let max_events = config.upload.max_batch_events.max(1);
+        if pending < max_events && started.elapsed() < Duration::from_secs(config.upload.max_batch_age) {
+            self.replay(config).await;
+            return Vec::new();
+        }
+        let positions = std::mem::take(&mut self.pending_positions);
+
+        let items = self.take_batches(config, max_events);
+        for item in items {
+            // Only bypass the spool when nothing older is waiting, to keep delivery in order
+            if self.spool.is_empty() && self.backoff.ready() {
+                match self.deliver(config, &item).await {
+                    Ok(()) => continue,
+                    Err(SendError::Permanent(e)) => {
+                        eprintln!("Backend rejected {} payload, dropping: {}", item.endpoint, e);
+                        let bytes = item.body.to_string().len() as u64;
+                        self.spool.record_dropped(item.events, bytes);
+                        continue;
+                    }
+                    Err(SendError::Retryable(e)) => {
+                        eprintln!("Backend unavailable, spooling data: {}", e);
+                        self.backoff.failed();
+                    }
+                }
+            }
+            if let Err(e) = self.spool.push(&item) {
+                eprintln!("Failed to spool {} payload: {}", item.endpoint, e);
+                self.spool.record_dropped(item.events, 0);
+            }
+        }
+
+        self.replay(config).await;
+        positions
+    }
+
+    // Splits everything pending into batches of at most `max_events` events
+    fn take_batches(&mut self, config: &Config, max_events: usize) -> Vec<SpoolItem> {
+        let mut metrics = std::mem::take(&mut self.pending_metrics);
+        let mut logs = std::mem::take(&mut self.pending_logs);
+        self.batch_started = None;
+
+        let mut items = Vec::new();
+        while !metrics.is_empty() || !logs.is_empty() {
+            let batch_metrics: Vec<AgentMetrics> = metrics.drain(..metrics.len().min(max_events)).collect();
+            let room = max_events - batch_metrics.len();
+            let batch_logs: Vec<AgentLog> = logs.drain(..logs.len().min(room)).collect();
+            items.push(SpoolItem {
+                endpoint: "batch".to_string(),
+                events: (batch_metrics.len() + batch_logs.len()) as u64,
+                body: json!({
+                    "agent_id": config.agent.instance_id,
+                    "agent_name": config.agent.name,
+                    "metrics": batch_metrics,
+                    "logs": batch_logs,
+                }),
+            });
+        }
+        items
+    }
+
+    async fn replay(&mut self, config: &Config) {
+        for _ in 0..MAX_REPLAY_PER_TICK {
+            if !self.backoff.ready() {
+                return;
+            }
+            let item = match self.spool.front() {
+                None => return,
+                Some(Ok(item)) => item,
+                Some(Err(e)) => {
+                    eprintln!("Discarding unreadable spool entry: {}", e);
+                    self.spool.pop_front();
+                    continue;
+                }
+            };
+
+            match self.deliver(config, &item).await {
+                Ok(()) => self.spool.pop_front(),
+                Err(SendError::Permanent(e)) => {
+                    eprintln!("Backend rejected spooled {} payload, dropping: {}", item.endpoint, e);
+                    let bytes = item.body.to_string().len() as u64;
+                    self.spool.pop_front();
+                    self.spool.record_dropped(item.events, bytes);
+                }
+                Err(SendError::Retryable(e)) => {
+                    eprintln!("Replay failed, will retry: {}", e);
+                    self.backoff.failed();
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn deliver(&mut self, config: &Config, item: &SpoolItem) -> Result<(), SendError> {
+        if self.credential.is_none() && !config.agent.enrollment_token.is_empty() {
+            // Like registration, a failed enrollment must not cause data loss
+            self.enroll(config).await.map_err(|e| match e {
+                SendError::Permanent(e) => SendError::Retryable(e),
+                e => e,
+            })?;
+        }
+        if !self.registered {
+            // A rejected registration must not cause data loss, so it is always retried
+            self.register(config).await.map_err(|e| match e {
+                SendError::Permanent(e) => SendError::Retryable(e),
+                e => e,
+            })?;
+        }
+        let url = format!("{}/api/agents/{}", config.agent.server_url, item.endpoint);
+        self.post(&url, &item.body).await?;
+        self.backoff.reset();
+
+        if self.rotate_due {
+            if let Err(SendError::Retryable(e) | SendError::Permanent(e)) = self.rotate(config).await {
+                eprintln!("Credential rotation failed, will retry: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn register(&mut self, config: &Config) -> Result<(), SendError> {
+        let url = format!("{}/api/agents/register", config.agent.server_url);
+
+        let payload = json!({
+            "id": config.agent.instance_id,
+            "name": config.agent.name,
+            "host": HostInfo::detect(),
+            "labels": config.agent.labels,
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "report_interval": config.agent.report_interval,
+            "capabilities": {
+                "metrics": config.collection.metrics,
+                "logs": config.collection.logs,
+                "resources": config.collection.resources
+            }
+        });
+
+        self.post(&url, &payload).await?;
         println!("Agent registered successfully");
-    } else {
-        println!("Failed to register agent: {}", response.status());
+        self.registered = true;
+        Ok(())
+    }
+
+    // Trades the configured one-time enrollment token for a signing credential
+    async fn enroll(&mut self, config: &Config) -> Result<(), SendError> {
+        let url = format!("{}/api/agents/enroll", config.agent.server_url);
+        let payload = json!({
+            "agent_id": config.agent.instance_id,
+            "name": config.agent.name,
+            "enrollment_token": config.agent.enrollment_token,
+        });
+
+        let response = self.post(&url, &payload).await?;
+        self.store_credential(config, response).await?;
+        println!("Agent enrolled successfully");
+        Ok(())
+    }
+
+    async fn rotate(&mut self, config: &Config) -> Result<(), SendError> {
+        let url = format!("{}/api/agents/rotate", config.agent.server_url);
+        let response = self.post(&url, &json!({})).await?;
+        self.store_credential(config, response).await?;
+        self.rotate_due = false;
+        println!("Agent credential rotated");
+        Ok(())
+    }
+
+    async fn store_credential(&mut self, config: &Config, response: Response) -> Result<(), SendError> {
+        #[derive(serde::Deserialize)]
+        struct Issued {
+            data: Credential,
+        }
+        let issued: Issued = response.json().await
+            .map_err(|e| SendError::Retryable(format!("invalid credential response: {}", e)))?;
+        if let Err(e) = issued.data.save(Path::new(&config.agent.credential_file)) {
+            // Still usable for this run; it just has to be re-issued after a restart
+            eprintln!("Failed to save credential to {}: {}", config.agent.credential_file, e);
+        }
+        self.credential = Some(issued.data);
+        Ok(())
+    }
+
+    async fn post(&mut self, url: &str, body: &serde_json::Value) -> Result<Response, SendError> {
+        let (data, encoding) = encode_body(body, self.compression)
+            .map_err(|e| SendError::Permanent(format!("failed to encode body: {}", e)))?;
+
+        let mut request = self.client.post(url)
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(encoding) = encoding {
+            request = request.header(CONTENT_ENCODING, encoding);
+        }
+        if let Some(credential) = &self.credential {
+            let path = Url::parse(url).map(|u| u.path().to_string()).unwrap_or_default();
+            let timestamp = chrono::Utc::now().timestamp().to_string();
+            let nonce = format!("{:032x}", rand::thread_rng().gen::<u128>());
+            request = request
+                .header(AGENT_ID_HEADER, &credential.agent_id)
+                .header(KEY_ID_HEADER, &credential.key_id)
+                .header(SIGNATURE_HEADER, credential.sign(&timestamp, &nonce, "POST", &path, &data))
+                .header(TIMESTAMP_HEADER, timestamp)
+                .header(NONCE_HEADER, nonce);
+        }
+
+        let response = request
+            .body(data)
+            .send()
+            .await
+            .map_err(|e| SendError::Retryable(e.to_string()))?;
+
+        let status = response.status();
+        if response.headers().contains_key(ROTATE_HEADER) {
+            self.rotate_due = true;
+        }
+        if status.is_success() {
+            Ok(response)
+        } else if status == StatusCode::FORBIDDEN && self.credential.is_some() {
+            // Revoked or unknown credential: forget it so the agent re-enrolls with
+            // the configured token. Data stays spooled until that succeeds.
+            eprintln!("Backend rejected this agent's credential; it must be re-enrolled");
+            self.credential = None;
+            self.registered = false;
+            Credential::remove(Path::new(&self.credential_path));
+            Err(SendError::Retryable(format!("{} returned {}", url, status)))
+        } else if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            // Credentials can be fixed without losing data, so keep it spooled
+            Err(SendError::Retryable(format!("{} returned {}, check agent.enrollment_token", url, status)))
+        } else if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::REQUEST_TIMEOUT {
+            Err(SendError::Retryable(format!("{} returned {}", url, status)))
+        } else {
+            Err(SendError::Permanent(format!("{} returned {}", url, status)))
+        }
     }
-    
-    Ok(())
 }
 
-pub async fn send_data(
-    config: &Config,
-    metrics: AgentMetrics,
-    logs: Vec<AgentLog>
-) -> Result<(), Box<dyn std::error::Error>> {
-    let client = Client::new();
-    
-    // Send metrics
-    if config.collection.metrics {
-        let url = format!("{}/api/agents/metrics", config.agent.server_url);
-        let _ = client.post(&url).json(&metrics).send().await;
-    }
-    
-    // Send logs
-    if config.collection.logs && !logs.is_empty() {
-        let url = format!("{}/api/agents/logs", config.agent.server_url);
-        let _ = client.post(&url).json(&logs).send().await;
-    }
-    
-    Ok(())
+fn encode_body(body: &serde_json::Value, compression: Compression) -> std::io::Result<(Vec<u8>, Option<&'static str>)> {
+    let json = serde_json::to_vec(body)?;
+    match compression {
+        Compression::None => Ok((json, None)),
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&json)?;
+            Ok((encoder.finish()?, Some("gzip")))
+        }
+        Compression::Zstd => Ok((zstd::encode_all(json.as_slice(), 3)?, Some("zstd"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collector::Collector;
+    use std::collections::HashMap;
+
+    #[test]
+    fn backoff_doubles_with_jitter_up_to_the_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        assert!(backoff.ready());
+        for expected in [1, 2, 4, 8, 10, 10] {
+            let before = Instant::now();
+            backoff.failed();
+            let delay = backoff.next_attempt - before;
+            let expected = Duration::from_secs(expected);
+            assert!(delay >= expected / 2 && delay <= expected + Duration::from_millis(100), "{delay:?}");
+            assert!(!backoff.ready());
+        }
+        backoff.reset();
+        assert!(backoff.ready());
+        assert_eq!(backoff.failures, 0);
+    }
+
+    // A reporter whose backend refuses connections, spooling under `dir`
+    fn reporter(dir: &Path, max_batch_events: usize, max_batch_age: u64) -> (Config, Reporter) {
+        let config: Config = serde_yaml::from_str(&format!(
+            "agent: {{name: test, server_url: 'http://127.0.0.1:9', report_interval: 5, instance_id: test-1, \
+                      credential_file: '{dir}/credential.json'}}\n\
+             collection: {{metrics: false, logs: true, resources: false}}\n\
+             log_paths: []\n\
+             spool: {{dir: '{dir}/spool', max_bytes: 1048576, initial_backoff: 60, max_backoff: 60}}\n\
+             upload: {{compression: none, max_batch_events: {max_batch_events}, max_batch_age: {max_batch_age}, timeout: 5}}",
+            dir = dir.display(),
+        )).unwrap();
+        let reporter = Reporter::new(&config).unwrap();
+        (config, reporter)
+    }
+
+    fn log(message: &str) -> AgentLog {
+        AgentLog {
+            agent_id: "test-1".to_string(),
+            agent_name: "test".to_string(),
+            event_time: "2024-05-01T10:00:00Z".to_string(),
+            ingest_time: "2024-05-01T10:00:00Z".to_string(),
+            level: "INFO".to_string(),
+            message: message.to_string(),
+            source_file: "app.log".to_string(),
+            fields: HashMap::new(),
+        }
+    }
+
+    fn position(offset: u64) -> FileState {
+        FileState { inode: 1, device: 1, offset }
+    }
+
+    #[tokio::test]
+    async fn returns_positions_only_once_their_logs_are_spooled() {
+        let dir = std::env::temp_dir().join(format!("monx-reporter-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (config, mut reporter) = reporter(&dir, 3, 3600);
+        let mut collector = Collector::new();
+
+        // The batch is neither full nor old enough yet
+        let metrics = collector.collect_metrics(&config);
+        let done = reporter.send_data(&config, metrics, vec![log("a")], vec![position(2)]).await;
+        assert!(done.is_empty());
+        assert!(reporter.spool.is_empty());
+
+        // Full: delivery fails, so it is spooled and the positions are handed back
+        let metrics = collector.collect_metrics(&config);
+        let logs = vec![log("b"), log("c"), log("d")];
+        let done = reporter.send_data(&config, metrics, logs, vec![position(4), position(6)]).await;
+        assert_eq!(done, [position(2), position(4), position(6)]);
+        let stats = reporter.spool_stats();
+        assert_eq!((stats.queued_events, stats.dropped_events), (4, 0));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn splits_pending_data_into_batches_of_max_events() {
+        let dir = std::env::temp_dir().join(format!("monx-reporter-batches-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (config, mut reporter) = reporter(&dir, 2, 0);
+        reporter.pending_logs = (0..5).map(|n| log(&n.to_string())).collect();
+
+        let batches = reporter.take_batches(&config, 2);
+        let sizes: Vec<u64> = batches.iter().map(|item| item.events).collect();
+        assert_eq!(sizes, [2, 2, 1]);
+        assert_eq!(batches[2].body["logs"][0]["message"], "4");
+        assert!(reporter.pending_logs.is_empty() && reporter.batch_started.is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
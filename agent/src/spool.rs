@@ -0,0 +1,238 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// A payload waiting to be delivered to `{server_url}/api/agents/{endpoint}`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SpoolItem {
+    pub endpoint: String,
+    pub events: u64,
+    pub body: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SpoolStats {
+    pub queued_bytes: u64,
+    pub queued_events: u64,
+    pub dropped_bytes: u64,
+    pub dropped_events: u64,
+}
+
+struct SpoolEntry {
+    path: PathBuf,
+    bytes: u64,
+    events: u64,
+}
+
+// Bounded FIFO of undelivered payloads, one file per item named
+// `{sequence}-{events}.json` so the queue can be rebuilt on startup
+// without reading every file.
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    entries: VecDeque<SpoolEntry>,
+    next_seq: u64,
+    stats: SpoolStats,
+}
+
+impl Spool {
+    pub fn open(dir: &Path, max_bytes: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let Some((seq, events)) = name.strip_suffix(".json").and_then(|stem| stem.split_once('-')) else {
+                continue;
+            };
+            let (Ok(seq), Ok(events)) = (seq.parse::<u64>(), events.parse::<u64>()) else {
+                continue;
+            };
+            found.push((seq, SpoolEntry { path: entry.path(), bytes: entry.metadata()?.len(), events }));
+        }
+        found.sort_by_key(|(seq, _)| *seq);
+
+        let next_seq = found.last().map(|(seq, _)| seq + 1).unwrap_or(0);
+        let mut spool = Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            entries: found.into_iter().map(|(_, entry)| entry).collect(),
+            next_seq,
+            stats: SpoolStats::default(),
+        };
+        spool.recount();
+        if !spool.entries.is_empty() {
+            println!(
+                "Spool has {} undelivered events ({} bytes) from a previous run",
+                spool.stats.queued_events, spool.stats.queued_bytes
+            );
+        }
+        Ok(spool)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn stats(&self) -> SpoolStats {
+        self.stats.clone()
+    }
+
+    pub fn push(&mut self, item: &SpoolItem) -> io::Result<()> {
+        let data = serde_json::to_vec(item)?;
+        let bytes = data.len() as u64;
+        if bytes > self.max_bytes {
+            self.record_dropped(item.events, bytes);
+            return Ok(());
+        }
+
+        let path = self.dir.join(format!("{:020}-{}.json", self.next_seq, item.events));
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, &data)?;
+        std::fs::rename(&tmp, &path)?;
+        self.next_seq += 1;
+        self.entries.push_back(SpoolEntry { path, bytes, events: item.events });
+        self.stats.queued_bytes += bytes;
+        self.stats.queued_events += item.events;
+
+        // Over the cap: shed the oldest data first
+        while self.stats.queued_bytes > self.max_bytes {
+            let Some(oldest) = self.entries.pop_front() else { break };
+            let _ = std::fs::remove_file(&oldest.path);
+            self.stats.queued_bytes -= oldest.bytes;
+            self.stats.queued_events -= oldest.events;
+            self.record_dropped(oldest.events, oldest.bytes);
+        }
+        Ok(())
+    }
+
+    // Oldest queued item, if any
+    pub fn front(&self) -> Option<io::Result<SpoolItem>> {
+        let entry = self.entries.front()?;
+        Some(std::fs::read(&entry.path).and_then(|data| Ok(serde_json::from_slice(&data)?)))
+    }
+
+    // Removes the oldest item after it was delivered (or found to be undeliverable)
+    pub fn pop_front(&mut self) {
+        if let Some(entry) = self.entries.pop_front() {
+            let _ = std::fs::remove_file(&entry.path);
+            self.stats.queued_bytes -= entry.bytes;
+            self.stats.queued_events -= entry.events;
+        }
+    }
+
+    pub fn record_dropped(&mut self, events: u64, bytes: u64) {
+        self.stats.dropped_events += events;
+        self.stats.dropped_bytes += bytes;
+    }
+
+    fn recount(&mut self) {
+        self.stats.queued_bytes = self.entries.iter().map(|e| e.bytes).sum();
+        self.stats.queued_events = self.entries.iter().map(|e| e.events).sum();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // A fresh directory under the system temp dir, removed again when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("monx-spool-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn item(n: u64, events: u64) -> SpoolItem {
+        SpoolItem { endpoint: "batch".to_string(), events, body: json!({ "n": n }) }
+    }
+
+    fn size(n: u64, events: u64) -> u64 {
+        serde_json::to_vec(&item(n, events)).unwrap().len() as u64
+    }
+
+    fn front(spool: &Spool) -> u64 {
+        spool.front().unwrap().unwrap().body["n"].as_u64().unwrap()
+    }
+
+    #[test]
+    fn delivers_in_order_across_restarts() {
+        let dir = TempDir::new("order");
+        let mut spool = Spool::open(&dir.0, 1 << 20).unwrap();
+        assert!(spool.is_empty() && spool.front().is_none());
+        for n in 0..3 {
+            spool.push(&item(n, 10)).unwrap();
+        }
+        assert_eq!(front(&spool), 0);
+        spool.pop_front();
+
+        let mut spool = Spool::open(&dir.0, 1 << 20).unwrap();
+        assert_eq!(spool.stats().queued_events, 20);
+        assert_eq!(spool.stats().queued_bytes, size(1, 10) + size(2, 10));
+        // New items go after the ones from the previous run
+        spool.push(&item(3, 5)).unwrap();
+        let mut order = Vec::new();
+        while !spool.is_empty() {
+            order.push(front(&spool));
+            spool.pop_front();
+        }
+        assert_eq!(order, [1, 2, 3]);
+        assert_eq!(spool.stats().queued_bytes, 0);
+    }
+
+    #[test]
+    fn ignores_files_it_did_not_write() {
+        let dir = TempDir::new("foreign");
+        std::fs::create_dir_all(&dir.0).unwrap();
+        for name in ["notes.txt", "00000000000000000001-3.tmp", "x-3.json", "7-y.json"] {
+            std::fs::write(dir.0.join(name), "{}").unwrap();
+        }
+        let spool = Spool::open(&dir.0, 1 << 20).unwrap();
+        assert!(spool.is_empty());
+    }
+
+    #[test]
+    fn sheds_the_oldest_items_beyond_max_bytes() {
+        let dir = TempDir::new("cap");
+        let max_bytes = size(0, 1) * 2;
+        let mut spool = Spool::open(&dir.0, max_bytes).unwrap();
+        for n in 0..4 {
+            spool.push(&item(n, 1)).unwrap();
+        }
+        assert_eq!(front(&spool), 2);
+        let stats = spool.stats();
+        assert_eq!((stats.queued_events, stats.dropped_events), (2, 2));
+        assert_eq!(stats.dropped_bytes, size(0, 1) * 2);
+        assert_eq!(std::fs::read_dir(&dir.0).unwrap().count(), 2);
+
+        // Larger than the whole spool: dropped without touching the queue
+        spool.push(&SpoolItem { endpoint: "batch".to_string(), events: 7, body: json!("x".repeat(100)) }).unwrap();
+        assert_eq!(spool.stats().dropped_events, 9);
+        assert_eq!(front(&spool), 2);
+    }
+
+    #[test]
+    fn reports_unreadable_items_so_they_can_be_skipped() {
+        let dir = TempDir::new("corrupt");
+        std::fs::create_dir_all(&dir.0).unwrap();
+        std::fs::write(dir.0.join("00000000000000000000-4.json"), "{not json").unwrap();
+        let mut spool = Spool::open(&dir.0, 1 << 20).unwrap();
+        assert_eq!(spool.stats().queued_events, 4);
+        assert!(spool.front().unwrap().is_err());
+        spool.pop_front();
+        assert!(spool.is_empty());
+    }
+}
@@ -0,0 +1,604 @@
+use notify::{RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc as std_mpsc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use crate::config::Config;
+
+const READ_CHUNK: usize = 64 * 1024;
+const MAX_LINE_BYTES: usize = 1024 * 1024;
+const CHANNEL_CAPACITY: usize = 10_000;
+// Every file's ID where inodes are unavailable, so files can only be told apart by path
+const UNKNOWN_FILE_ID: (u64, u64) = (0, 0);
+
+#[derive(Debug)]
+pub struct TailedLine {
+    pub path: String,
+    pub line: String,
+    // Just past this line, for acknowledging it once it has been delivered or spooled
+    pub position: FileState,
+}
+
+// A position in a file. The persisted one points just past the last line the reporter
+// has delivered or spooled, so lines still in memory are read again after a crash.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FileState {
+    pub inode: u64,
+    pub device: u64,
+    pub offset: u64,
+}
+
+struct OpenFile {
+    file: File,
+    inode: u64,
+    device: u64,
+    // Read position in the file, including any buffered partial line
+    position: u64,
+    partial: Vec<u8>,
+    // Offset up to which lines have been delivered or spooled
+    acked: u64,
+}
+
+impl OpenFile {
+    fn state(&self) -> FileState {
+        FileState { inode: self.inode, device: self.device, offset: self.acked }
+    }
+
+    fn at(&self, offset: u64) -> FileState {
+        FileState { inode: self.inode, device: self.device, offset }
+    }
+}
+
+pub struct Tailer {
+    patterns: Vec<String>,
+    state_file: PathBuf,
+    saved: HashMap<String, FileState>,
+    open: HashMap<String, OpenFile>,
+    // Files present before the first scan with no saved offset start at EOF,
+    // anything discovered later (e.g. after rotation) is read from the start.
+    initial_scan: bool,
+    tx: mpsc::Sender<TailedLine>,
+    acks: std_mpsc::Receiver<Vec<FileState>>,
+}
+
+// Starts the tailer on its own thread. Complete lines arrive on the returned receiver;
+// send their positions back on the returned sender once they are delivered or spooled.
+pub fn spawn(config: &Config) -> (mpsc::Receiver<TailedLine>, std_mpsc::Sender<Vec<FileState>>) {
+    let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let (ack_tx, ack_rx) = std_mpsc::channel();
+    let patterns = config.log_paths.clone();
+    let state_file = PathBuf::from(&config.tailer.state_file);
+    let poll_interval = Duration::from_secs(config.tailer.poll_interval.max(1));
+
+    std::thread::spawn(move || {
+        let mut tailer = Tailer::new(patterns, state_file, tx, ack_rx);
+        tailer.run(poll_interval);
+    });
+
+    (rx, ack_tx)
+}
+
+impl Tailer {
+    fn new(
+        patterns: Vec<String>,
+        state_file: PathBuf,
+        tx: mpsc::Sender<TailedLine>,
+        acks: std_mpsc::Receiver<Vec<FileState>>,
+    ) -> Self {
+        let saved = load_state(&state_file);
+        Self {
+            patterns,
+            state_file,
+            saved,
+            open: HashMap::new(),
+            initial_scan: true,
+            tx,
+            acks,
+        }
+    }
+
+    fn run(&mut self, poll_interval: Duration) {
+        let (event_tx, event_rx) = std_mpsc::channel();
+        let mut watcher = match notify::recommended_watcher(event_tx) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                eprintln!("File watcher unavailable, falling back to polling: {}", e);
+                None
+            }
+        };
+        let mut watched_dirs = HashSet::new();
+
+        loop {
+            if let Some(watcher) = watcher.as_mut() {
+                for dir in self.watch_dirs() {
+                    if !watched_dirs.contains(&dir) && watcher.watch(&dir, RecursiveMode::NonRecursive).is_ok() {
+                        watched_dirs.insert(dir);
+                    }
+                }
+            }
+
+            if !self.poll() {
+                // Receiver dropped, agent is shutting down
+                return;
+            }
+
+            // Sleep until something in a watched directory changes, then coalesce the burst
+            match event_rx.recv_timeout(poll_interval) {
+                Ok(_) | Err(std_mpsc::RecvTimeoutError::Timeout) => {}
+                Err(std_mpsc::RecvTimeoutError::Disconnected) => {
+                    std::thread::sleep(poll_interval);
+                }
+            }
+            while event_rx.try_recv().is_ok() {}
+        }
+    }
+
+    // Directory to watch for each pattern: the longest prefix without glob metacharacters
+    fn watch_dirs(&self) -> Vec<PathBuf> {
+        self.patterns
+            .iter()
+            .filter_map(|pattern| {
+                let mut dir = PathBuf::new();
+                for component in Path::new(pattern).components() {
+                    let part = component.as_os_str().to_string_lossy();
+                    if part.contains(['*', '?', '[']) {
+                        break;
+                    }
+                    dir.push(component);
+                }
+                if dir.as_os_str() == pattern.as_str() {
+                    dir.pop();
+                }
+                dir.is_dir().then_some(dir)
+            })
+            .collect()
+    }
+
+    fn expand_patterns(&self) -> Vec<String> {
+        let mut paths = Vec::new();
+        for pattern in &self.patterns {
+            match glob::glob(pattern) {
+                Ok(entries) => {
+                    for entry in entries.flatten() {
+                        if entry.is_file() {
+                            paths.push(entry.to_string_lossy().to_string());
+                        }
+                    }
+                }
+                Err(e) => eprintln!("Invalid log path pattern {}: {}", pattern, e),
+            }
+        }
+        paths
+    }
+
+    // Reads everything new across all matched files. Returns false once the receiver is gone.
+    fn poll(&mut self) -> bool {
+        let mut paths: Vec<String> = self.expand_patterns();
+        for path in self.open.keys() {
+            if !paths.contains(path) {
+                paths.push(path.clone());
+            }
+        }
+
+        for path in &paths {
+            if !self.open.contains_key(path) {
+                self.follow_rename(path);
+            }
+        }
+        for path in paths {
+            if !self.poll_file(&path) {
+                return false;
+            }
+        }
+        self.initial_scan = false;
+
+        while let Ok(positions) = self.acks.try_recv() {
+            for position in positions {
+                self.ack(position);
+            }
+        }
+        let current: HashMap<String, FileState> = self.open
+            .iter()
+            .map(|(path, open)| (path.clone(), open.state()))
+            .collect();
+        if current != self.saved {
+            self.saved = current;
+            if let Err(e) = save_state(&self.state_file, &self.saved) {
+                eprintln!("Failed to persist tailer state: {}", e);
+            }
+        }
+
+        true
+    }
+
+    // Files that are no longer tailed need no position
+    fn ack(&mut self, position: FileState) {
+        let open = self.open
+            .values_mut()
+            .find(|open| open.inode == position.inode && open.device == position.device);
+        if let Some(open) = open {
+            // Positions from before a truncation no longer apply
+            if position.offset <= open.position {
+                open.acked = position.offset;
+            }
+        }
+    }
+
+    // A file rotated to another matched path (app.log -> app.log.1) keeps its handle and
+    // position under the new path instead of being read again from the start
+    fn follow_rename(&mut self, path: &str) {
+        let Ok(metadata) = std::fs::metadata(path) else { return };
+        let id = file_id(&metadata);
+        if id == UNKNOWN_FILE_ID {
+            return;
+        }
+        let renamed = self.open
+            .iter()
+            .find(|(old, open)| {
+                (open.inode, open.device) == id
+                    && std::fs::metadata(old).map_or(true, |metadata| file_id(&metadata) != id)
+            })
+            .map(|(old, _)| old.clone());
+        if let Some(old) = renamed {
+            let open = self.open.remove(&old).expect("path was just found");
+            self.open.insert(path.to_string(), open);
+        }
+    }
+
+    fn poll_file(&mut self, path: &str) -> bool {
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                // Renamed away or deleted: drain what is left through the old handle and forget it
+                if let Some(mut open) = self.open.remove(path) {
+                    return self.read_new(path, &mut open) && self.flush_partial(path, &mut open);
+                }
+                return true;
+            }
+        };
+        let (inode, device) = file_id(&metadata);
+
+        if let Some(mut open) = self.open.remove(path) {
+            if open.inode == inode && open.device == device {
+                if metadata.len() < open.position {
+                    // Truncated in place (copytruncate rotation)
+                    open.position = 0;
+                    open.partial.clear();
+                    open.acked = 0;
+                }
+                let ok = self.read_new(path, &mut open);
+                self.open.insert(path.to_string(), open);
+                return ok;
+            }
+
+            // Path now points at a new file: finish the rotated one first
+            if !(self.read_new(path, &mut open) && self.flush_partial(path, &mut open)) {
+                return false;
+            }
+            return self.open_file(path, inode, device, 0);
+        }
+
+        // The file may have been saved under the name it had before a rotation renamed it
+        let same_file = |saved: &&FileState| saved.inode == inode && saved.device == device;
+        let saved = self.saved.get(path).filter(same_file).or_else(|| {
+            let known = (inode, device) != UNKNOWN_FILE_ID;
+            self.saved.values().filter(|_| known).find(same_file)
+        });
+        let start = match saved {
+            Some(saved) if saved.offset <= metadata.len() => saved.offset,
+            Some(_) => 0,
+            // The path now holds a different file
+            None if self.saved.contains_key(path) => 0,
+            None if self.initial_scan => metadata.len(),
+            None => 0,
+        };
+        self.open_file(path, inode, device, start)
+    }
+
+    fn open_file(&mut self, path: &str, inode: u64, device: u64, start: u64) -> bool {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Failed to open {}: {}", path, e);
+                return true;
+            }
+        };
+        let mut open = OpenFile {
+            file,
+            inode,
+            device,
+            position: start,
+            partial: Vec::new(),
+            acked: start,
+        };
+        let ok = self.read_new(path, &mut open);
+        self.open.insert(path.to_string(), open);
+        ok
+    }
+
+    fn read_new(&self, path: &str, open: &mut OpenFile) -> bool {
+        if open.file.seek(SeekFrom::Start(open.position)).is_err() {
+            return true;
+        }
+
+        let mut chunk = vec![0u8; READ_CHUNK];
+        loop {
+            let read = match open.file.read(&mut chunk) {
+                Ok(0) => return true,
+                Ok(read) => read,
+                Err(e) => {
+                    eprintln!("Failed to read {}: {}", path, e);
+                    return true;
+                }
+            };
+            let chunk_start = open.position;
+            open.position += read as u64;
+
+            for (i, &byte) in chunk[..read].iter().enumerate() {
+                if byte != b'\n' {
+                    open.partial.push(byte);
+                    if open.partial.len() < MAX_LINE_BYTES {
+                        continue;
+                    }
+                }
+                let line = std::mem::take(&mut open.partial);
+                if !self.emit(path, line, open.at(chunk_start + i as u64 + 1)) {
+                    return false;
+                }
+            }
+        }
+    }
+
+    fn flush_partial(&self, path: &str, open: &mut OpenFile) -> bool {
+        if open.partial.is_empty() {
+            return true;
+        }
+        let line = std::mem::take(&mut open.partial);
+        self.emit(path, line, open.at(open.position))
+    }
+
+    fn emit(&self, path: &str, mut line: Vec<u8>, position: FileState) -> bool {
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        let line = String::from_utf8_lossy(&line).to_string();
+        // Blocks when the collector falls behind instead of dropping lines
+        self.tx.blocking_send(TailedLine { path: path.to_string(), line, position }).is_ok()
+    }
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &std::fs::Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.ino(), metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &std::fs::Metadata) -> (u64, u64) {
+    UNKNOWN_FILE_ID
+}
+
+fn load_state(path: &Path) -> HashMap<String, FileState> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save_state(path: &Path, state: &HashMap<String, FileState>) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+    // Write-then-rename so a crash never leaves a half-written state file
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec(state)?)?;
+    std::fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    // A fresh directory under the system temp dir, removed again when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("monx-tailer-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+
+        fn write(&self, name: &str, content: &str) {
+            std::fs::write(self.path(name), content).unwrap();
+        }
+
+        fn append(&self, name: &str, content: &str) {
+            let mut file = std::fs::OpenOptions::new().append(true).create(true).open(self.path(name)).unwrap();
+            file.write_all(content.as_bytes()).unwrap();
+        }
+
+        fn rename(&self, from: &str, to: &str) {
+            std::fs::rename(self.path(from), self.path(to)).unwrap();
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    struct Harness {
+        tailer: Tailer,
+        lines: mpsc::Receiver<TailedLine>,
+        acks: std_mpsc::Sender<Vec<FileState>>,
+    }
+
+    impl Harness {
+        fn start(dir: &TempDir, pattern: &str) -> Self {
+            let (tx, lines) = mpsc::channel(CHANNEL_CAPACITY);
+            let (acks, ack_rx) = std_mpsc::channel();
+            let pattern = dir.path(pattern).to_string_lossy().into_owned();
+            let tailer = Tailer::new(vec![pattern], dir.path("state.json"), tx, ack_rx);
+            Self { tailer, lines, acks }
+        }
+
+        // Polls once and returns the new lines as "file: line"
+        fn poll(&mut self) -> Vec<String> {
+            assert!(self.tailer.poll());
+            let mut lines = Vec::new();
+            while let Ok(line) = self.lines.try_recv() {
+                let name = Path::new(&line.path).file_name().unwrap().to_string_lossy().into_owned();
+                lines.push(format!("{}: {}", name, line.line));
+            }
+            lines
+        }
+
+        // Polls, acknowledging every line read
+        fn poll_and_ack(&mut self) -> Vec<String> {
+            assert!(self.tailer.poll());
+            let mut lines = Vec::new();
+            let mut positions = Vec::new();
+            while let Ok(line) = self.lines.try_recv() {
+                lines.push(line.line);
+                positions.push(line.position);
+            }
+            self.acks.send(positions).unwrap();
+            // Acknowledgements are saved on the next poll
+            assert!(self.tailer.poll());
+            lines
+        }
+    }
+
+    fn saved(dir: &TempDir) -> Vec<u64> {
+        let mut offsets: Vec<u64> = load_state(&dir.path("state.json")).values().map(|s| s.offset).collect();
+        offsets.sort();
+        offsets
+    }
+
+    #[test]
+    fn existing_files_start_at_the_end_and_new_ones_at_the_start() {
+        let dir = TempDir::new("start");
+        dir.write("old.log", "before\n");
+        let mut harness = Harness::start(&dir, "*.log");
+        assert!(harness.poll().is_empty());
+
+        dir.append("old.log", "after\n");
+        dir.write("new.log", "first\n");
+        let mut lines = harness.poll();
+        lines.sort();
+        assert_eq!(lines, ["new.log: first", "old.log: after"]);
+    }
+
+    #[test]
+    fn holds_partial_lines_until_they_end() {
+        let dir = TempDir::new("partial");
+        dir.write("app.log", "");
+        let mut harness = Harness::start(&dir, "app.log");
+        harness.poll();
+
+        dir.append("app.log", "hel");
+        assert!(harness.poll().is_empty());
+        dir.append("app.log", "lo\r\nworld\n");
+        assert_eq!(harness.poll(), ["app.log: hello", "app.log: world"]);
+    }
+
+    #[test]
+    fn rereads_a_file_truncated_in_place() {
+        let dir = TempDir::new("truncate");
+        dir.write("app.log", "");
+        let mut harness = Harness::start(&dir, "app.log");
+        harness.poll();
+        dir.append("app.log", "one\ntwo\n");
+        assert_eq!(harness.poll().len(), 2);
+
+        // copytruncate
+        dir.write("app.log", "new\n");
+        assert_eq!(harness.poll(), ["app.log: new"]);
+    }
+
+    #[test]
+    fn finishes_a_rotated_file_before_reading_its_replacement() {
+        let dir = TempDir::new("rotate");
+        dir.write("app.log", "");
+        let mut harness = Harness::start(&dir, "app.log");
+        harness.poll();
+
+        dir.append("app.log", "one\ntail");
+        dir.rename("app.log", "app.log.1");
+        dir.write("app.log", "two\n");
+        assert_eq!(harness.poll(), ["app.log: one", "app.log: tail", "app.log: two"]);
+    }
+
+    #[test]
+    fn follows_a_rotated_file_renamed_to_another_matched_path() {
+        let dir = TempDir::new("rename");
+        dir.write("app.log", "");
+        let mut harness = Harness::start(&dir, "app.log*");
+        harness.poll();
+        dir.append("app.log", "one\n");
+        assert_eq!(harness.poll(), ["app.log: one"]);
+
+        dir.append("app.log", "two\n");
+        dir.rename("app.log", "app.log.1");
+        dir.write("app.log", "three\n");
+        let mut lines = harness.poll();
+        lines.sort();
+        assert_eq!(lines, ["app.log.1: two", "app.log: three"]);
+        assert!(harness.poll().is_empty());
+    }
+
+    #[test]
+    fn saves_only_acknowledged_offsets() {
+        let dir = TempDir::new("ack");
+        dir.write("app.log", "");
+        let mut harness = Harness::start(&dir, "app.log");
+        harness.poll();
+        dir.append("app.log", "one\ntwo\n");
+        assert_eq!(harness.poll().len(), 2);
+        assert_eq!(saved(&dir), [0]);
+
+        // Restarted before the lines were delivered: they are read again
+        drop(harness);
+        let mut harness = Harness::start(&dir, "app.log");
+        assert_eq!(harness.poll_and_ack(), ["one", "two"]);
+        assert_eq!(saved(&dir), [8]);
+
+        drop(harness);
+        dir.append("app.log", "three\n");
+        let mut harness = Harness::start(&dir, "app.log");
+        assert_eq!(harness.poll(), ["app.log: three"]);
+    }
+
+    #[test]
+    fn resumes_a_file_renamed_while_stopped() {
+        let dir = TempDir::new("restart");
+        dir.write("app.log", "");
+        let mut harness = Harness::start(&dir, "app.log*");
+        harness.poll();
+        dir.append("app.log", "one\n");
+        assert_eq!(harness.poll_and_ack(), ["one"]);
+        drop(harness);
+
+        dir.append("app.log", "two\n");
+        dir.rename("app.log", "app.log.1");
+        dir.write("app.log", "three\n");
+        let mut harness = Harness::start(&dir, "app.log*");
+        let mut lines = harness.poll();
+        lines.sort();
+        assert_eq!(lines, ["app.log.1: two", "app.log: three"]);
+    }
+}
@@ -0,0 +1,217 @@
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use regex::Regex;
+use std::sync::LazyLock;
+
+// RFC 5424 syslog: "<PRI>VERSION TIMESTAMP ..."
+static RFC5424: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^<\d{1,3}>\d{1,2} (\S+)").unwrap()
+});
+
+// RFC 3339 / ISO 8601, anywhere in the line; the offset is optional
+static RFC3339: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(\d{4}-\d{2}-\d{2})[T ](\d{2}:\d{2}:\d{2}(?:[.,]\d{1,9})?)(Z|[+-]\d{2}:?\d{2})?").unwrap()
+});
+
+// BSD syslog (RFC 3164): "Oct 11 22:14:15", optionally after a "<PRI>"
+static SYSLOG_BSD: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?:<\d{1,3}>)?([A-Z][a-z]{2}) +(\d{1,2}) (\d{2}:\d{2}:\d{2})").unwrap()
+});
+
+// Apache/nginx common log format: "[10/Oct/2000:13:55:36 -0700]"
+static COMMON_LOG: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\[(\d{2}/[A-Z][a-z]{2}/\d{4}:\d{2}:\d{2}:\d{2} [+-]\d{4})\]").unwrap()
+});
+
+// Epoch milliseconds (13 digits) or seconds (10 digits) at the start of the line
+static EPOCH: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(\d{13}|\d{10})(?:\.(\d{1,6}))?\b").unwrap()
+});
+
+// Extracts the event time from a raw log line. Custom strftime `formats` are tried
+// first against the start of the line, then the built-in formats. Timestamps without
+// an offset are interpreted in the agent's local timezone.
+pub fn extract_timestamp(line: &str, formats: &[String]) -> Option<DateTime<Utc>> {
+    let trimmed = line.trim_start().trim_start_matches('[');
+    for format in formats {
+        if let Some(ts) = parse_custom(trimmed, format) {
+            return Some(ts);
+        }
+    }
+
+    if let Some(caps) = RFC5424.captures(line) {
+        if let Ok(ts) = DateTime::parse_from_rfc3339(&caps[1]) {
+            return Some(ts.with_timezone(&Utc));
+        }
+    }
+
+    if let Some(ts) = parse_syslog_bsd(line) {
+        return Some(ts);
+    }
+
+    if let Some(ts) = parse_epoch(trimmed) {
+        return Some(ts);
+    }
+
+    if let Some(ts) = parse_rfc3339(line) {
+        return Some(ts);
+    }
+
+    let caps = COMMON_LOG.captures(line)?;
+    DateTime::parse_from_str(&caps[1], "%d/%b/%Y:%H:%M:%S %z")
+        .ok()
+        .map(|ts| ts.with_timezone(&Utc))
+}
+
+// Parses a timestamp taken from a structured field, where the whole value is the timestamp
+pub fn parse_timestamp_value(value: &str, formats: &[String]) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    if let Ok(ts) = DateTime::parse_from_str(value, "%d/%b/%Y:%H:%M:%S %z") {
+        return Some(ts.with_timezone(&Utc));
+    }
+    extract_timestamp(value, formats)
+}
+
+fn parse_custom(line: &str, format: &str) -> Option<DateTime<Utc>> {
+    if let Ok((ts, _)) = DateTime::parse_and_remainder(line, format) {
+        return Some(ts.with_timezone(&Utc));
+    }
+    if let Ok((naive, _)) = NaiveDateTime::parse_and_remainder(line, format) {
+        return local_to_utc(naive);
+    }
+    None
+}
+
+fn parse_rfc3339(line: &str) -> Option<DateTime<Utc>> {
+    let caps = RFC3339.captures(line)?;
+    let date_time = format!("{}T{}", &caps[1], caps[2].replace(',', "."));
+    match caps.get(3) {
+        Some(offset) => {
+            let offset = match offset.as_str() {
+                "Z" => "+00:00".to_string(),
+                o if o.len() == 5 => format!("{}:{}", &o[..3], &o[3..]),
+                o => o.to_string(),
+            };
+            DateTime::parse_from_rfc3339(&format!("{}{}", date_time, offset))
+                .ok()
+                .map(|ts| ts.with_timezone(&Utc))
+        }
+        None => NaiveDateTime::parse_from_str(&date_time, "%Y-%m-%dT%H:%M:%S%.f")
+            .ok()
+            .and_then(local_to_utc),
+    }
+}
+
+fn parse_syslog_bsd(line: &str) -> Option<DateTime<Utc>> {
+    let caps = SYSLOG_BSD.captures(line)?;
+    let now = Local::now();
+    // BSD syslog has no year: assume the current one unless that lands in the future
+    for year in [now.year(), now.year() - 1] {
+        let text = format!("{} {} {} {}", year, &caps[1], &caps[2], &caps[3]);
+        if let Ok(naive) = NaiveDateTime::parse_from_str(&text, "%Y %b %d %H:%M:%S") {
+            if let Some(ts) = local_to_utc(naive) {
+                if ts <= Utc::now() + chrono::Duration::days(1) {
+                    return Some(ts);
+                }
+            }
+        }
+    }
+    None
+}
+
+fn parse_epoch(line: &str) -> Option<DateTime<Utc>> {
+    let caps = EPOCH.captures(line)?;
+    let whole: i64 = caps[1].parse().ok()?;
+    let ts = if caps[1].len() == 13 {
+        DateTime::from_timestamp_millis(whole)?
+    } else {
+        let nanos = caps.get(2)
+            .map(|frac| format!("{:0<9}", frac.as_str()).parse::<u32>().unwrap_or(0))
+            .unwrap_or(0);
+        DateTime::from_timestamp(whole, nanos)?
+    };
+    // Reject numbers that are clearly not timestamps (before 2000)
+    let cutoff = NaiveDate::from_ymd_opt(2000, 1, 1)?.and_hms_opt(0, 0, 0)?.and_utc();
+    (ts >= cutoff).then_some(ts)
+}
+
+fn local_to_utc(naive: NaiveDateTime) -> Option<DateTime<Utc>> {
+    Local.from_local_datetime(&naive)
+        .earliest()
+        .map(|ts| ts.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().with_timezone(&Utc)
+    }
+
+    fn local(text: &str) -> DateTime<Utc> {
+        let naive = NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f").unwrap();
+        Local.from_local_datetime(&naive).earliest().unwrap().with_timezone(&Utc)
+    }
+
+    fn extract(line: &str) -> Option<DateTime<Utc>> {
+        extract_timestamp(line, &[])
+    }
+
+    #[test]
+    fn finds_rfc3339_anywhere_in_the_line() {
+        assert_eq!(extract("2024-05-01T10:00:00Z started"), Some(utc("2024-05-01T10:00:00Z")));
+        assert_eq!(extract("INFO at 2024-05-01 10:00:00.250+0200 done"), Some(utc("2024-05-01T08:00:00.250Z")));
+        assert_eq!(extract("[2024-05-01T10:00:00,5-05:30] x"), Some(utc("2024-05-01T15:30:00.5Z")));
+        // No offset: the agent's timezone
+        assert_eq!(extract("2024-05-01 10:00:00 started"), Some(local("2024-05-01 10:00:00")));
+    }
+
+    #[test]
+    fn reads_syslog_and_common_log_timestamps() {
+        assert_eq!(
+            extract("<34>1 2003-10-11T22:14:15.003Z mymachine su - ID47 - failed"),
+            Some(utc("2003-10-11T22:14:15.003Z")),
+        );
+        assert_eq!(
+            extract(r#"127.0.0.1 - - [10/Oct/2000:13:55:36 -0700] "GET / HTTP/1.0" 200 1"#),
+            Some(utc("2000-10-10T20:55:36Z")),
+        );
+
+        // BSD syslog has no year, so it is the most recent one not in the future
+        let yesterday = Local::now() - chrono::Duration::days(1);
+        let line = format!("<13>{} host app[1]: hello", yesterday.format("%b %e %H:%M:%S"));
+        let expected = local(&yesterday.format("%Y-%m-%d %H:%M:%S").to_string());
+        assert_eq!(extract(&line), Some(expected));
+    }
+
+    #[test]
+    fn reads_epoch_seconds_and_millis_from_2000_on() {
+        assert_eq!(extract("1700000000123 tick"), Some(utc("2023-11-14T22:13:20.123Z")));
+        assert_eq!(extract("1700000000.5 tick"), Some(utc("2023-11-14T22:13:20.5Z")));
+        assert_eq!(extract("0946684799 tick"), None);
+        assert_eq!(extract("12345 tick"), None);
+        assert_eq!(extract("no time here"), None);
+    }
+
+    #[test]
+    fn tries_custom_formats_first() {
+        let formats = vec!["%d.%m.%Y %H:%M:%S %z".to_string(), "%Y%m%d-%H%M%S".to_string()];
+        assert_eq!(
+            extract_timestamp("01.05.2024 10:00:00 +0000 then 2020-01-01T00:00:00Z", &formats),
+            Some(utc("2024-05-01T10:00:00Z")),
+        );
+        assert_eq!(
+            extract_timestamp("[20240501-100000] started", &formats),
+            Some(local("2024-05-01 10:00:00")),
+        );
+        // Falls through to the built-in formats
+        assert_eq!(extract_timestamp("2024-05-01T10:00:00Z x", &formats), Some(utc("2024-05-01T10:00:00Z")));
+    }
+
+    #[test]
+    fn parses_whole_field_values() {
+        assert_eq!(parse_timestamp_value(" 10/Oct/2000:13:55:36 -0700 ", &[]), Some(utc("2000-10-10T20:55:36Z")));
+        assert_eq!(parse_timestamp_value("1700000000", &[]), Some(utc("2023-11-14T22:13:20Z")));
+        assert_eq!(parse_timestamp_value("yesterday", &[]), None);
+    }
+}
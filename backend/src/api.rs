@@ -1,14 +1,45 @@
-use actix_web::{web, HttpResponse, Result};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
-use std::sync::{Mutex, LazyLock};
+use crate::indexer::{BulkIndexer, IndexerError, Reservation};
+use crate::store::{AggregateQuery, Cursor, GroupBy, LogFilter, LogRecord, LogStore, SearchQuery, SortOrder};
+
+// Most time buckets one aggregation may return
+const MAX_AGGREGATE_BUCKETS: i64 = 10_000;
+// Most groups one field aggregation may return
+const MAX_AGGREGATE_GROUPS: usize = 1000;
+// Largest page of logs one search may return
+const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct LogDeleteQuery {
+    from: String,
+    to: String,
+}
 
 #[derive(Deserialize)]
-pub struct LogQuery {
-    q: Option<String>,
-    from: Option<usize>,
-    size: Option<usize>,
+pub struct MetricsQuery {
+    from: Option<String>,
+    to: Option<String>,
+    // Length of the window ending at `to` (e.g. "15m", "24h"); ignored when `from` is given
+    range: Option<String>,
+    // Comma-separated service names
+    service: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MetricsSeriesQuery {
+    from: Option<String>,
+    to: Option<String>,
+    step: Option<String>,
+    fields: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PatternQuery {
+    service: Option<String>,
+    limit: Option<usize>,
 }
 
 #[derive(Serialize)]
@@ -17,15 +48,26 @@ pub struct ApiResponse<T> {
     data: T,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Agent {
-    pub name: String,
-    pub last_seen: String,
-    pub capabilities: HashMap<String, bool>,
+#[derive(Serialize)]
+pub struct LogPage {
+    // Matches of the whole search, not just this page
+    total: u64,
+    logs: Vec<LogRecord>,
+    // Pass as `cursor` to get the next page; null on the last page
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AgentStatus {
+    #[serde(flatten)]
+    agent: crate::registry::Agent,
+    seconds_since_last_report: f64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AgentMetrics {
+    #[serde(default)]
+    pub agent_id: String,
     pub agent_name: String,
     pub timestamp: String,
     pub cpu_usage: f32,
@@ -33,53 +75,297 @@ pub struct AgentMetrics {
     pub disk_usage: HashMap<String, f64>,
     pub network_rx: u64,
     pub network_tx: u64,
+    #[serde(default)]
+    pub interval_secs: f64,
+    #[serde(default)]
+    pub disks: Vec<DiskStats>,
+    #[serde(default)]
+    pub networks: Vec<NetworkStats>,
+    #[serde(default)]
+    pub spool: SpoolStats,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DiskStats {
+    pub mount_point: String,
+    pub file_system: String,
+    pub total_bytes: u64,
+    pub used_bytes: u64,
+    pub available_bytes: u64,
+    pub usage_percent: f64,
+    pub inodes_total: Option<u64>,
+    pub inodes_used: Option<u64>,
+    pub inodes_free: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NetworkStats {
+    pub interface: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    pub rx_bytes_per_sec: f64,
+    pub tx_bytes_per_sec: f64,
+    pub rx_packets_per_sec: f64,
+    pub tx_packets_per_sec: f64,
+}
+
+// Agent-side delivery buffer: what is waiting to be sent and what was lost to the size cap
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SpoolStats {
+    pub queued_bytes: u64,
+    pub queued_events: u64,
+    pub dropped_bytes: u64,
+    pub dropped_events: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AgentLog {
+    #[serde(default)]
+    pub agent_id: String,
     pub agent_name: String,
-    pub timestamp: String,
+    #[serde(alias = "timestamp")]
+    pub event_time: String,
+    #[serde(default = "now_rfc3339")]
+    pub ingest_time: String,
     pub level: String,
     pub message: String,
     pub source_file: String,
+    #[serde(default)]
+    pub fields: HashMap<String, serde_json::Value>,
 }
 
-static AGENTS: LazyLock<Mutex<HashMap<String, Agent>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
-static AGENT_METRICS: LazyLock<Mutex<Vec<AgentMetrics>>> = LazyLock::new(|| Mutex::new(Vec::new()));
+#[derive(Debug, Deserialize)]
+pub struct AgentBatch {
+    #[serde(default)]
+    pub agent_id: String,
+    #[serde(default)]
+    pub agent_name: String,
+    #[serde(default)]
+    pub metrics: Vec<AgentMetrics>,
+    #[serde(default)]
+    pub logs: Vec<AgentLog>,
+}
 
-pub async fn get_logs(query: web::Query<LogQuery>) -> Result<HttpResponse> {
-    let search_query = query.q.as_deref().unwrap_or("*");
-    let from = query.from.unwrap_or(0);
-    let size = query.size.unwrap_or(50);
+#[derive(Debug, Deserialize)]
+pub struct AgentRegistration {
+    // Agents that predate instance IDs are keyed by name
+    pub id: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub capabilities: HashMap<String, bool>,
+    pub report_interval: Option<u64>,
+    #[serde(default)]
+    pub host: crate::registry::HostInfo,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EnrollmentRequest {
+    pub agent_id: String,
+    pub name: String,
+    pub enrollment_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EnrollmentTokenRequest {
+    // Restricts the token to agents reporting under this service name
+    pub service: Option<String>,
+    pub ttl_secs: Option<i64>,
+}
+
+fn now_rfc3339() -> String {
+    chrono::Utc::now().to_rfc3339()
+}
+
+// Signed submissions may only speak for the agent that signed them, under the
+// service it enrolled with
+fn bind_to_signer(req: &HttpRequest, agent_id: &mut String, agent_name: &mut String) {
+    if let Some(signer) = crate::auth::signed_agent_id(req) {
+        if let Some(service) = crate::credentials::service(&signer) {
+            *agent_name = service;
+        }
+        *agent_id = signer;
+    }
+}
+
+// Submissions from agents without an instance ID fall back to the service name
+fn instance_id<'a>(id: &'a str, name: &'a str) -> &'a str {
+    if id.is_empty() { name } else { id }
+}
+
+
+pub async fn get_logs(req: HttpRequest, store: web::Data<dyn LogStore>) -> Result<HttpResponse> {
+    let bad_request = |message: String| Ok(HttpResponse::BadRequest().json(json!({
+        "success": false,
+        "error": message
+    })));
+    
+    let (filter, params) = match log_params(req.query_string(), &["from", "to", "range", "sort", "size", "cursor"]) {
+        Ok(parsed) => parsed,
+        Err(e) => return bad_request(e),
+    };
+    // Unbounded unless `from`, `to` or `range` is given
+    let end = match params.get("to").map(|to| parse_time(to)).transpose() {
+        Ok(end) => end,
+        Err(e) => return bad_request(e),
+    };
+    let start = match (params.get("from"), params.get("range")) {
+        (Some(from), _) => parse_time(from).map(Some),
+        (None, Some(range)) => parse_duration(range).and_then(|range| before(end.unwrap_or_else(chrono::Utc::now), range)).map(Some),
+        (None, None) => Ok(None),
+    };
+    let start = match start {
+        Ok(start) => start,
+        Err(e) => return bad_request(e),
+    };
+    if let (Some(start), Some(end)) = (start, end) {
+        if start >= end {
+            return bad_request("`from` must be before `to`".to_string());
+        }
+    }
+    let sort = match params.get("sort").map(String::as_str) {
+        None | Some("desc") => SortOrder::Desc,
+        Some("asc") => SortOrder::Asc,
+        Some(other) => return bad_request(format!("sort must be asc or desc, got {}", other)),
+    };
+    let size = match params.get("size").map(|size| size.parse::<usize>()) {
+        None => 50,
+        Some(Ok(size)) if (1..=MAX_PAGE_SIZE).contains(&size) => size,
+        Some(_) => return bad_request(format!("size must be between 1 and {}", MAX_PAGE_SIZE)),
+    };
+    let after = match params.get("cursor").map(|cursor| Cursor::decode(cursor)) {
+        None => None,
+        Some(Some(cursor)) => Some(cursor),
+        Some(None) => return bad_request("invalid cursor".to_string()),
+    };
     
-    match crate::opensearch::search_logs(search_query, from, size).await {
+    let search = SearchQuery { filter, start, end, sort, size, after };
+    match store.search(&search).await {
         Ok(results) => Ok(HttpResponse::Ok().json(ApiResponse {
             success: true,
-            data: results,
+            data: LogPage {
+                total: results.total,
+                logs: results.logs,
+                next_cursor: results.next_cursor.map(|cursor| cursor.encode()),
+            },
         })),
         Err(e) => Ok(HttpResponse::InternalServerError().json(json!({
             "success": false,
-            "error": e.to_string()
+            "error": e
         }))),
     }
 }
 
-pub async fn get_metrics() -> Result<HttpResponse> {
-    // Mock metrics - in production, calculate from OpenSearch aggregations
-    let metrics = json!({
-        "total_logs": 125000,
-        "error_rate": 0.023,
-        "avg_response_time": 145.7,
-        "active_services": 12,
-        "anomalies_detected": crate::ml::get_anomalies().len()
-    });
+pub async fn get_metrics(query: web::Query<MetricsQuery>) -> Result<HttpResponse> {
+    let bad_request = |message: String| Ok(HttpResponse::BadRequest().json(json!({
+        "success": false,
+        "error": message
+    })));
+    
+    let (from, to) = match time_window(query.from.as_deref(), query.to.as_deref(), query.range.as_deref()) {
+        Ok(window) => window,
+        Err(e) => return bad_request(e),
+    };
+    let services: Vec<String> = query.service.as_deref()
+        .map(|s| s.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default();
     
     Ok(HttpResponse::Ok().json(ApiResponse {
         success: true,
-        data: metrics,
+        data: crate::stats::summary(from, to, &services),
     }))
 }
 
+// Takes the filters of /api/logs, plus `by` (a keyword field to group by) or `interval`
+// (the width of event time buckets, e.g. "5m"), and `size` for the number of groups
+pub async fn get_log_aggregate(req: HttpRequest, store: web::Data<dyn LogStore>) -> Result<HttpResponse> {
+    let bad_request = |message: String| Ok(HttpResponse::BadRequest().json(json!({
+        "success": false,
+        "error": message
+    })));
+    
+    let (filter, params) = match log_params(req.query_string(), &["from", "to", "range", "by", "interval", "size"]) {
+        Ok(parsed) => parsed,
+        Err(e) => return bad_request(e),
+    };
+    let param = |name: &str| params.get(name).map(String::as_str);
+    let (start, end) = match time_window(param("from"), param("to"), param("range")) {
+        Ok(window) => window,
+        Err(e) => return bad_request(e),
+    };
+    let group_by = match (param("by"), param("interval")) {
+        (Some(field), None) => {
+            if !matches!(field, "level" | "service" | "trace_id") && !field.starts_with("fields.") {
+                return bad_request(format!("cannot group by {}; use level, service, trace_id or fields.<name>", field));
+            }
+            let size = match param("size").map(str::parse::<usize>) {
+                None => 10,
+                Some(Ok(size)) if (1..=MAX_AGGREGATE_GROUPS).contains(&size) => size,
+                Some(_) => return bad_request(format!("size must be between 1 and {}", MAX_AGGREGATE_GROUPS)),
+            };
+            GroupBy::Field { field: field.to_string(), size }
+        }
+        (None, Some(interval)) => match parse_duration(interval) {
+            Ok(width) if (end - start).num_seconds() / width.num_seconds() > MAX_AGGREGATE_BUCKETS => {
+                return bad_request(format!("interval {} gives more than {} buckets", interval, MAX_AGGREGATE_BUCKETS));
+            }
+            Ok(width) => GroupBy::Interval(width),
+            Err(e) => return bad_request(e),
+        },
+        _ => return bad_request("give exactly one of `by` and `interval`".to_string()),
+    };
+    let aggregate = AggregateQuery {
+        filter,
+        start,
+        end,
+        group_by,
+    };
+    
+    match store.aggregate(&aggregate).await {
+        Ok(buckets) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: buckets,
+        })),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({
+            "success": false,
+            "error": e
+        }))),
+    }
+}
+
+pub async fn delete_logs(store: web::Data<dyn LogStore>, query: web::Query<LogDeleteQuery>) -> Result<HttpResponse> {
+    let (start, end) = match (parse_time(&query.from), parse_time(&query.to)) {
+        (Ok(start), Ok(end)) if start < end => (start, end),
+        (Err(e), _) | (_, Err(e)) => return Ok(HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": e
+        }))),
+        _ => return Ok(HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": "`from` must be before `to`"
+        }))),
+    };
+    
+    match store.delete_range(start, end).await {
+        Ok(deleted) => {
+            println!("Deleted {} logs from {} to {}", deleted, start.to_rfc3339(), end.to_rfc3339());
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: json!({ "deleted": deleted }),
+            }))
+        }
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({
+            "success": false,
+            "error": e
+        }))),
+    }
+}
+
 pub async fn get_anomalies() -> Result<HttpResponse> {
     let anomalies = crate::ml::get_anomalies();
     
@@ -89,59 +375,441 @@ pub async fn get_anomalies() -> Result<HttpResponse> {
     }))
 }
 
-pub async fn register_agent(agent_data: web::Json<serde_json::Value>) -> Result<HttpResponse> {
-    let name = agent_data["name"].as_str().unwrap_or("unknown").to_string();
-    let capabilities = agent_data["capabilities"].as_object()
-        .map(|obj| obj.iter().map(|(k, v)| (k.clone(), v.as_bool().unwrap_or(false))).collect())
-        .unwrap_or_default();
+// Detectors running for each service that has sent logs, with their settings and state
+pub async fn get_detectors() -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        data: crate::ml::detector_states(),
+    }))
+}
+
+// Mined log templates, most frequent first
+pub async fn get_patterns(query: web::Query<PatternQuery>) -> Result<HttpResponse> {
+    let limit = query.limit.unwrap_or(100);
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": format!("limit must be between 1 and {}", MAX_PAGE_SIZE)
+        })));
+    }
     
-    let agent = Agent {
-        name: name.clone(),
-        last_seen: chrono::Utc::now().to_rfc3339(),
-        capabilities,
-    };
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        data: crate::drain::templates(query.service.as_deref(), limit),
+    }))
+}
+
+pub async fn clear_anomalies() -> Result<HttpResponse> {
+    let cleared = crate::ml::clear_anomalies();
+    
+    Ok(HttpResponse::Ok().json(json!({
+        "success": true,
+        "data": { "cleared": cleared }
+    })))
+}
+
+pub async fn register_agent(req: HttpRequest, registration: web::Json<AgentRegistration>) -> Result<HttpResponse> {
+    let mut registration = registration.into_inner();
+    let mut id = registration.id.take().unwrap_or_default();
+    bind_to_signer(&req, &mut id, &mut registration.name);
+    if id.is_empty() {
+        id = registration.name.clone();
+    }
     
-    AGENTS.lock().unwrap().insert(name, agent);
+    let reregistered = crate::registry::register(crate::registry::Registration {
+        id: id.clone(),
+        name: registration.name,
+        capabilities: registration.capabilities,
+        report_interval: registration.report_interval,
+        host: registration.host,
+        labels: registration.labels,
+    });
+    
+    let message = if reregistered { "Agent re-registered" } else { "Agent registered" };
+    Ok(HttpResponse::Ok().json(json!({"success": true, "message": message, "id": id})))
+}
+
+pub async fn receive_agent_metrics(req: HttpRequest, metrics: web::Json<AgentMetrics>) -> Result<HttpResponse> {
+    let mut metrics = metrics.into_inner();
+    bind_to_signer(&req, &mut metrics.agent_id, &mut metrics.agent_name);
+    crate::registry::touch(instance_id(&metrics.agent_id, &metrics.agent_name), &metrics.agent_name);
+    store_agent_metrics(vec![metrics]);
     
-    Ok(HttpResponse::Ok().json(json!({"success": true, "message": "Agent registered"})))
+    Ok(HttpResponse::Ok().json(json!({"success": true})))
 }
 
-pub async fn receive_agent_metrics(metrics: web::Json<AgentMetrics>) -> Result<HttpResponse> {
-    let mut agent_metrics = AGENT_METRICS.lock().unwrap();
-    agent_metrics.push(metrics.into_inner());
+pub async fn receive_agent_logs(req: HttpRequest, indexer: web::Data<BulkIndexer>, logs: web::Json<Vec<AgentLog>>) -> Result<HttpResponse> {
+    let mut logs = logs.into_inner();
+    let reservation = match indexer.reserve(logs.len()).await {
+        Ok(reservation) => reservation,
+        Err(e) => return Ok(indexer_unavailable(e)),
+    };
+    for log in &mut logs {
+        bind_to_signer(&req, &mut log.agent_id, &mut log.agent_name);
+    }
+    if let Some(log) = logs.first() {
+        crate::registry::touch(instance_id(&log.agent_id, &log.agent_name), &log.agent_name);
+    }
+    ingest_agent_logs(reservation, logs).await;
     
-    // Keep only last 1000 metrics
-    let len = agent_metrics.len();
-    if len > 1000 {
-        agent_metrics.drain(0..len - 1000);
+    Ok(HttpResponse::Ok().json(json!({"success": true})))
+}
+
+// Metrics and logs from one agent upload; gzip/zstd bodies are decoded by the Json extractor
+pub async fn receive_agent_batch(req: HttpRequest, indexer: web::Data<BulkIndexer>, batch: web::Json<AgentBatch>) -> Result<HttpResponse> {
+    let mut batch = batch.into_inner();
+    // Nothing is recorded unless the logs can be queued too, so a rejected batch can be resent as is
+    let reservation = match indexer.reserve(batch.logs.len()).await {
+        Ok(reservation) => reservation,
+        Err(e) => return Ok(indexer_unavailable(e)),
+    };
+    bind_to_signer(&req, &mut batch.agent_id, &mut batch.agent_name);
+    for m in &mut batch.metrics {
+        bind_to_signer(&req, &mut m.agent_id, &mut m.agent_name);
+    }
+    for log in &mut batch.logs {
+        bind_to_signer(&req, &mut log.agent_id, &mut log.agent_name);
     }
+    if !batch.agent_name.is_empty() {
+        crate::registry::touch(instance_id(&batch.agent_id, &batch.agent_name), &batch.agent_name);
+    }
+    store_agent_metrics(batch.metrics);
+    ingest_agent_logs(reservation, batch.logs).await;
     
     Ok(HttpResponse::Ok().json(json!({"success": true})))
 }
 
-pub async fn receive_agent_logs(logs: web::Json<Vec<AgentLog>>) -> Result<HttpResponse> {
+// Exchanges a one-time enrollment token for a per-agent signing credential
+pub async fn enroll_agent(request: web::Json<EnrollmentRequest>) -> Result<HttpResponse> {
+    if request.agent_id.is_empty() || request.name.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": "agent_id and name are required"
+        })));
+    }
+    match crate::credentials::enroll(&request.enrollment_token, &request.agent_id, &request.name) {
+        Ok(credential) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: credential,
+        })),
+        Err(e) => {
+            println!("Rejected enrollment of agent {} ({}): {}", request.name, request.agent_id, e);
+            Ok(HttpResponse::Forbidden().json(json!({
+                "success": false,
+                "error": e
+            })))
+        }
+    }
+}
+
+// Called by a signed agent to replace its key
+pub async fn rotate_agent_credential(req: HttpRequest) -> Result<HttpResponse> {
+    let Some(agent_id) = crate::auth::signed_agent_id(&req) else {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": "credential rotation requires a signed request"
+        })));
+    };
+    match crate::credentials::rotate(&agent_id) {
+        Ok(credential) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: credential,
+        })),
+        Err(e) => Ok(HttpResponse::Forbidden().json(json!({
+            "success": false,
+            "error": e
+        }))),
+    }
+}
+
+pub async fn get_credentials() -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        data: crate::credentials::list(),
+    }))
+}
+
+// Flags the credential so the agent rotates on its next submission
+pub async fn request_credential_rotation(path: web::Path<String>) -> Result<HttpResponse> {
+    credential_admin_response(&path, crate::credentials::request_rotation(&path))
+}
+
+pub async fn revoke_credential(path: web::Path<String>) -> Result<HttpResponse> {
+    credential_admin_response(&path, crate::credentials::revoke(&path))
+}
+
+fn credential_admin_response(agent_id: &str, result: std::result::Result<Option<crate::credentials::CredentialInfo>, String>) -> Result<HttpResponse> {
+    match result {
+        Ok(Some(credential)) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: credential,
+        })),
+        Ok(None) => Ok(HttpResponse::NotFound().json(json!({
+            "success": false,
+            "error": format!("no active credential for agent {}", agent_id)
+        }))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({
+            "success": false,
+            "error": e
+        }))),
+    }
+}
+
+pub async fn get_enrollment_tokens() -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        data: crate::credentials::list_enrollment_tokens(),
+    }))
+}
+
+// The token is only ever returned here
+pub async fn create_enrollment_token(request: web::Json<EnrollmentTokenRequest>) -> Result<HttpResponse> {
+    let request = request.into_inner();
+    if request.ttl_secs.is_some_and(|ttl| ttl <= 0) {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": "ttl_secs must be positive"
+        })));
+    }
+    let ttl = match request.ttl_secs.map(chrono::Duration::try_seconds) {
+        Some(None) => return Ok(HttpResponse::BadRequest().json(json!({
+            "success": false,
+            "error": "ttl_secs is too large"
+        }))),
+        ttl => ttl.flatten(),
+    };
+    match crate::credentials::create_enrollment_token(request.service, ttl) {
+        Ok((info, token)) => Ok(HttpResponse::Ok().json(json!({
+            "success": true,
+            "data": { "token": token, "info": info }
+        }))),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(json!({
+            "success": false,
+            "error": e
+        }))),
+    }
+}
+
+fn store_agent_metrics(metrics: Vec<AgentMetrics>) {
+    for m in &metrics {
+        crate::timeseries::record(instance_id(&m.agent_id, &m.agent_name), m);
+    }
+}
+
+async fn ingest_agent_logs(mut reservation: Reservation<'_>, logs: Vec<AgentLog>) {
     // Convert agent logs to standard log format and process
-    for log in logs.iter() {
+    for mut log in logs {
+        if !log.agent_id.is_empty() {
+            log.fields.insert("agent_id".to_string(), serde_json::Value::from(log.agent_id));
+        }
         let log_event = crate::kafka::LogEvent {
-            timestamp: log.timestamp.clone(),
-            level: log.level.clone(),
-            message: log.message.clone(),
-            service: log.agent_name.clone(),
-            trace_id: None,
+            event_time: log.event_time,
+            ingest_time: log.ingest_time,
+            level: log.level,
+            message: log.message,
+            service: log.agent_name,
+            trace_id: log.fields.get("trace_id").and_then(|v| v.as_str()).map(str::to_string),
+            fields: log.fields,
         };
         
+        // The agent's own heartbeat is not application traffic
+        if log.source_file != "agent" {
+            crate::stats::record(&log_event);
+        }
+        
         // Process through ML pipeline
         crate::ml::analyze_event(&log_event).await;
         
-        // Store in OpenSearch
-        crate::opensearch::index_log(&log_event).await;
+        // Queue for storage
+        reservation.send(&log_event).await;
     }
+}
+
+// Agents treat a 503 as retryable and keep the upload in their spool
+fn indexer_unavailable(error: IndexerError) -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .insert_header(("Retry-After", "1"))
+        .json(json!({
+            "success": false,
+            "error": error.to_string()
+        }))
+}
+
+pub async fn get_indexer_stats(indexer: web::Data<BulkIndexer>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        data: indexer.stats(),
+    }))
+}
+
+pub async fn get_agent_metrics(path: web::Path<String>, query: web::Query<MetricsSeriesQuery>) -> Result<HttpResponse> {
+    let agent_id = path.into_inner();
+    let bad_request = |message: String| Ok(HttpResponse::BadRequest().json(json!({
+        "success": false,
+        "error": message
+    })));
     
-    Ok(HttpResponse::Ok().json(json!({"success": true})))
+    let to = match query.to.as_deref().map(parse_time).transpose() {
+        Ok(to) => to.unwrap_or_else(chrono::Utc::now),
+        Err(e) => return bad_request(e),
+    };
+    let from = match query.from.as_deref().map(parse_time).transpose() {
+        Ok(Some(from)) => from,
+        Ok(None) => match before(to, chrono::Duration::hours(1)) {
+            Ok(from) => from,
+            Err(e) => return bad_request(e),
+        },
+        Err(e) => return bad_request(e),
+    };
+    if from >= to {
+        return bad_request("`from` must be before `to`".to_string());
+    }
+    let step = match query.step.as_deref().map(parse_duration).transpose() {
+        Ok(step) => step,
+        Err(e) => return bad_request(e),
+    };
+    let fields = query.fields.as_deref()
+        .map(|f| f.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default();
+    
+    let series_query = crate::timeseries::SeriesQuery { from, to, step, fields };
+    match crate::timeseries::query(&agent_id, &series_query) {
+        Some(result) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: result,
+        })),
+        None => Ok(HttpResponse::NotFound().json(json!({
+            "success": false,
+            "error": format!("no metrics for agent {}", agent_id)
+        }))),
+    }
+}
+
+// Splits a log query string into the filters shared by the log endpoints and the endpoint's
+// own parameters, which must be in `allowed`. Filters may repeat or hold comma-separated
+// lists: ?service=api,web&level=ERROR&text=timeout&fields.status=500
+fn log_params(query: &str, allowed: &[&str]) -> std::result::Result<(LogFilter, HashMap<String, String>), String> {
+    let mut filter = LogFilter::default();
+    let mut params = HashMap::new();
+    let list = |value: &str| value.split(',').map(str::trim).filter(|v| !v.is_empty()).map(str::to_string).collect::<Vec<_>>();
+    
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "service" => filter.services.extend(list(&value)),
+            "level" => filter.levels.extend(list(&value)),
+            "trace_id" => filter.trace_id = Some(value.into_owned()),
+            "text" => filter.text = Some(value.into_owned()),
+            key if key.starts_with("fields.") && key.len() > "fields.".len() => {
+                filter.fields.push((key["fields.".len()..].to_string(), value.into_owned()));
+            }
+            key if allowed.contains(&key) => {
+                params.insert(key.to_string(), value.into_owned());
+            }
+            key => return Err(format!("unknown parameter {}", key)),
+        }
+    }
+    Ok((filter, params))
+}
+
+// `from`/`to` as given, `to` defaulting to now and `from` to `range` (default 1h) before it
+fn time_window(
+    from: Option<&str>,
+    to: Option<&str>,
+    range: Option<&str>,
+) -> std::result::Result<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>), String> {
+    let to = to.map(parse_time).transpose()?.unwrap_or_else(chrono::Utc::now);
+    let range = range.map(parse_duration).transpose()?.unwrap_or(chrono::Duration::hours(1));
+    let from = match from {
+        Some(from) => parse_time(from)?,
+        None => before(to, range)?,
+    };
+    if from >= to {
+        return Err("`from` must be before `to`".to_string());
+    }
+    Ok((from, to))
+}
+
+// Accepts RFC3339 or epoch milliseconds
+fn parse_time(value: &str) -> std::result::Result<chrono::DateTime<chrono::Utc>, String> {
+    if let Ok(millis) = value.parse::<i64>() {
+        return chrono::DateTime::from_timestamp_millis(millis).ok_or_else(|| format!("invalid timestamp {}", value));
+    }
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map(|ts| ts.with_timezone(&chrono::Utc))
+        .map_err(|e| format!("invalid timestamp {}: {}", value, e))
+}
+
+// Accepts plain seconds or a number with an s/m/h/d suffix
+fn parse_duration(value: &str) -> std::result::Result<chrono::Duration, String> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let number: i64 = number.parse().map_err(|_| format!("invalid duration {}", value))?;
+    let seconds = match unit {
+        "s" => Some(number),
+        "m" => number.checked_mul(60),
+        "h" => number.checked_mul(3600),
+        "d" => number.checked_mul(86400),
+        _ => return Err(format!("invalid duration unit in {}", value)),
+    };
+    if seconds.is_some_and(|seconds| seconds <= 0) {
+        return Err(format!("duration {} must be positive", value));
+    }
+    seconds
+        .and_then(chrono::Duration::try_seconds)
+        .ok_or_else(|| format!("duration {} is too long", value))
+}
+
+// The time `range` before `end`, if there is one
+fn before(end: chrono::DateTime<chrono::Utc>, range: chrono::Duration) -> std::result::Result<chrono::DateTime<chrono::Utc>, String> {
+    end.checked_sub_signed(range)
+        .ok_or_else(|| format!("range of {}s reaches before the earliest supported time", range.num_seconds()))
+}
+
+pub async fn get_services() -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        data: crate::registry::services(),
+    }))
+}
+
+// Drops the agent from the registry along with its credential, so it has to enroll again
+pub async fn remove_agent(path: web::Path<String>) -> Result<HttpResponse> {
+    let id = path.into_inner();
+    let credential_removed = match crate::credentials::remove(&id) {
+        Ok(removed) => removed,
+        Err(e) => return Ok(HttpResponse::InternalServerError().json(json!({
+            "success": false,
+            "error": e
+        }))),
+    };
+    let agent = crate::registry::remove(&id);
+    if let Some(agent) = &agent {
+        println!("Agent {} ({}) removed from registry", agent.name, agent.id);
+    }
+    if agent.is_none() && !credential_removed {
+        return Ok(HttpResponse::NotFound().json(json!({
+            "success": false,
+            "error": format!("unknown agent {}", id)
+        })));
+    }
+    
+    Ok(HttpResponse::Ok().json(json!({
+        "success": true,
+        "data": { "agent": agent, "credential_removed": credential_removed }
+    })))
 }
 
 pub async fn get_agents() -> Result<HttpResponse> {
-    let agents: Vec<Agent> = AGENTS.lock().unwrap().values().cloned().collect();
+    let now = chrono::Utc::now();
+    let agents: Vec<AgentStatus> = crate::registry::list()
+        .into_iter()
+        .map(|agent| AgentStatus {
+            seconds_since_last_report: (now - agent.last_seen).num_milliseconds() as f64 / 1000.0,
+            agent,
+        })
+        .collect();
     
     Ok(HttpResponse::Ok().json(ApiResponse {
         success: true,
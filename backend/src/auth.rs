@@ -0,0 +1,316 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue, AUTHORIZATION, UPGRADE, WWW_AUTHENTICATE};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse};
+use futures_util::StreamExt;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use crate::credentials;
+use crate::tls::PeerCertificate;
+
+// What a caller may do. Viewer < operator < admin form a hierarchy; agents
+// authenticate with signed requests and may only submit data.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Operator,
+    Admin,
+    Agent,
+}
+
+impl Role {
+    pub fn grants(self, required: Role) -> bool {
+        matches!(
+            (self, required),
+            (Role::Admin, Role::Viewer | Role::Operator | Role::Admin)
+                | (Role::Operator, Role::Viewer | Role::Operator)
+                | (Role::Viewer, Role::Viewer)
+                | (Role::Agent, Role::Agent)
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Claims {
+    pub sub: String,
+    pub role: Role,
+    pub exp: u64,
+    #[serde(default)]
+    pub iss: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+pub enum JwtAlgorithm {
+    HS256,
+    RS256,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // Local development only: every caller is treated as an admin
+    pub disabled: bool,
+    pub algorithm: JwtAlgorithm,
+    // HS256: file holding the shared secret. RS256: PEM-encoded public key.
+    pub key_file: Option<String>,
+    // Tokens must carry this `iss` when set
+    pub issuer: Option<String>,
+    // Seconds of clock skew tolerated when checking `exp`
+    pub leeway: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            disabled: false,
+            algorithm: JwtAlgorithm::HS256,
+            key_file: None,
+            issuer: None,
+            leeway: 30,
+        }
+    }
+}
+
+pub struct Authenticator {
+    // None when auth is disabled
+    key: Option<DecodingKey>,
+    validation: Validation,
+    // Signed bodies are buffered for verification, so they get the same cap as JSON bodies
+    max_body_bytes: usize,
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig, max_body_bytes: usize) -> Result<Self, String> {
+        let algorithm = match config.algorithm {
+            JwtAlgorithm::HS256 => Algorithm::HS256,
+            JwtAlgorithm::RS256 => Algorithm::RS256,
+        };
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = config.leeway;
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+            validation.set_required_spec_claims(&["exp", "sub", "iss"]);
+        }
+
+        if config.disabled {
+            println!("WARNING: API authentication is disabled");
+            return Ok(Self { key: None, validation, max_body_bytes });
+        }
+
+        let path = config.key_file.as_deref()
+            .ok_or("auth.key_file must be set (or auth.disabled for local development)")?;
+        let data = std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+        let key = match config.algorithm {
+            JwtAlgorithm::RS256 => DecodingKey::from_rsa_pem(&data)
+                .map_err(|e| format!("invalid RSA public key in {}: {}", path, e))?,
+            JwtAlgorithm::HS256 => {
+                let secret = String::from_utf8_lossy(&data).trim().to_string();
+                if secret.len() < 32 {
+                    return Err(format!("HS256 secret in {} must be at least 32 bytes", path));
+                }
+                DecodingKey::from_secret(secret.as_bytes())
+            }
+        };
+
+        Ok(Self { key: Some(key), validation, max_body_bytes })
+    }
+
+    fn verify(&self, token: Option<&str>) -> Result<Claims, String> {
+        let Some(key) = &self.key else {
+            // Auth disabled: everyone is an admin
+            return Ok(Claims { sub: "anonymous".to_string(), role: Role::Admin, exp: 0, iss: None });
+        };
+        let token = token.ok_or("missing bearer token")?;
+        jsonwebtoken::decode::<Claims>(token, key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|e| format!("invalid token: {}", e))
+    }
+}
+
+// Authenticates every request to the routes it wraps, so nothing behind it is public by
+// accident. Callers present a bearer token, or sign their request or present a client
+// certificate as an agent. Roles are checked per route by the `require_*` middleware.
+pub async fn authenticate(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(authenticator) = req.app_data::<web::Data<Authenticator>>().cloned() else {
+        return Ok(reject(req, StatusCode::INTERNAL_SERVER_ERROR, "authentication is not configured"));
+    };
+    let token = bearer_token(&req);
+    let signed = [
+        credentials::AGENT_ID_HEADER,
+        credentials::KEY_ID_HEADER,
+        credentials::TIMESTAMP_HEADER,
+        credentials::NONCE_HEADER,
+        credentials::SIGNATURE_HEADER,
+    ]
+        .iter()
+        .any(|name| req.headers().contains_key(*name));
+    let certified = req.conn_data::<PeerCertificate>().is_some_and(|cert| cert.common_name.is_some());
+    if token.is_none() && (signed || certified) {
+        return authenticate_agent(req, next, &authenticator).await;
+    }
+    if token.is_none() && authenticator.key.is_some() {
+        return Ok(reject(req, StatusCode::UNAUTHORIZED, "missing bearer token, agent signature or client certificate"));
+    }
+
+    let claims = match authenticator.verify(token.as_deref()) {
+        Ok(claims) => claims,
+        Err(e) => return Ok(reject(req, StatusCode::UNAUTHORIZED, &e)),
+    };
+    req.extensions_mut().insert(claims);
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+pub async fn require_viewer(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    authorize(req, next, Role::Viewer).await
+}
+
+pub async fn require_operator(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    authorize(req, next, Role::Operator).await
+}
+
+pub async fn require_admin(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    authorize(req, next, Role::Admin).await
+}
+
+pub async fn require_agent(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    authorize(req, next, Role::Agent).await
+}
+
+// Agent submissions are signed with the per-agent credential issued at enrollment
+// (see `credentials`) or sent over mutual TLS, rather than carrying a JWT
+async fn authenticate_agent<B: MessageBody + 'static>(
+    mut req: ServiceRequest,
+    next: Next<B>,
+    authenticator: &Authenticator,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    // A client certificate verified against the TLS client CA names the agent by its CN
+    let certificate = req.conn_data::<PeerCertificate>().cloned();
+    let certificate_agent = certificate.as_ref().and_then(|cert| cert.common_name.clone());
+
+    let header = |name: &str| req.headers().get(name).and_then(|h| h.to_str().ok()).map(str::to_string);
+    let signature_headers = (
+        header(credentials::AGENT_ID_HEADER),
+        header(credentials::KEY_ID_HEADER),
+        header(credentials::TIMESTAMP_HEADER),
+        header(credentials::NONCE_HEADER),
+        header(credentials::SIGNATURE_HEADER),
+    );
+    let (Some(agent_id), Some(key_id), Some(timestamp), Some(nonce), Some(signature)) = signature_headers else {
+        let claims = match certificate_agent {
+            Some(agent_id) if credentials::is_revoked(&agent_id) => {
+                return Ok(reject(req, StatusCode::FORBIDDEN, &format!("credential for agent {} was revoked", agent_id)));
+            }
+            Some(agent_id) => Claims { sub: agent_id, role: Role::Agent, exp: 0, iss: None },
+            None => return Ok(reject(req, StatusCode::UNAUTHORIZED, "missing agent signature headers or client certificate")),
+        };
+        req.extensions_mut().insert(claims);
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    if let Some(certificate) = certificate.filter(|_| certificate_agent.as_deref() != Some(agent_id.as_str())) {
+        let message = format!("request signed by agent {} over a connection certified for {}", agent_id, certificate.subject);
+        return Ok(reject(req, StatusCode::FORBIDDEN, &message));
+    }
+    if authenticator.key.is_none() {
+        req.extensions_mut().insert(Claims { sub: agent_id, role: Role::Agent, exp: 0, iss: None });
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    // The signature covers the body exactly as sent, before any Content-Encoding is undone
+    let mut payload = req.take_payload();
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > authenticator.max_body_bytes {
+            return Ok(reject(req, StatusCode::PAYLOAD_TOO_LARGE, "request body is too large"));
+        }
+    }
+    let body = body.freeze();
+
+    let verified = credentials::verify(
+        &agent_id,
+        &key_id,
+        &timestamp,
+        &nonce,
+        &signature,
+        req.method().as_str(),
+        req.path(),
+        &body,
+    );
+    let verified = match verified {
+        Ok(verified) => verified,
+        Err(credentials::VerifyError::Invalid(e)) => return Ok(reject(req, StatusCode::UNAUTHORIZED, &e)),
+        Err(credentials::VerifyError::Rejected(e)) => return Ok(reject(req, StatusCode::FORBIDDEN, &e)),
+    };
+
+    req.set_payload(body.into());
+    req.extensions_mut().insert(Claims { sub: agent_id, role: Role::Agent, exp: 0, iss: None });
+    let mut response = next.call(req).await?;
+    if verified.rotation_requested {
+        response.headers_mut().insert(
+            HeaderName::from_static(credentials::ROTATE_HEADER),
+            HeaderValue::from_static("required"),
+        );
+    }
+    Ok(response.map_into_left_body())
+}
+
+// Checks the role of the caller `authenticate` let through
+async fn authorize(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+    required: Role,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(authenticator) = req.app_data::<web::Data<Authenticator>>().cloned() else {
+        return Ok(reject(req, StatusCode::INTERNAL_SERVER_ERROR, "authentication is not configured"));
+    };
+    let Some(role) = req.extensions().get::<Claims>().map(|claims| claims.role) else {
+        return Ok(reject(req, StatusCode::UNAUTHORIZED, "request was not authenticated"));
+    };
+    // Auth disabled: any caller may call anything
+    if authenticator.key.is_some() && !role.grants(required) {
+        let message = format!("role {:?} may not call this endpoint", role).to_lowercase();
+        return Ok(reject(req, StatusCode::FORBIDDEN, &message));
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+// The agent a request was signed by or certified for, if it came through `require_agent`
+pub fn signed_agent_id(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<Claims>()
+        .filter(|claims| claims.role == Role::Agent)
+        .map(|claims| claims.sub.clone())
+}
+
+// `Authorization: Bearer <token>`, or an `access_token` query parameter for
+// WebSocket upgrades since browsers cannot set headers on those
+fn bearer_token(req: &ServiceRequest) -> Option<String> {
+    if let Some(header) = req.headers().get(AUTHORIZATION).and_then(|h| h.to_str().ok()) {
+        return header.strip_prefix("Bearer ").map(|t| t.trim().to_string());
+    }
+    let is_upgrade = req.headers().get(UPGRADE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.eq_ignore_ascii_case("websocket"));
+    if !is_upgrade {
+        return None;
+    }
+    url::form_urlencoded::parse(req.query_string().as_bytes())
+        .find(|(key, _)| key == "access_token")
+        .map(|(_, value)| value.into_owned())
+}
+
+fn reject<B>(req: ServiceRequest, status: StatusCode, message: &str) -> ServiceResponse<EitherBody<B>> {
+    let mut builder = HttpResponse::build(status);
+    if status == StatusCode::UNAUTHORIZED {
+        builder.insert_header((WWW_AUTHENTICATE, "Bearer"));
+    }
+    let response = builder.json(json!({
+        "success": false,
+        "error": message
+    }));
+    req.into_response(response).map_into_right_body()
+}
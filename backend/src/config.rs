@@ -0,0 +1,317 @@
+use serde::{Deserialize, Deserializer};
+use serde_yaml::{Mapping, Value};
+use std::net::ToSocketAddrs;
+use crate::auth::AuthConfig;
+use crate::credentials::CredentialsConfig;
+use crate::indexer::IndexerConfig;
+use crate::kafka::KafkaConfig;
+use crate::ml::MlConfig;
+use crate::opensearch::OpenSearchConfig;
+use crate::registry::LivenessConfig;
+use crate::stats::StatsConfig;
+use crate::store::StorageConfig;
+use crate::timeseries::RetentionConfig;
+use crate::tls::TlsConfig;
+
+// Read when neither --config nor MONX_CONFIG names a file
+const DEFAULT_CONFIG_FILE: &str = "config.yaml";
+// MONX_KAFKA__BROKERS overrides kafka.brokers; `__` separates nesting levels
+const ENV_PREFIX: &str = "MONX_";
+
+pub const USAGE: &str = "\
+Usage: ml-monitoring-backend [OPTIONS]
+
+Options:
+  --config <FILE>       Configuration file (default: $MONX_CONFIG, else ./config.yaml if present)
+  --bind <ADDR>         Listen address, same as --set server.bind=<ADDR>
+  --set <KEY>=<VALUE>   Override a setting by its dotted path, e.g. --set kafka.topic=logs
+  --check               Validate the configuration and exit
+  --help                Show this message
+
+Settings are layered: defaults, then the file, then MONX_<SECTION>__<KEY>
+environment variables, then command-line flags.";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    // Largest accepted JSON body in bytes; agent batches can be large once decompressed
+    pub json_limit: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1:8080".to_string(),
+            json_limit: 32 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub kafka: KafkaConfig,
+    pub storage: StorageConfig,
+    pub opensearch: OpenSearchConfig,
+    pub indexer: IndexerConfig,
+    pub ml: MlConfig,
+    pub auth: AuthConfig,
+    pub credentials: CredentialsConfig,
+    pub tls: TlsConfig,
+    pub liveness: LivenessConfig,
+    pub timeseries: RetentionConfig,
+    pub stats: StatsConfig,
+}
+
+#[derive(Debug, Default)]
+pub struct Args {
+    pub config_file: Option<String>,
+    // Dotted path and raw value, in command-line order
+    pub overrides: Vec<(String, String)>,
+    pub check: bool,
+    pub help: bool,
+}
+
+impl Args {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // Accept both `--flag value` and `--flag=value`
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+                _ => (arg, None),
+            };
+            let mut value = || inline.clone().or_else(|| args.next()).ok_or(format!("{} needs a value", flag));
+            match flag.as_str() {
+                "--config" => parsed.config_file = Some(value()?),
+                "--bind" => parsed.overrides.push(("server.bind".to_string(), value()?)),
+                "--set" => {
+                    let setting = value()?;
+                    let (key, value) = setting.split_once('=')
+                        .ok_or(format!("--set expects KEY=VALUE, got {}", setting))?;
+                    parsed.overrides.push((key.trim().to_string(), value.to_string()));
+                }
+                "--check" => parsed.check = true,
+                "--help" | "-h" => parsed.help = true,
+                other => return Err(format!("unknown argument {}", other)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+// Builds the configuration from every layer and validates it, reporting all problems at once
+pub fn load(args: &Args) -> Result<Config, Vec<String>> {
+    let mut tree = read_file(args).map_err(|e| vec![e])?;
+
+    let mut env: Vec<(String, String)> = std::env::vars()
+        .filter(|(key, _)| key.starts_with(ENV_PREFIX) && key != "MONX_CONFIG")
+        .collect();
+    env.sort();
+    for (key, value) in env {
+        let path = key[ENV_PREFIX.len()..].to_lowercase().replace("__", ".");
+        set(&mut tree, &path, &value).map_err(|e| vec![format!("{}: {}", key, e)])?;
+    }
+    for (path, value) in &args.overrides {
+        set(&mut tree, path, value).map_err(|e| vec![format!("--set {}: {}", path, e)])?;
+    }
+
+    let config: Config = serde_path_to_error::deserialize(tree)
+        .map_err(|e| vec![format!("{}: {}", e.path(), e.inner())])?;
+    let errors = config.validate();
+    if errors.is_empty() { Ok(config) } else { Err(errors) }
+}
+
+fn read_file(args: &Args) -> Result<Value, String> {
+    let explicit = args.config_file.clone()
+        .or_else(|| std::env::var("MONX_CONFIG").ok().filter(|v| !v.is_empty()));
+    let path = match explicit {
+        Some(path) => path,
+        None if std::path::Path::new(DEFAULT_CONFIG_FILE).exists() => DEFAULT_CONFIG_FILE.to_string(),
+        None => return Ok(Value::Mapping(Mapping::new())),
+    };
+
+    let data = std::fs::read_to_string(&path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+    match serde_yaml::from_str(&data).map_err(|e| format!("failed to parse {}: {}", path, e))? {
+        Value::Null => Ok(Value::Mapping(Mapping::new())),
+        value @ Value::Mapping(_) => {
+            println!("Loaded configuration from {}", path);
+            Ok(value)
+        }
+        _ => Err(format!("{} must contain a mapping of sections", path)),
+    }
+}
+
+// A list that may also be written as one comma-separated string, which is easier to pass in an environment variable
+pub fn string_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringList {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match StringList::deserialize(deserializer)? {
+        StringList::One(s) => s.split(',').map(str::trim).filter(|s| !s.is_empty()).map(str::to_string).collect(),
+        StringList::Many(list) => list,
+    })
+}
+
+// Sets a dotted path in the tree; the value is read as YAML so numbers and booleans keep
+// their type. An empty value removes the setting, restoring its default.
+fn set(tree: &mut Value, path: &str, raw: &str) -> Result<(), String> {
+    let keys: Vec<&str> = path.split('.').collect();
+    if keys.iter().any(|key| key.is_empty()) {
+        return Err(format!("invalid setting name {}", path));
+    }
+    let value = match raw.trim() {
+        "" => None,
+        trimmed => Some(serde_yaml::from_str(trimmed).map_err(|e| format!("invalid value {}: {}", raw, e))?),
+    };
+
+    let (last, parents) = keys.split_last().expect("split always yields a key");
+    let mut node = tree;
+    for key in parents {
+        if !node.is_mapping() {
+            *node = Value::Mapping(Mapping::new());
+        }
+        let Value::Mapping(map) = node else { unreachable!() };
+        node = map.entry(Value::String(key.to_string())).or_insert(Value::Null);
+    }
+    if !node.is_mapping() {
+        *node = Value::Mapping(Mapping::new());
+    }
+    let Value::Mapping(map) = node else { unreachable!() };
+    match value {
+        Some(value) => { map.insert(Value::String(last.to_string()), value); }
+        None => { map.remove(last); }
+    }
+    Ok(())
+}
+
+impl Config {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, message: &str| {
+            if !ok {
+                errors.push(message.to_string());
+            }
+        };
+
+        check(
+            self.server.bind.to_socket_addrs().is_ok_and(|mut addrs| addrs.next().is_some()),
+            &format!("server.bind {} is not a valid host:port address", self.server.bind),
+        );
+        check(self.server.json_limit > 0, "server.json_limit must be greater than 0");
+
+        check(!self.kafka.brokers.trim().is_empty(), "kafka.brokers must not be empty");
+        check(!self.kafka.group_id.trim().is_empty(), "kafka.group_id must not be empty");
+        check(!self.kafka.topic.trim().is_empty(), "kafka.topic must not be empty");
+        check(
+            matches!(self.kafka.auto_offset_reset.as_str(), "earliest" | "latest" | "error"),
+            &format!("kafka.auto_offset_reset {} must be earliest, latest or error", self.kafka.auto_offset_reset),
+        );
+
+        let local = &self.storage.local;
+        check(!local.path.is_empty(), "storage.local.path must not be empty");
+        check(local.segment_size > 0, "storage.local.segment_size must be greater than 0");
+
+        let opensearch = &self.opensearch;
+        check(!opensearch.nodes.is_empty(), "opensearch.nodes must list at least one node");
+        for node in &opensearch.nodes {
+            check(
+                url::Url::parse(node).is_ok_and(|url| matches!(url.scheme(), "http" | "https")),
+                &format!("opensearch.nodes entry {} is not a valid http(s) URL", node),
+            );
+        }
+        check(
+            opensearch.password.is_none() || opensearch.password_file.is_none(),
+            "opensearch.password and opensearch.password_file are mutually exclusive",
+        );
+        check(
+            opensearch.username.is_some() || (opensearch.password.is_none() && opensearch.password_file.is_none()),
+            "opensearch.password needs opensearch.username",
+        );
+        check(opensearch.timeout > 0, "opensearch.timeout must be greater than 0");
+        check(
+            !opensearch.index_prefix.is_empty()
+                && !opensearch.index_prefix.starts_with(['-', '_', '+'])
+                && opensearch.index_prefix.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '_' | '.')),
+            &format!("opensearch.index_prefix {} is not a valid index name", opensearch.index_prefix),
+        );
+        let period = opensearch.rollover.period_days();
+        check(
+            opensearch.retention_days == 0 || opensearch.retention_days >= period,
+            &format!(
+                "opensearch.retention_days {} is shorter than an index's rollover period of {} days; use daily rollover",
+                opensearch.retention_days, period,
+            ),
+        );
+        check(opensearch.shards > 0, "opensearch.shards must be greater than 0");
+
+        let indexer = &self.indexer;
+        check(indexer.queue_capacity > 0, "indexer.queue_capacity must be greater than 0");
+        check(
+            indexer.batch_size > 0 && indexer.batch_bytes > 0,
+            "indexer.batch_size and indexer.batch_bytes must be greater than 0",
+        );
+        check(indexer.flush_interval_ms > 0, "indexer.flush_interval_ms must be greater than 0");
+        check(indexer.enqueue_timeout_ms > 0, "indexer.enqueue_timeout_ms must be greater than 0");
+
+        let ml = &self.ml;
+        check(ml.interval > 0, "ml.interval must be greater than 0");
+        check(ml.max_anomalies > 0, "ml.max_anomalies must be greater than 0");
+        for error in crate::ml::validate(ml) {
+            check(false, &error);
+        }
+
+        check(
+            self.auth.disabled || self.auth.key_file.as_deref().is_some_and(|f| !f.is_empty()),
+            "auth.key_file must be set (or auth.disabled for local development)",
+        );
+
+        check(!self.credentials.state_file.is_empty(), "credentials.state_file must not be empty");
+        check(self.credentials.max_clock_skew > 0, "credentials.max_clock_skew must be greater than 0");
+
+        let tls = &self.tls;
+        check(
+            tls.cert_file.is_some() == tls.key_file.is_some(),
+            "tls.cert_file and tls.key_file must be set together",
+        );
+        check(
+            tls.client_ca_file.is_none() || tls.cert_file.is_some(),
+            "tls.client_ca_file needs tls.cert_file and tls.key_file",
+        );
+        check(
+            !tls.require_client_cert || tls.client_ca_file.is_some(),
+            "tls.require_client_cert needs tls.client_ca_file",
+        );
+
+        let liveness = &self.liveness;
+        check(liveness.stale_after > 0.0, "liveness.stale_after must be greater than 0");
+        check(
+            liveness.offline_after > liveness.stale_after,
+            "liveness.offline_after must be greater than liveness.stale_after",
+        );
+        check(liveness.check_interval_secs > 0, "liveness.check_interval_secs must be greater than 0");
+
+        let series = &self.timeseries;
+        check(series.rollup_width > 0, "timeseries.rollup_width must be greater than 0");
+        check(
+            series.raw_retention > 0 && series.rollup_retention >= series.raw_retention,
+            "timeseries.rollup_retention must be at least timeseries.raw_retention, which must be greater than 0",
+        );
+        check(series.max_points > 0, "timeseries.max_points must be greater than 0");
+
+        check(self.stats.bucket_width > 0, "stats.bucket_width must be greater than 0");
+        check(
+            self.stats.retention >= self.stats.bucket_width,
+            "stats.retention must be at least stats.bucket_width",
+        );
+
+        errors
+    }
+}
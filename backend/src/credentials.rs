@@ -0,0 +1,531 @@
+use chrono::{DateTime, Duration, Utc};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, LazyLock};
+
+// Headers carried by signed agent requests
+pub const AGENT_ID_HEADER: &str = "x-agent-id";
+pub const KEY_ID_HEADER: &str = "x-agent-key-id";
+pub const TIMESTAMP_HEADER: &str = "x-agent-timestamp";
+pub const NONCE_HEADER: &str = "x-agent-nonce";
+pub const SIGNATURE_HEADER: &str = "x-agent-signature";
+// Set on responses when the agent should fetch a new key via /api/agents/rotate
+pub const ROTATE_HEADER: &str = "x-agent-rotate";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CredentialsConfig {
+    pub state_file: String,
+    // Seconds a request timestamp may be from our clock. Nonces are remembered for as long
+    // as their request could still be accepted, across restarts too.
+    pub max_clock_skew: u64,
+    // Seconds a superseded key keeps working after a rotation, in case the agent missed the response
+    pub rotation_grace: u64,
+}
+
+impl Default for CredentialsConfig {
+    fn default() -> Self {
+        Self {
+            state_file: "backend_state/credentials.json".to_string(),
+            max_clock_skew: 300,
+            rotation_grace: 600,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct EnrollmentToken {
+    id: String,
+    // Only a hash is kept; the token itself is shown once when it is created
+    token_hash: String,
+    service: Option<String>,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    used_by: Option<String>,
+    used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct AgentKey {
+    key_id: String,
+    secret: String,
+    created_at: DateTime<Utc>,
+    // Set once the key has been superseded by a rotation
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct AgentCredential {
+    agent_id: String,
+    service: String,
+    keys: Vec<AgentKey>,
+    enrolled_at: DateTime<Utc>,
+    rotated_at: Option<DateTime<Utc>>,
+    last_used: Option<DateTime<Utc>>,
+    revoked_at: Option<DateTime<Utc>>,
+    rotation_requested: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct Store {
+    enrollment_tokens: Vec<EnrollmentToken>,
+    credentials: HashMap<String, AgentCredential>,
+    // Nonces of accepted requests by agent, with when each stops being acceptable anyway
+    #[serde(skip)]
+    nonces: Nonces,
+    #[serde(skip)]
+    journal: Option<NonceJournal>,
+}
+
+type Nonces = HashMap<String, HashMap<String, DateTime<Utc>>>;
+
+// Journal lines are not rewritten until there are at least this many
+const MIN_COMPACT_LINES: usize = 10_000;
+
+// Accepted nonces, appended as they arrive so that a restart does not let requests from
+// before it be replayed. Rewritten with only the live ones at startup and whenever it has
+// grown to twice that.
+#[derive(Debug)]
+struct NonceJournal {
+    path: PathBuf,
+    file: File,
+    lines: usize,
+    compact_at: usize,
+}
+
+impl NonceJournal {
+    fn open(path: PathBuf, nonces: &mut Nonces) -> Result<Self, String> {
+        let now = Utc::now();
+        match std::fs::read_to_string(&path) {
+            Ok(content) => {
+                for line in content.lines() {
+                    // A line cut short by a crash is skipped
+                    let Ok((agent_id, nonce, expires)) = serde_json::from_str::<(String, String, DateTime<Utc>)>(line) else {
+                        continue;
+                    };
+                    if expires >= now {
+                        nonces.entry(agent_id).or_default().insert(nonce, expires);
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("failed to read {}: {}", path.display(), e)),
+        }
+        let (file, lines) = write_journal(&path, nonces)?;
+        Ok(Self { path, file, lines, compact_at: (2 * lines).max(MIN_COMPACT_LINES) })
+    }
+
+    fn append(&mut self, agent_id: &str, nonce: &str, expires: DateTime<Utc>, nonces: &mut Nonces) {
+        let line = json_line(agent_id, nonce, expires);
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            eprintln!("Failed to record nonce in {}: {}", self.path.display(), e);
+        }
+        self.lines += 1;
+        if self.lines < self.compact_at {
+            return;
+        }
+
+        let now = Utc::now();
+        for agent_nonces in nonces.values_mut() {
+            agent_nonces.retain(|_, expires| *expires >= now);
+        }
+        nonces.retain(|_, agent_nonces| !agent_nonces.is_empty());
+        match write_journal(&self.path, nonces) {
+            Ok((file, lines)) => {
+                self.file = file;
+                self.lines = lines;
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+        self.compact_at = (2 * self.lines).max(MIN_COMPACT_LINES);
+    }
+}
+
+fn json_line(agent_id: &str, nonce: &str, expires: DateTime<Utc>) -> String {
+    let mut line = serde_json::to_string(&(agent_id, nonce, expires)).expect("strings and times serialize");
+    line.push('\n');
+    line
+}
+
+// Replaces the journal with the given nonces and returns it opened for appending
+fn write_journal(path: &Path, nonces: &Nonces) -> Result<(File, usize), String> {
+    let mut data = String::new();
+    let mut lines = 0;
+    for (agent_id, agent_nonces) in nonces {
+        for (nonce, expires) in agent_nonces {
+            data.push_str(&json_line(agent_id, nonce, *expires));
+            lines += 1;
+        }
+    }
+    let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+    std::fs::write(&tmp, data)
+        .and_then(|_| std::fs::rename(&tmp, path))
+        .and_then(|_| std::fs::OpenOptions::new().append(true).open(path))
+        .map(|file| (file, lines))
+        .map_err(|e| format!("failed to write {}: {}", path.display(), e))
+}
+
+// Admin view of an enrollment token, without the token itself
+#[derive(Debug, Serialize)]
+pub struct EnrollmentTokenInfo {
+    pub id: String,
+    pub service: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub used_by: Option<String>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+// Admin view of an agent credential, without secrets
+#[derive(Debug, Serialize)]
+pub struct CredentialInfo {
+    pub agent_id: String,
+    pub service: String,
+    pub key_ids: Vec<String>,
+    pub enrolled_at: DateTime<Utc>,
+    pub rotated_at: Option<DateTime<Utc>>,
+    pub last_used: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub rotation_requested: bool,
+}
+
+// Handed to the agent on enrollment and rotation; the secret is not shown again
+#[derive(Debug, Serialize)]
+pub struct IssuedCredential {
+    pub agent_id: String,
+    pub key_id: String,
+    pub secret: String,
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+    // Malformed, stale or badly signed request; worth retrying
+    Invalid(String),
+    // No usable credential for this agent; it has to enroll again
+    Rejected(String),
+}
+
+pub struct Verified {
+    pub rotation_requested: bool,
+}
+
+static CONFIG: LazyLock<Mutex<CredentialsConfig>> = LazyLock::new(|| Mutex::new(CredentialsConfig::default()));
+static STORE: LazyLock<Mutex<Store>> = LazyLock::new(|| Mutex::new(Store::default()));
+
+// Random hex, long enough not to repeat by chance
+const MIN_NONCE_LEN: usize = 16;
+const MAX_NONCE_LEN: usize = 64;
+
+// Loads persisted credentials. Must run before the server starts accepting requests.
+pub fn init(config: CredentialsConfig) -> Result<(), String> {
+    let mut store: Store = match std::fs::read(&config.state_file) {
+        Ok(data) => serde_json::from_slice(&data)
+            .map_err(|e| format!("failed to parse {}: {}", config.state_file, e))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Store::default(),
+        Err(e) => return Err(format!("failed to read {}: {}", config.state_file, e)),
+    };
+    println!("Loaded {} agent credentials from {}", store.credentials.len(), config.state_file);
+    let path = Path::new(&config.state_file);
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent).map_err(|e| format!("failed to create {}: {}", parent.display(), e))?;
+    }
+    store.journal = Some(NonceJournal::open(path.with_extension("nonces"), &mut store.nonces)?);
+    *STORE.lock().unwrap() = store;
+    *CONFIG.lock().unwrap() = config;
+    Ok(())
+}
+
+pub fn create_enrollment_token(service: Option<String>, ttl: Option<Duration>) -> Result<(EnrollmentTokenInfo, String), String> {
+    let token = random_hex(32)?;
+    let now = Utc::now();
+    let entry = EnrollmentToken {
+        id: random_hex(8)?,
+        token_hash: sha256_hex(token.as_bytes()),
+        service,
+        created_at: now,
+        expires_at: ttl.map(|ttl| now + ttl),
+        used_by: None,
+        used_at: None,
+    };
+    let info = token_info(&entry);
+
+    let mut store = STORE.lock().unwrap();
+    store.enrollment_tokens.push(entry);
+    persist(&store)?;
+    Ok((info, token))
+}
+
+pub fn list_enrollment_tokens() -> Vec<EnrollmentTokenInfo> {
+    STORE.lock().unwrap().enrollment_tokens.iter().map(token_info).collect()
+}
+
+// Exchanges a one-time enrollment token for a fresh credential. An agent that already
+// has one, even a revoked one, cannot enroll again until an admin removes it.
+pub fn enroll(token: &str, agent_id: &str, service: &str) -> Result<IssuedCredential, String> {
+    let now = Utc::now();
+    let token_hash = sha256_hex(token.as_bytes());
+    let key = new_key(now)?;
+
+    let mut store = STORE.lock().unwrap();
+    if store.credentials.contains_key(agent_id) {
+        return Err(format!("agent {} is already enrolled", agent_id));
+    }
+    let index = store.enrollment_tokens.iter()
+        .position(|t| t.token_hash == token_hash)
+        .ok_or("unknown enrollment token")?;
+    let entry = &mut store.enrollment_tokens[index];
+    if entry.used_at.is_some() {
+        return Err("enrollment token was already used".to_string());
+    }
+    if entry.expires_at.is_some_and(|expires| expires <= now) {
+        return Err("enrollment token has expired".to_string());
+    }
+    if entry.service.as_deref().is_some_and(|s| s != service) {
+        return Err(format!("enrollment token is not valid for service {}", service));
+    }
+    entry.used_by = Some(agent_id.to_string());
+    entry.used_at = Some(now);
+
+    let issued = IssuedCredential {
+        agent_id: agent_id.to_string(),
+        key_id: key.key_id.clone(),
+        secret: key.secret.clone(),
+    };
+    store.credentials.insert(agent_id.to_string(), AgentCredential {
+        agent_id: agent_id.to_string(),
+        service: service.to_string(),
+        keys: vec![key],
+        enrolled_at: now,
+        rotated_at: None,
+        last_used: None,
+        revoked_at: None,
+        rotation_requested: false,
+    });
+    // The token stays usable if the credential could not be saved
+    if let Err(e) = persist(&store) {
+        let entry = &mut store.enrollment_tokens[index];
+        entry.used_by = None;
+        entry.used_at = None;
+        store.credentials.remove(agent_id);
+        return Err(e);
+    }
+    println!("Agent {} ({}) enrolled", service, agent_id);
+    Ok(issued)
+}
+
+// Checks a signed request. The signature is HMAC-SHA256 over
+// "{timestamp}\n{nonce}\n{method}\n{path}\n{hex sha256 of the raw body}".
+// A nonce is accepted once per agent.
+#[allow(clippy::too_many_arguments)]
+pub fn verify(
+    agent_id: &str,
+    key_id: &str,
+    timestamp: &str,
+    nonce: &str,
+    signature: &str,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> Result<Verified, VerifyError> {
+    let max_skew = Duration::seconds(CONFIG.lock().unwrap().max_clock_skew as i64);
+    let now = Utc::now();
+    let sent_at = timestamp.parse::<i64>().ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .ok_or_else(|| VerifyError::Invalid("invalid request timestamp".to_string()))?;
+    if (now - sent_at).abs() > max_skew {
+        return Err(VerifyError::Invalid("request timestamp is outside the allowed clock skew".to_string()));
+    }
+    if !(MIN_NONCE_LEN..=MAX_NONCE_LEN).contains(&nonce.len()) || !nonce.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(VerifyError::Invalid("malformed request nonce".to_string()));
+    }
+    let signature = hex::decode(signature)
+        .map_err(|_| VerifyError::Invalid("malformed signature".to_string()))?;
+
+    let mut store = STORE.lock().unwrap();
+    let credential = store.credentials.get_mut(agent_id)
+        .ok_or_else(|| VerifyError::Rejected(format!("agent {} is not enrolled", agent_id)))?;
+    if credential.revoked_at.is_some() {
+        return Err(VerifyError::Rejected(format!("credential for agent {} was revoked", agent_id)));
+    }
+    let key = credential.keys.iter()
+        .find(|k| k.key_id == key_id && k.expires_at.is_none_or(|expires| expires > now))
+        .ok_or_else(|| VerifyError::Rejected(format!("unknown or expired key {}", key_id)))?;
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key.secret.as_bytes());
+    hmac::verify(&key, signing_string(timestamp, nonce, method, path, body).as_bytes(), &signature)
+        .map_err(|_| VerifyError::Invalid("signature mismatch".to_string()))?;
+    credential.last_used = Some(now);
+    let rotation_requested = credential.rotation_requested;
+
+    let nonces = store.nonces.entry(agent_id.to_string()).or_default();
+    nonces.retain(|_, expires| *expires >= now);
+    if nonces.contains_key(nonce) {
+        return Err(VerifyError::Invalid("request was already received".to_string()));
+    }
+    nonces.insert(nonce.to_string(), sent_at + max_skew);
+    let Store { nonces, journal, .. } = &mut *store;
+    if let Some(journal) = journal {
+        journal.append(agent_id, nonce, sent_at + max_skew, nonces);
+    }
+    Ok(Verified { rotation_requested })
+}
+
+// Issues a new key to an already authenticated agent. Older keys keep working
+// for the rotation grace period.
+pub fn rotate(agent_id: &str) -> Result<IssuedCredential, String> {
+    let grace = Duration::seconds(CONFIG.lock().unwrap().rotation_grace as i64);
+    let now = Utc::now();
+    let key = new_key(now)?;
+
+    let mut store = STORE.lock().unwrap();
+    let credential = store.credentials.get_mut(agent_id)
+        .filter(|c| c.revoked_at.is_none())
+        .ok_or_else(|| format!("agent {} has no active credential", agent_id))?;
+    credential.keys.retain(|k| k.expires_at.is_none_or(|expires| expires > now));
+    for old in credential.keys.iter_mut().filter(|k| k.expires_at.is_none()) {
+        old.expires_at = Some(now + grace);
+    }
+    let issued = IssuedCredential {
+        agent_id: agent_id.to_string(),
+        key_id: key.key_id.clone(),
+        secret: key.secret.clone(),
+    };
+    credential.keys.push(key);
+    credential.rotated_at = Some(now);
+    credential.rotation_requested = false;
+    persist(&store)?;
+    println!("Rotated credential for agent {}", agent_id);
+    Ok(issued)
+}
+
+// Asks the agent to rotate on its next request
+pub fn request_rotation(agent_id: &str) -> Result<Option<CredentialInfo>, String> {
+    let mut store = STORE.lock().unwrap();
+    let Some(credential) = store.credentials.get_mut(agent_id).filter(|c| c.revoked_at.is_none()) else {
+        return Ok(None);
+    };
+    credential.rotation_requested = true;
+    let info = credential_info(credential);
+    persist(&store)?;
+    Ok(Some(info))
+}
+
+// Cuts an agent off immediately. It can only come back with a new enrollment token
+// once an admin has removed it.
+pub fn revoke(agent_id: &str) -> Result<Option<CredentialInfo>, String> {
+    let mut store = STORE.lock().unwrap();
+    let Some(credential) = store.credentials.get_mut(agent_id) else {
+        return Ok(None);
+    };
+    credential.keys.clear();
+    credential.revoked_at = Some(Utc::now());
+    credential.rotation_requested = false;
+    let info = credential_info(credential);
+    store.nonces.remove(agent_id);
+    persist(&store)?;
+    println!("Revoked credential for agent {}", agent_id);
+    Ok(Some(info))
+}
+
+// Forgets an agent's credential so it can enroll again. True if it had one.
+pub fn remove(agent_id: &str) -> Result<bool, String> {
+    let mut store = STORE.lock().unwrap();
+    if store.credentials.remove(agent_id).is_none() {
+        return Ok(false);
+    }
+    store.nonces.remove(agent_id);
+    persist(&store)?;
+    println!("Removed credential for agent {}", agent_id);
+    Ok(true)
+}
+
+pub fn is_revoked(agent_id: &str) -> bool {
+    STORE.lock().unwrap().credentials.get(agent_id).is_some_and(|c| c.revoked_at.is_some())
+}
+
+// Service an enrolled agent reports under
+pub fn service(agent_id: &str) -> Option<String> {
+    STORE.lock().unwrap().credentials.get(agent_id).map(|c| c.service.clone())
+}
+
+pub fn list() -> Vec<CredentialInfo> {
+    let store = STORE.lock().unwrap();
+    let mut credentials: Vec<CredentialInfo> = store.credentials.values().map(credential_info).collect();
+    credentials.sort_by(|a, b| a.service.cmp(&b.service).then_with(|| a.agent_id.cmp(&b.agent_id)));
+    credentials
+}
+
+fn signing_string(timestamp: &str, nonce: &str, method: &str, path: &str, body: &[u8]) -> String {
+    format!("{}\n{}\n{}\n{}\n{}", timestamp, nonce, method, path, sha256_hex(body))
+}
+
+fn new_key(now: DateTime<Utc>) -> Result<AgentKey, String> {
+    Ok(AgentKey {
+        key_id: random_hex(8)?,
+        secret: random_hex(32)?,
+        created_at: now,
+        expires_at: None,
+    })
+}
+
+fn random_hex(bytes: usize) -> Result<String, String> {
+    let mut buf = vec![0u8; bytes];
+    SystemRandom::new().fill(&mut buf).map_err(|_| "system random number generator failed".to_string())?;
+    Ok(hex::encode(buf))
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(digest::digest(&digest::SHA256, data))
+}
+
+fn token_info(token: &EnrollmentToken) -> EnrollmentTokenInfo {
+    EnrollmentTokenInfo {
+        id: token.id.clone(),
+        service: token.service.clone(),
+        created_at: token.created_at,
+        expires_at: token.expires_at,
+        used_by: token.used_by.clone(),
+        used_at: token.used_at,
+    }
+}
+
+fn credential_info(credential: &AgentCredential) -> CredentialInfo {
+    let now = Utc::now();
+    CredentialInfo {
+        agent_id: credential.agent_id.clone(),
+        service: credential.service.clone(),
+        key_ids: credential.keys.iter()
+            .filter(|k| k.expires_at.is_none_or(|expires| expires > now))
+            .map(|k| k.key_id.clone())
+            .collect(),
+        enrolled_at: credential.enrolled_at,
+        rotated_at: credential.rotated_at,
+        last_used: credential.last_used,
+        revoked_at: credential.revoked_at,
+        rotation_requested: credential.rotation_requested,
+    }
+}
+
+// Writes the store atomically; the file holds agent secrets so it is private to the backend user
+fn persist(store: &Store) -> Result<(), String> {
+    let path = CONFIG.lock().unwrap().state_file.clone();
+    let path = Path::new(&path);
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent).map_err(|e| format!("failed to create {}: {}", parent.display(), e))?;
+    }
+    let data = serde_json::to_vec_pretty(store).map_err(|e| e.to_string())?;
+    let tmp = path.with_extension("tmp");
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let write = options.open(&tmp).and_then(|mut file| std::io::Write::write_all(&mut file, &data));
+    write.and_then(|_| std::fs::rename(&tmp, path))
+        .map_err(|e| format!("failed to write {}: {}", path.display(), e))
+}
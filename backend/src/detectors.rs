@@ -0,0 +1,367 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use crate::drain;
+use crate::isolation_forest::IsolationForestDetector;
+use crate::kafka::LogEvent;
+use crate::ml::{settings, Anomaly, Detector, Factory};
+use crate::random_cut_forest::RandomCutForestDetector;
+use crate::windows::EventWindow;
+
+// Every detector by the name used in the `ml.detectors` and `ml.services` config.
+// New detectors only need an entry here.
+pub const REGISTRY: &[(&str, Factory)] = &[
+    ("statistical", StatisticalDetector::build),
+    ("frequency", FrequencyDetector::build),
+    ("pattern", PatternDetector::build),
+    ("isolation_forest", IsolationForestDetector::build),
+    ("random_cut_forest", RandomCutForestDetector::build),
+];
+
+fn anomaly(event: &LogEvent, score: f64, reason: String, algorithm: &str) -> Anomaly {
+    Anomaly {
+        timestamp: Utc::now().to_rfc3339(),
+        score,
+        event: event.clone(),
+        reason,
+        algorithm: algorithm.to_string(),
+    }
+}
+
+// Events that became final since the last run: those between the previous watermark and
+// this one, or the whole history on the first run
+fn new_events<'a>(events: &'a EventWindow, checked_until: &mut Option<DateTime<Utc>>, history: Duration) -> &'a [LogEvent] {
+    let start = checked_until.unwrap_or(events.watermark - history);
+    *checked_until = Some(events.watermark);
+    events.between(start, events.watermark)
+}
+
+// Flags new errors whose message is `length_factor` times the average length over the
+// last `baseline_secs`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatisticalConfig {
+    pub baseline_secs: i64,
+    // Events needed in the baseline
+    pub min_events: usize,
+    pub length_factor: f64,
+}
+
+impl Default for StatisticalConfig {
+    fn default() -> Self {
+        Self {
+            baseline_secs: 3600,
+            min_events: 10,
+            length_factor: 3.0,
+        }
+    }
+}
+
+pub struct StatisticalDetector {
+    config: StatisticalConfig,
+    checked_until: Option<DateTime<Utc>>,
+}
+
+impl StatisticalDetector {
+    fn build(value: serde_yaml::Value) -> Result<Box<dyn Detector>, Vec<String>> {
+        let config: StatisticalConfig = settings(value)?;
+        let mut errors = Vec::new();
+        if config.baseline_secs <= 0 {
+            errors.push("baseline_secs must be greater than 0".to_string());
+        }
+        if config.length_factor <= 0.0 {
+            errors.push("length_factor must be greater than 0".to_string());
+        }
+        if !errors.is_empty() { return Err(errors); }
+        Ok(Box::new(Self { config, checked_until: None }))
+    }
+}
+
+impl Detector for StatisticalDetector {
+    fn observe(&mut self, _event: &LogEvent) {}
+
+    fn evaluate(&mut self, events: &EventWindow) -> Vec<Anomaly> {
+        let config = &self.config;
+        let baseline_length = Duration::seconds(config.baseline_secs);
+        let new = new_events(events, &mut self.checked_until, baseline_length);
+        let baseline = events.last(baseline_length);
+        if baseline.is_empty() || baseline.len() < config.min_events { return Vec::new(); }
+
+        // Simple heuristic: detect unusually long messages
+        let avg_length: f64 = baseline.iter().map(|e| e.message.len() as f64).sum::<f64>() / baseline.len() as f64;
+        let threshold = avg_length * config.length_factor;
+
+        new.iter()
+            .filter(|event| event.message.len() as f64 > threshold && event.level == "ERROR")
+            .map(|event| anomaly(
+                event,
+                0.85,
+                format!("Unusually long error message ({} chars vs {} avg)", event.message.len(), avg_length as usize),
+                "StatisticalAnalysis",
+            ))
+            .collect()
+    }
+
+    fn history_secs(&self) -> i64 {
+        self.config.baseline_secs
+    }
+
+    fn state(&self) -> serde_json::Value {
+        json!({ "config": self.config, "checked_until": self.checked_until })
+    }
+}
+
+// Flags an error rate over the last `recent_secs` that is `spike_factor` times the rate
+// over the `baseline_secs` before them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FrequencyConfig {
+    pub recent_secs: i64,
+    pub baseline_secs: i64,
+    // Events needed in the recent window
+    pub min_events: usize,
+    pub spike_factor: f64,
+    // Recent error rates below this are never a spike
+    pub min_rate: f64,
+}
+
+impl Default for FrequencyConfig {
+    fn default() -> Self {
+        Self {
+            recent_secs: 300,
+            baseline_secs: 3600,
+            min_events: 20,
+            spike_factor: 3.0,
+            min_rate: 0.1,
+        }
+    }
+}
+
+pub struct FrequencyDetector {
+    config: FrequencyConfig,
+    // Rates seen by the last evaluation
+    recent_rate: f64,
+    baseline_rate: f64,
+    // A spike is reported once per recent window
+    reported_at: Option<DateTime<Utc>>,
+}
+
+impl FrequencyDetector {
+    fn build(value: serde_yaml::Value) -> Result<Box<dyn Detector>, Vec<String>> {
+        let config: FrequencyConfig = settings(value)?;
+        let mut errors = Vec::new();
+        if config.recent_secs <= 0 || config.baseline_secs <= 0 {
+            errors.push("recent_secs and baseline_secs must be greater than 0".to_string());
+        }
+        if config.spike_factor <= 0.0 {
+            errors.push("spike_factor must be greater than 0".to_string());
+        }
+        if !(0.0..=1.0).contains(&config.min_rate) {
+            errors.push("min_rate must be between 0 and 1".to_string());
+        }
+        if !errors.is_empty() { return Err(errors); }
+        Ok(Box::new(Self { config, recent_rate: 0.0, baseline_rate: 0.0, reported_at: None }))
+    }
+}
+
+fn error_rate(events: &[LogEvent]) -> f64 {
+    let errors = events.iter().filter(|e| e.level == "ERROR").count();
+    errors as f64 / events.len().max(1) as f64
+}
+
+impl Detector for FrequencyDetector {
+    fn observe(&mut self, _event: &LogEvent) {}
+
+    fn evaluate(&mut self, events: &EventWindow) -> Vec<Anomaly> {
+        let config = &self.config;
+        let recent_start = events.watermark - Duration::seconds(config.recent_secs);
+        let recent = events.between(recent_start, events.watermark);
+        let baseline = events.between(recent_start - Duration::seconds(config.baseline_secs), recent_start);
+        if recent.len() < config.min_events { return Vec::new(); }
+
+        self.recent_rate = error_rate(recent);
+        self.baseline_rate = error_rate(baseline);
+
+        // Anomaly if the recent error rate spikes above the baseline
+        if self.recent_rate <= self.baseline_rate * config.spike_factor || self.recent_rate <= config.min_rate {
+            return Vec::new();
+        }
+        if self.reported_at.is_some_and(|at| at > recent_start) {
+            return Vec::new();
+        }
+        let Some(latest_error) = recent.iter().rev().find(|e| e.level == "ERROR") else { return Vec::new() };
+        self.reported_at = Some(events.watermark);
+        vec![anomaly(
+            latest_error,
+            0.92,
+            format!(
+                "Error rate spike: {:.1}% in the last {}s vs {:.1}% in the {}s before",
+                self.recent_rate * 100.0, config.recent_secs, self.baseline_rate * 100.0, config.baseline_secs,
+            ),
+            "ErrorRateSpike",
+        )]
+    }
+
+    fn history_secs(&self) -> i64 {
+        self.config.recent_secs + self.config.baseline_secs
+    }
+
+    fn state(&self) -> serde_json::Value {
+        json!({
+            "config": self.config,
+            "recent_rate": self.recent_rate,
+            "baseline_rate": self.baseline_rate,
+            "reported_at": self.reported_at,
+        })
+    }
+}
+
+// Flags new errors whose template has been seen fewer times than `rare_fraction` of the
+// service's events over the last `baseline_secs`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PatternConfig {
+    pub baseline_secs: i64,
+    pub rare_fraction: f64,
+}
+
+impl Default for PatternConfig {
+    fn default() -> Self {
+        Self {
+            baseline_secs: 3600,
+            rare_fraction: 0.01,
+        }
+    }
+}
+
+pub struct PatternDetector {
+    config: PatternConfig,
+    checked_until: Option<DateTime<Utc>>,
+    // Template of each event observed in the baseline, by event time
+    templates: VecDeque<(DateTime<Utc>, String)>,
+}
+
+impl PatternDetector {
+    fn build(value: serde_yaml::Value) -> Result<Box<dyn Detector>, Vec<String>> {
+        let config: PatternConfig = settings(value)?;
+        let mut errors = Vec::new();
+        if config.baseline_secs <= 0 {
+            errors.push("baseline_secs must be greater than 0".to_string());
+        }
+        if config.rare_fraction <= 0.0 || config.rare_fraction > 1.0 {
+            errors.push("rare_fraction must be in (0, 1]".to_string());
+        }
+        if !errors.is_empty() { return Err(errors); }
+        Ok(Box::new(Self { config, checked_until: None, templates: VecDeque::new() }))
+    }
+}
+
+impl Detector for PatternDetector {
+    fn observe(&mut self, event: &LogEvent) {
+        if let Some((id, _)) = drain::lookup(&event.service, &event.message) {
+            let time = event.event_timestamp();
+            let pos = self.templates.partition_point(|(t, _)| *t <= time);
+            self.templates.insert(pos, (time, id));
+            // No more than the service's event log keeps
+            if self.templates.len() > crate::ml::config().windows.max_events {
+                self.templates.pop_front();
+            }
+        }
+    }
+
+    fn evaluate(&mut self, events: &EventWindow) -> Vec<Anomaly> {
+        let baseline_length = Duration::seconds(self.config.baseline_secs);
+        let new = new_events(events, &mut self.checked_until, baseline_length);
+
+        // How often each template occurs in the baseline, which ends at the watermark
+        let start = events.watermark - baseline_length;
+        let expired = self.templates.partition_point(|(time, _)| *time < start);
+        self.templates.drain(..expired);
+        let mut counts: HashMap<&str, u64> = HashMap::new();
+        for (_, id) in self.templates.iter().take_while(|(time, _)| *time < events.watermark) {
+            *counts.entry(id.as_str()).or_default() += 1;
+        }
+        let total_logs = events.last(baseline_length).len() as f64;
+        let rare_threshold = (total_logs * self.config.rare_fraction).max(1.0) as u64;
+
+        new.iter()
+            .filter(|event| event.level == "ERROR")
+            .filter_map(|event| {
+                let (id, _) = drain::lookup(&event.service, &event.message)?;
+                let count = counts.get(id.as_str()).copied().unwrap_or(0);
+                (count < rare_threshold).then(|| anomaly(
+                    event,
+                    0.78,
+                    format!("Rare error template {} (seen {} times in the last {}s)", id, count, self.config.baseline_secs),
+                    "RareTemplate",
+                ))
+            })
+            .collect()
+    }
+
+    fn history_secs(&self) -> i64 {
+        self.config.baseline_secs
+    }
+
+    fn state(&self) -> serde_json::Value {
+        json!({ "config": self.config, "checked_until": self.checked_until, "baseline_events": self.templates.len() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::windows::{EventLog, WindowConfig};
+
+    fn at(minute: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 10, 17, 10, 0, 0).unwrap() + Duration::minutes(minute)
+    }
+
+    fn event(service: &str, minute: i64, level: &str, message: &str) -> LogEvent {
+        LogEvent {
+            event_time: at(minute).to_rfc3339(),
+            ingest_time: at(minute).to_rfc3339(),
+            level: level.to_string(),
+            message: message.to_string(),
+            service: service.to_string(),
+            trace_id: None,
+            fields: Default::default(),
+        }
+    }
+
+    // Feeds the events to the miner, the service's event log and the detector, as ml::process does
+    fn observe(detector: &mut dyn Detector, log: &mut EventLog, events: &[LogEvent]) {
+        let config = WindowConfig { idle_timeout_secs: 86_400, ..WindowConfig::default() };
+        for event in events {
+            drain::add(&event.service, &event.message, event.event_timestamp());
+            assert!(log.insert(event, &config, event.event_timestamp()));
+            detector.observe(event);
+        }
+    }
+
+    #[test]
+    fn rare_templates_are_counted_within_the_baseline() {
+        let service = "rare-template-test";
+        let mut detector = PatternDetector::build(serde_yaml::Value::Null).unwrap();
+        let mut log = EventLog::default();
+
+        // Common two hours ago, then gone for longer than the baseline
+        let old: Vec<LogEvent> = (0..50).map(|_| event(service, 0, "ERROR", "disk full on volume data")).collect();
+        observe(detector.as_mut(), &mut log, &old);
+        detector.evaluate(&log.view(at(1)));
+
+        let mut recent: Vec<LogEvent> = (0..300)
+            .map(|n| event(service, 120 + n / 10, "ERROR", "request timed out after retry"))
+            .collect();
+        recent.push(event(service, 140, "ERROR", "disk full on volume logs"));
+        observe(detector.as_mut(), &mut log, &recent);
+        let anomalies = detector.evaluate(&log.view(at(160)));
+
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0].event.message, "disk full on volume logs");
+        assert_eq!(anomalies[0].algorithm, "RareTemplate");
+        assert!(anomalies[0].reason.contains("seen 1 times"), "{}", anomalies[0].reason);
+    }
+}
@@ -0,0 +1,413 @@
+use chrono::{DateTime, Utc};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex, OnceLock};
+
+// Online log template mining after Drain (He et al., 2017). Messages are routed through a
+// fixed-depth tree by service, token count and leading tokens, then matched to the most
+// similar template in the leaf. Tokens that differ between messages of a template become
+// parameter slots.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TemplateConfig {
+    // Leading tokens used to route a message to its leaf
+    pub prefix_tokens: usize,
+    // Share of tokens a message must have in common with a template to join it
+    pub similarity: f64,
+    // Distinct tokens per tree node; further ones share the parameter branch
+    pub max_children: usize,
+    // Templates kept across all services; the least recently seen is dropped beyond this
+    pub max_templates: usize,
+    // Example messages kept per template
+    pub examples: usize,
+}
+
+impl Default for TemplateConfig {
+    fn default() -> Self {
+        Self {
+            prefix_tokens: 2,
+            similarity: 0.5,
+            max_children: 100,
+            max_templates: 5000,
+            examples: 3,
+        }
+    }
+}
+
+// Stands for a parameter, in templates and in the routing tree
+pub const PARAM: &str = "<*>";
+
+// Longest example message kept
+const MAX_EXAMPLE_LEN: usize = 500;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Template {
+    // Fixed when the template is first seen, so it survives later generalization
+    pub id: String,
+    pub service: String,
+    pub template: String,
+    pub count: u64,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub examples: Vec<String>,
+}
+
+struct Cluster {
+    template: Template,
+    tokens: Vec<String>,
+    // Branches to its leaf below the token count
+    path: Vec<String>,
+    // For dropping the least recently used template
+    last_used: u64,
+}
+
+// A routing tree node; leaves list the templates routed to them
+#[derive(Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    clusters: Vec<u64>,
+}
+
+pub struct TemplateMiner {
+    config: TemplateConfig,
+    // By service, then token count
+    roots: HashMap<String, HashMap<usize, Node>>,
+    clusters: HashMap<u64, Cluster>,
+    next_key: u64,
+    uses: u64,
+}
+
+impl TemplateMiner {
+    pub fn new(config: TemplateConfig) -> Self {
+        Self { config, roots: HashMap::new(), clusters: HashMap::new(), next_key: 0, uses: 0 }
+    }
+
+    // Assigns the message to a template, creating one if nothing is similar enough
+    pub fn add(&mut self, service: &str, message: &str, time: DateTime<Utc>) -> &Template {
+        let tokens = tokenize(message);
+        self.uses += 1;
+
+        let key = match self.find(service, &tokens) {
+            Some(key) => {
+                let examples = self.config.examples;
+                let cluster = self.clusters.get_mut(&key).expect("leaves only list live clusters");
+                for (slot, token) in cluster.tokens.iter_mut().zip(&tokens) {
+                    if slot != token {
+                        *slot = PARAM.to_string();
+                    }
+                }
+                let template = &mut cluster.template;
+                template.template = cluster.tokens.join(" ");
+                template.count += 1;
+                template.first_seen = template.first_seen.min(time);
+                template.last_seen = template.last_seen.max(time);
+                if template.examples.len() < examples && !template.examples.iter().any(|e| e == message) {
+                    template.examples.push(truncate(message));
+                }
+                cluster.last_used = self.uses;
+                key
+            }
+            None => {
+                if self.clusters.len() >= self.config.max_templates {
+                    self.evict();
+                }
+                let key = self.next_key;
+                self.next_key += 1;
+                let path = self.leaf(service, &tokens, key);
+                let template = tokens.join(" ");
+                let id = hex::encode(&digest::digest(&digest::SHA256, format!("{}\n{}", service, template).as_bytes()).as_ref()[..6]);
+                self.clusters.insert(key, Cluster {
+                    template: Template {
+                        id,
+                        service: service.to_string(),
+                        template,
+                        count: 1,
+                        first_seen: time,
+                        last_seen: time,
+                        examples: if self.config.examples > 0 { vec![truncate(message)] } else { Vec::new() },
+                    },
+                    tokens,
+                    path,
+                    last_used: self.uses,
+                });
+                key
+            }
+        };
+        &self.clusters[&key].template
+    }
+
+    // The template the message would join, without changing anything
+    pub fn lookup(&self, service: &str, message: &str) -> Option<&Template> {
+        self.find(service, &tokenize(message)).map(|key| &self.clusters[&key].template)
+    }
+
+    pub fn templates(&self) -> impl Iterator<Item = &Template> {
+        self.clusters.values().map(|c| &c.template)
+    }
+
+    fn find(&self, service: &str, tokens: &[String]) -> Option<u64> {
+        let mut node = self.roots.get(service)?.get(&tokens.len())?;
+        for token in tokens.iter().take(self.config.prefix_tokens) {
+            node = node.children.get(token).or_else(|| node.children.get(PARAM))?;
+        }
+
+        // The template with the most tokens in common, preferring the more general on ties
+        let mut best: Option<(u64, f64, usize)> = None;
+        for &key in &node.clusters {
+            let cluster = &self.clusters[&key];
+            let params = cluster.tokens.iter().filter(|t| *t == PARAM).count();
+            let same = cluster.tokens.iter().zip(tokens).filter(|(a, b)| a == b).count();
+            let similarity = if tokens.is_empty() { 1.0 } else { same as f64 / tokens.len() as f64 };
+            if best.is_none_or(|(_, s, p)| similarity > s || (similarity == s && params > p)) {
+                best = Some((key, similarity, params));
+            }
+        }
+        best.filter(|&(_, similarity, _)| similarity >= self.config.similarity).map(|(key, _, _)| key)
+    }
+
+    // Adds the template to its leaf, creating the branches on the way, and returns the branches
+    fn leaf(&mut self, service: &str, tokens: &[String], key: u64) -> Vec<String> {
+        let max_children = self.config.max_children;
+        let mut node = self.roots.entry(service.to_string()).or_default().entry(tokens.len()).or_default();
+        let mut path = Vec::new();
+        for token in tokens.iter().take(self.config.prefix_tokens) {
+            // Once a node is full, new tokens share the parameter branch
+            let branch = if node.children.contains_key(token) || node.children.len() < max_children {
+                token.as_str()
+            } else {
+                PARAM
+            };
+            path.push(branch.to_string());
+            node = node.children.entry(branch.to_string()).or_default();
+        }
+        node.clusters.push(key);
+        path
+    }
+
+    // Drops the least recently used template and any routing nodes left empty
+    fn evict(&mut self) {
+        let Some((&key, _)) = self.clusters.iter().min_by_key(|(_, c)| c.last_used) else { return };
+        let cluster = self.clusters.remove(&key).expect("key was just found");
+        if let Some(lengths) = self.roots.get_mut(&cluster.template.service) {
+            if let Some(node) = lengths.get_mut(&cluster.tokens.len()) {
+                prune(node, &cluster.path, key);
+                if node.children.is_empty() && node.clusters.is_empty() {
+                    lengths.remove(&cluster.tokens.len());
+                }
+            }
+            if lengths.is_empty() {
+                self.roots.remove(&cluster.template.service);
+            }
+        }
+    }
+}
+
+fn prune(node: &mut Node, path: &[String], key: u64) {
+    match path.split_first() {
+        None => node.clusters.retain(|&k| k != key),
+        Some((branch, rest)) => {
+            if let Some(child) = node.children.get_mut(branch) {
+                prune(child, rest, key);
+                if child.children.is_empty() && child.clusters.is_empty() {
+                    node.children.remove(branch);
+                }
+            }
+        }
+    }
+}
+
+fn truncate(message: &str) -> String {
+    match message.char_indices().nth(MAX_EXAMPLE_LEN) {
+        Some((end, _)) => format!("{}...", &message[..end]),
+        None => message.to_string(),
+    }
+}
+
+// Splits on whitespace, keeping quoted values together, and replaces values that vary
+// between messages with PARAM
+pub fn tokenize(message: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut rest = message.trim_start();
+    while !rest.is_empty() {
+        let end = match rest.chars().next() {
+            // A quoted value runs to its closing quote, spaces included
+            Some(quote @ ('"' | '\'')) => rest[1..].find(quote).map(|i| i + 2),
+            _ => None,
+        }
+        .or_else(|| rest.find(char::is_whitespace))
+        .unwrap_or(rest.len());
+        tokens.push(mask(&rest[..end]));
+        rest = rest[end..].trim_start();
+    }
+    tokens
+}
+
+fn mask(token: &str) -> String {
+    // `key=value` keeps its key
+    if let Some((key, value)) = token.split_once('=') {
+        if !key.is_empty() && !value.is_empty() {
+            return format!("{}={}", key, mask(value));
+        }
+    }
+
+    let core = token.trim_start_matches(['(', '[', '{', '<']).trim_end_matches([',', ';', ':', '.', ')', ']', '}', '>']);
+    if core.is_empty() || !is_param(core) {
+        return token.to_string();
+    }
+    let start = token.len() - token.trim_start_matches(['(', '[', '{', '<']).len();
+    let end = start + core.len();
+    format!("{}{}{}", &token[..start], PARAM, &token[end..])
+}
+
+// Numbers, IDs, hashes, addresses, paths, emails and quoted values
+fn is_param(token: &str) -> bool {
+    token.starts_with(['"', '\'', '/'])
+        || token.contains('@')
+        || token.chars().any(|c| c.is_ascii_digit())
+        || (token.len() >= 8 && token.chars().all(|c| c.is_ascii_hexdigit()))
+        || token.starts_with("0x")
+}
+
+static CONFIG: OnceLock<TemplateConfig> = OnceLock::new();
+static MINER: LazyLock<Mutex<TemplateMiner>> =
+    LazyLock::new(|| Mutex::new(TemplateMiner::new(CONFIG.get().cloned().unwrap_or_default())));
+
+// Called once at startup, before any events arrive
+pub fn configure(config: TemplateConfig) {
+    let _ = CONFIG.set(config);
+}
+
+// Mines the message and returns its template's ID and count so far
+pub fn add(service: &str, message: &str, time: DateTime<Utc>) -> (String, u64) {
+    let mut miner = MINER.lock().unwrap();
+    let template = miner.add(service, message, time);
+    (template.id.clone(), template.count)
+}
+
+pub fn lookup(service: &str, message: &str) -> Option<(String, u64)> {
+    MINER.lock().unwrap().lookup(service, message).map(|t| (t.id.clone(), t.count))
+}
+
+// The most frequent templates, optionally of one service
+pub fn templates(service: Option<&str>, limit: usize) -> Vec<Template> {
+    let miner = MINER.lock().unwrap();
+    let mut templates: Vec<Template> = miner.templates()
+        .filter(|t| service.is_none_or(|s| t.service == s))
+        .cloned()
+        .collect();
+    templates.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.id.cmp(&b.id)));
+    templates.truncate(limit);
+    templates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(minute: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_700_000_000 + minute * 60, 0).unwrap()
+    }
+
+    fn miner(max_templates: usize) -> TemplateMiner {
+        TemplateMiner::new(TemplateConfig { max_templates, ..TemplateConfig::default() })
+    }
+
+    #[test]
+    fn masks_values_that_vary_between_messages() {
+        assert_eq!(
+            tokenize("request 550e8400-e29b-41d4-a716-446655440000 from 10.0.0.12:8080 took 15ms."),
+            ["request", "<*>", "from", "<*>", "took", "<*>."],
+        );
+        assert_eq!(tokenize("commit deadbeefcafe at 0xff in cafe"), ["commit", "<*>", "at", "<*>", "in", "cafe"]);
+        assert_eq!(tokenize("mail to ops@example.com about /var/log/app"), ["mail", "to", "<*>", "about", "<*>"]);
+        assert_eq!(tokenize("failed (42), retrying [attempt 3]"), ["failed", "(<*>),", "retrying", "[attempt", "<*>]"]);
+    }
+
+    #[test]
+    fn keeps_quoted_values_together() {
+        assert_eq!(tokenize(r#"user "John Smith" logged in"#), ["user", "<*>", "logged", "in"]);
+        assert_eq!(tokenize("opened 'my file.txt'  twice"), ["opened", "<*>", "twice"]);
+        // An unclosed quote ends at the next space
+        assert_eq!(tokenize(r#"got "half open"#), ["got", "<*>", "open"]);
+    }
+
+    #[test]
+    fn masks_only_the_value_of_key_value_pairs() {
+        assert_eq!(
+            tokenize("status=503 user=alice path=/api/v1 empty= =x"),
+            ["status=<*>", "user=alice", "path=<*>", "empty=", "=x"],
+        );
+        assert_eq!(tokenize("a=b=1"), ["a=b=<*>"]);
+    }
+
+    #[test]
+    fn merges_differing_tokens_into_params() {
+        let mut miner = miner(100);
+        let first = miner.add("api", "user logged in from paris", at(0)).clone();
+        let merged = miner.add("api", "user logged in from berlin", at(2)).clone();
+        miner.add("api", "user logged in from rome", at(1));
+
+        assert_eq!(merged.template, "user logged in from <*>");
+        let template = miner.lookup("api", "user logged in from oslo").unwrap();
+        assert_eq!(template.count, 3);
+        assert_eq!((template.first_seen, template.last_seen), (at(0), at(2)));
+        assert_eq!(template.examples.len(), 3);
+        // Not similar enough, or routed elsewhere by a leading token
+        assert!(miner.lookup("api", "user went out to paris").is_none());
+        assert!(miner.lookup("api", "admin logged in from paris").is_none());
+        assert!(miner.lookup("web", "user logged in from paris").is_none());
+        assert_eq!(miner.templates().count(), 1);
+        assert_eq!(first.template, "user logged in from paris");
+    }
+
+    #[test]
+    fn ids_survive_generalization_and_repeat_across_miners() {
+        let mut miner = miner(100);
+        let id = miner.add("api", "cache miss for users", at(0)).id.clone();
+        assert_eq!(miner.add("api", "cache miss for orders", at(1)).id, id);
+        assert_eq!(miner.lookup("api", "cache miss for carts").unwrap().template, "cache miss for <*>");
+
+        let mut other = self::miner(100);
+        assert_eq!(other.add("api", "cache miss for users", at(5)).id, id);
+        assert_ne!(other.add("web", "cache miss for users", at(5)).id, id);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_template() {
+        let mut miner = miner(2);
+        miner.add("api", "disk full on sda", at(0));
+        miner.add("api", "job started now", at(1));
+        miner.add("api", "disk full on sda", at(2));
+        miner.add("api", "queue drained quickly", at(3));
+
+        assert_eq!(miner.templates().count(), 2);
+        assert!(miner.lookup("api", "disk full on sda").is_some());
+        assert!(miner.lookup("api", "queue drained quickly").is_some());
+        assert!(miner.lookup("api", "job started now").is_none());
+        // Its routing branch went with it
+        assert!(!miner.roots["api"][&3].children.contains_key("job"));
+    }
+
+    #[test]
+    fn prunes_routing_nodes_left_empty() {
+        let mut miner = miner(1);
+        miner.add("api", "one two three", at(0));
+        miner.add("web", "four five", at(1));
+        assert!(!miner.roots.contains_key("api"));
+
+        miner.add("web", "six seven eight", at(2));
+        assert_eq!(miner.roots["web"].keys().collect::<Vec<_>>(), [&3]);
+    }
+
+    #[test]
+    fn full_nodes_route_new_tokens_through_the_param_branch() {
+        let mut miner = TemplateMiner::new(TemplateConfig { max_children: 1, ..TemplateConfig::default() });
+        miner.add("api", "alpha job done", at(0));
+        let id = miner.add("api", "beta job done", at(1)).id.clone();
+        let gamma = miner.add("api", "gamma job done", at(2));
+        assert_eq!((gamma.id.as_str(), gamma.template.as_str()), (id.as_str(), "<*> job done"));
+        assert_eq!(miner.lookup("api", "alpha job done").unwrap().template, "alpha job done");
+    }
+}
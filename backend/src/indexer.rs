@@ -0,0 +1,304 @@
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, PermitIterator};
+use tokio::time::{Duration, Instant};
+use crate::kafka::LogEvent;
+use crate::store::{BulkDocument, BulkError, BulkOutcome, LogStore};
+
+// Throughput is averaged over this window
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexerConfig {
+    // Logs waiting to be indexed; producers wait for room once it is full
+    pub queue_capacity: usize,
+    // A bulk request is sent once it holds `batch_size` documents or `batch_bytes` of
+    // source, or `flush_interval_ms` after its first document arrived
+    pub batch_size: usize,
+    pub batch_bytes: usize,
+    pub flush_interval_ms: u64,
+    // Retries for documents rejected with 429/5xx, backing off from `retry_backoff_ms` and doubling
+    pub max_retries: u32,
+    pub retry_backoff_ms: u64,
+    // How long an agent upload waits for queue room before it is turned away with a 503
+    pub enqueue_timeout_ms: u64,
+}
+
+impl Default for IndexerConfig {
+    fn default() -> Self {
+        Self {
+            queue_capacity: 20_000,
+            batch_size: 1000,
+            batch_bytes: 5 * 1024 * 1024,
+            flush_interval_ms: 1000,
+            max_retries: 3,
+            retry_backoff_ms: 500,
+            enqueue_timeout_ms: 2000,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum IndexerError {
+    // The queue stayed full for the whole enqueue timeout
+    QueueFull,
+    Closed,
+}
+
+impl std::fmt::Display for IndexerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IndexerError::QueueFull => write!(f, "indexing queue is full, retry later"),
+            IndexerError::Closed => write!(f, "indexer is not running"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct IndexerStats {
+    pub queued: usize,
+    pub queue_capacity: usize,
+    pub indexed: u64,
+    pub failed: u64,
+    pub retried: u64,
+    pub bulk_requests: u64,
+    // Agent uploads turned away because the queue was full
+    pub rejected_uploads: u64,
+    pub docs_per_sec: f64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Default)]
+struct Counters {
+    indexed: AtomicU64,
+    failed: AtomicU64,
+    retried: AtomicU64,
+    bulk_requests: AtomicU64,
+    rejected_uploads: AtomicU64,
+    // When each recent flush finished and how many documents it indexed
+    recent: Mutex<VecDeque<(Instant, u64)>>,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Counters {
+    fn record_flush(&self, indexed: u64) {
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap();
+        recent.push_back((now, indexed));
+        while recent.front().is_some_and(|(at, _)| now.duration_since(*at) > THROUGHPUT_WINDOW) {
+            recent.pop_front();
+        }
+    }
+
+    fn docs_per_sec(&self) -> f64 {
+        let now = Instant::now();
+        let recent = self.recent.lock().unwrap();
+        let indexed: u64 = recent.iter()
+            .filter(|(at, _)| now.duration_since(*at) <= THROUGHPUT_WINDOW)
+            .map(|(_, count)| count)
+            .sum();
+        indexed as f64 / THROUGHPUT_WINDOW.as_secs_f64()
+    }
+
+    fn set_error(&self, error: String) {
+        *self.last_error.lock().unwrap() = Some(error);
+    }
+}
+
+// Handle to the background task that batches logs into bulk writes to the log store.
+// Shared with handlers through `web::Data` and with the Kafka consumer.
+#[derive(Clone)]
+pub struct BulkIndexer {
+    sender: mpsc::Sender<LogEvent>,
+    counters: Arc<Counters>,
+    enqueue_timeout: Duration,
+}
+
+// Queue room held for one agent upload
+pub struct Reservation<'a> {
+    permits: PermitIterator<'a, LogEvent>,
+    sender: &'a mpsc::Sender<LogEvent>,
+}
+
+impl Reservation<'_> {
+    pub async fn send(&mut self, event: &LogEvent) {
+        match self.permits.next() {
+            Some(permit) => permit.send(event.clone()),
+            // Only reached by an upload larger than the whole queue
+            None => {
+                let _ = self.sender.send(event.clone()).await;
+            }
+        }
+    }
+}
+
+impl BulkIndexer {
+    pub fn start(store: web::Data<dyn LogStore>, config: IndexerConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(config.queue_capacity);
+        let indexer = Self {
+            sender,
+            counters: Arc::new(Counters::default()),
+            enqueue_timeout: Duration::from_millis(config.enqueue_timeout_ms),
+        };
+        tokio::spawn(run(store, receiver, config, indexer.counters.clone()));
+        tokio::spawn(report(indexer.clone()));
+        indexer
+    }
+
+    // Waits as long as it takes for queue room, which in turn holds back the caller (the Kafka consumer)
+    pub async fn submit(&self, event: &LogEvent) -> Result<(), IndexerError> {
+        self.sender.send(event.clone()).await.map_err(|_| IndexerError::Closed)
+    }
+
+    // Reserves room for `count` logs up front so an upload is either accepted whole or
+    // rejected before any of it is processed, and can then be retried without duplicates
+    pub async fn reserve(&self, count: usize) -> Result<Reservation<'_>, IndexerError> {
+        let count = count.min(self.sender.max_capacity());
+        match tokio::time::timeout(self.enqueue_timeout, self.sender.reserve_many(count)).await {
+            Ok(Ok(permits)) => Ok(Reservation { permits, sender: &self.sender }),
+            Ok(Err(_)) => Err(IndexerError::Closed),
+            Err(_) => {
+                self.counters.rejected_uploads.fetch_add(1, Ordering::Relaxed);
+                Err(IndexerError::QueueFull)
+            }
+        }
+    }
+
+    pub fn stats(&self) -> IndexerStats {
+        let counters = &self.counters;
+        IndexerStats {
+            queued: self.sender.max_capacity() - self.sender.capacity(),
+            queue_capacity: self.sender.max_capacity(),
+            indexed: counters.indexed.load(Ordering::Relaxed),
+            failed: counters.failed.load(Ordering::Relaxed),
+            retried: counters.retried.load(Ordering::Relaxed),
+            bulk_requests: counters.bulk_requests.load(Ordering::Relaxed),
+            rejected_uploads: counters.rejected_uploads.load(Ordering::Relaxed),
+            docs_per_sec: counters.docs_per_sec(),
+            last_error: counters.last_error.lock().unwrap().clone(),
+        }
+    }
+}
+
+async fn run(
+    store: web::Data<dyn LogStore>,
+    mut receiver: mpsc::Receiver<LogEvent>,
+    config: IndexerConfig,
+    counters: Arc<Counters>,
+) {
+    let flush_interval = Duration::from_millis(config.flush_interval_ms);
+
+    // Wait for a first document, then keep filling the batch until it is full or due
+    while let Some(first) = receiver.recv().await {
+        let deadline = Instant::now() + flush_interval;
+        let first = BulkDocument::new(&first);
+        let mut bytes = source_size(&first);
+        let mut batch = vec![first];
+
+        while batch.len() < config.batch_size && bytes < config.batch_bytes {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(event)) => {
+                    let doc = BulkDocument::new(&event);
+                    bytes += source_size(&doc);
+                    batch.push(doc);
+                }
+                Ok(None) | Err(_) => break,
+            }
+        }
+
+        // Nothing is read from the queue while a batch is being flushed or retried,
+        // so a slow cluster fills the queue and holds back producers
+        flush(store.as_ref(), &config, &counters, batch).await;
+    }
+}
+
+async fn flush(store: &dyn LogStore, config: &IndexerConfig, counters: &Counters, mut pending: Vec<BulkDocument>) {
+    let mut indexed = 0u64;
+    let mut dropped = 0u64;
+    let mut attempt = 0;
+
+    loop {
+        counters.bulk_requests.fetch_add(1, Ordering::Relaxed);
+        match store.bulk_index(&pending).await {
+            Ok(outcomes) => {
+                let mut retry = Vec::new();
+                for (doc, outcome) in pending.into_iter().zip(outcomes) {
+                    match outcome {
+                        BulkOutcome::Stored => indexed += 1,
+                        BulkOutcome::Retry(reason) => {
+                            counters.set_error(reason);
+                            retry.push(doc);
+                        }
+                        BulkOutcome::Failed(reason) => {
+                            if dropped == 0 {
+                                eprintln!("Dropping log {}: {}", doc.id, reason);
+                            }
+                            dropped += 1;
+                            counters.set_error(reason);
+                        }
+                    }
+                }
+                pending = retry;
+            }
+            Err(BulkError::Retryable(e)) => counters.set_error(e),
+            Err(BulkError::Rejected(e)) => {
+                eprintln!("Dropping {} logs: {}", pending.len(), e);
+                counters.failed.fetch_add(pending.len() as u64, Ordering::Relaxed);
+                counters.set_error(e);
+                pending.clear();
+            }
+        }
+
+        if pending.is_empty() {
+            break;
+        }
+        if attempt >= config.max_retries {
+            eprintln!("Dropping {} logs after {} retries", pending.len(), attempt);
+            counters.failed.fetch_add(pending.len() as u64, Ordering::Relaxed);
+            break;
+        }
+        let backoff = config.retry_backoff_ms.saturating_mul(1 << attempt.min(16));
+        attempt += 1;
+        counters.retried.fetch_add(pending.len() as u64, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(backoff)).await;
+    }
+
+    if dropped > 1 {
+        eprintln!("Dropped {} logs in total from one bulk request", dropped);
+    }
+    counters.failed.fetch_add(dropped, Ordering::Relaxed);
+    counters.indexed.fetch_add(indexed, Ordering::Relaxed);
+    counters.record_flush(indexed);
+}
+
+// Prints throughput and failures once a minute while there is traffic
+async fn report(indexer: BulkIndexer) {
+    let mut interval = tokio::time::interval(THROUGHPUT_WINDOW);
+    interval.tick().await;
+    let (mut last_indexed, mut last_failed) = (0, 0);
+
+    loop {
+        interval.tick().await;
+        let stats = indexer.stats();
+        if stats.indexed == last_indexed && stats.failed == last_failed && stats.queued == 0 {
+            continue;
+        }
+        println!(
+            "Indexer: {:.1} docs/s, {} indexed, {} failed in the last minute, {}/{} queued",
+            stats.docs_per_sec,
+            stats.indexed - last_indexed,
+            stats.failed - last_failed,
+            stats.queued,
+            stats.queue_capacity,
+        );
+        (last_indexed, last_failed) = (stats.indexed, stats.failed);
+    }
+}
+
+fn source_size(doc: &BulkDocument) -> usize {
+    serde_json::to_vec(&doc.source).map(|v| v.len()).unwrap_or(0)
+}
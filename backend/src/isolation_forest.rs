@@ -0,0 +1,404 @@
+use chrono::{DateTime, TimeZone, Utc};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashSet, VecDeque};
+use crate::drain;
+use crate::kafka::LogEvent;
+use crate::ml::{empty_window_event, settings, Anomaly, Detector};
+use crate::windows::{EventWindow, TumblingWindows};
+
+// Isolation Forest (Liu, Ting & Zhou, 2008). Anomalies are few and different, so random
+// axis-aligned splits isolate them in fewer steps than normal points. smartcore has no
+// isolation forest, so rather than depend on it for nothing the trees are built here.
+//
+// Unlike the paper, a point outside the range of a node's points counts as isolated there.
+// Otherwise a window far beyond anything in the history scores like the most extreme
+// training window, since every split sends it the same way.
+pub struct IsolationForest {
+    trees: Vec<Tree>,
+    // Points each tree was built from, which sets the expected path length
+    sample_size: usize,
+}
+
+struct Tree {
+    // Range of each feature over the tree's sample
+    bounds: Vec<(f64, f64)>,
+    nodes: Vec<Node>,
+}
+
+enum Node {
+    Leaf { size: usize },
+    // `min` and `max` bound the split feature over the node's points
+    Split { feature: usize, value: f64, min: f64, max: f64, left: usize, right: usize },
+}
+
+impl IsolationForest {
+    pub fn train(points: &[Vec<f64>], trees: usize, sample_size: usize, rng: &mut StdRng) -> Self {
+        let sample_size = sample_size.min(points.len());
+        // Deeper than this, the remaining points are normal anyway
+        let max_depth = (sample_size.max(2) as f64).log2().ceil() as usize;
+        let trees = (0..trees)
+            .map(|_| {
+                let sample: Vec<&Vec<f64>> = points.choose_multiple(rng, sample_size).collect();
+                let bounds = (0..sample[0].len()).map(|f| range(&sample, f)).collect();
+                let mut nodes = Vec::new();
+                build(&mut nodes, sample, 0, max_depth, rng);
+                Tree { bounds, nodes }
+            })
+            .collect();
+        Self { trees, sample_size }
+    }
+
+    // Near 1 for anomalies; at or below 0.5 for points like most of the training data
+    pub fn score(&self, point: &[f64]) -> f64 {
+        if self.trees.is_empty() { return 0.0; }
+        let mean_path = self.trees.iter().map(|tree| path_length(tree, point)).sum::<f64>() / self.trees.len() as f64;
+        2f64.powf(-mean_path / average_path(self.sample_size))
+    }
+}
+
+// Appends the subtree for `points` and returns its index
+fn build(nodes: &mut Vec<Node>, points: Vec<&Vec<f64>>, depth: usize, max_depth: usize, rng: &mut StdRng) -> usize {
+    let index = nodes.len();
+    nodes.push(Node::Leaf { size: points.len() });
+    if points.len() <= 1 || depth >= max_depth { return index; }
+
+    // Only features that still differ can separate the points
+    let ranges: Vec<(usize, f64, f64)> = (0..points[0].len())
+        .filter_map(|f| {
+            let (min, max) = range(&points, f);
+            (max > min).then_some((f, min, max))
+        })
+        .collect();
+    let Some(&(feature, min, max)) = ranges.choose(rng) else { return index };
+
+    let value = rng.gen_range(min..max);
+    let (left, right): (Vec<_>, Vec<_>) = points.into_iter().partition(|p| p[feature] < value);
+    let left = build(nodes, left, depth + 1, max_depth, rng);
+    let right = build(nodes, right, depth + 1, max_depth, rng);
+    nodes[index] = Node::Split { feature, value, min, max, left, right };
+    index
+}
+
+fn range(points: &[&Vec<f64>], feature: usize) -> (f64, f64) {
+    points.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), p| (min.min(p[feature]), max.max(p[feature])))
+}
+
+fn path_length(tree: &Tree, point: &[f64]) -> f64 {
+    if tree.bounds.iter().zip(point).any(|(&(min, max), &x)| x < min || x > max) {
+        return 1.0;
+    }
+    let mut index = 0;
+    let mut depth = 0.0;
+    loop {
+        match tree.nodes[index] {
+            // Unsplit points would have taken about this many more steps
+            Node::Leaf { size } => return depth + average_path(size),
+            Node::Split { feature, min, max, .. } if point[feature] < min || point[feature] > max => return depth + 1.0,
+            Node::Split { feature, value, left, right, .. } => {
+                index = if point[feature] < value { left } else { right };
+                depth += 1.0;
+            }
+        }
+    }
+}
+
+// Average path length of an unsuccessful binary search tree lookup among n points
+fn average_path(n: usize) -> f64 {
+    match n {
+        0 | 1 => 0.0,
+        2 => 1.0,
+        n => {
+            let n = n as f64;
+            2.0 * ((n - 1.0).ln() + 0.5772156649) - 2.0 * (n - 1.0) / n
+        }
+    }
+}
+
+// Summarizes a service's logs in fixed event time windows and flags windows that the
+// forest, trained on the service's recent windows, isolates easily
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IsolationForestConfig {
+    // Seconds per window
+    pub window_secs: i64,
+    // Windows kept for training
+    pub history_windows: usize,
+    // Windows needed before the first model is trained
+    pub min_training_windows: usize,
+    // Seconds between retraining on the current history
+    pub retrain_interval: i64,
+    pub trees: usize,
+    pub sample_size: usize,
+    // Windows that score above this fraction of the training windows are anomalies
+    pub threshold: f64,
+    // Unless the raw isolation score is below this, as when the history is very uniform
+    pub min_isolation_score: f64,
+}
+
+impl Default for IsolationForestConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 60,
+            history_windows: 1440,
+            min_training_windows: 30,
+            retrain_interval: 3600,
+            trees: 100,
+            sample_size: 256,
+            threshold: 0.999,
+            min_isolation_score: 0.6,
+        }
+    }
+}
+
+const FEATURES: [&str; 6] = [
+    "events_per_minute",
+    "error_ratio",
+    "warn_ratio",
+    "distinct_templates",
+    "mean_message_length",
+    "message_length_stddev",
+];
+
+#[derive(Default)]
+struct WindowStats {
+    events: usize,
+    errors: usize,
+    warnings: usize,
+    templates: HashSet<String>,
+    length_sum: f64,
+    length_sq_sum: f64,
+    // Reported with anomalies: the latest error, or the latest event if there were none
+    sample: Option<LogEvent>,
+}
+
+impl WindowStats {
+    fn features(&self, window_secs: i64) -> Vec<f64> {
+        let n = self.events.max(1) as f64;
+        let mean = self.length_sum / n;
+        vec![
+            self.events as f64 * 60.0 / window_secs as f64,
+            self.errors as f64 / n,
+            self.warnings as f64 / n,
+            self.templates.len() as f64,
+            mean,
+            (self.length_sq_sum / n - mean * mean).max(0.0).sqrt(),
+        ]
+    }
+}
+
+// The training set's score distribution, for calibration, and per-feature mean and
+// deviation, to explain anomalies
+struct Model {
+    forest: IsolationForest,
+    scores: Vec<f64>,
+    mean: Vec<f64>,
+    stddev: Vec<f64>,
+    trained_at: DateTime<Utc>,
+    windows: usize,
+}
+
+pub struct IsolationForestDetector {
+    config: IsolationForestConfig,
+    service: String,
+    windows: TumblingWindows<WindowStats>,
+    history: VecDeque<Vec<f64>>,
+    model: Option<Model>,
+    rng: StdRng,
+    last_score: Option<f64>,
+}
+
+impl IsolationForestDetector {
+    pub fn build(value: serde_yaml::Value) -> Result<Box<dyn Detector>, Vec<String>> {
+        let config: IsolationForestConfig = settings(value)?;
+        let mut errors = Vec::new();
+        if config.window_secs <= 0 || config.retrain_interval <= 0 {
+            errors.push("window_secs and retrain_interval must be greater than 0".to_string());
+        }
+        if config.min_training_windows < 2 || config.min_training_windows > config.history_windows {
+            errors.push("min_training_windows must be at least 2 and at most history_windows".to_string());
+        }
+        if config.trees == 0 || config.sample_size < 2 {
+            errors.push("trees must be greater than 0 and sample_size at least 2".to_string());
+        }
+        if config.threshold <= 0.0 || config.threshold > 1.0 {
+            errors.push("threshold must be in (0, 1]".to_string());
+        }
+        if !(0.0..=1.0).contains(&config.min_isolation_score) {
+            errors.push("min_isolation_score must be between 0 and 1".to_string());
+        }
+        if !errors.is_empty() { return Err(errors); }
+        Ok(Box::new(Self {
+            windows: TumblingWindows::new(config.window_secs),
+            config,
+            service: String::new(),
+            history: VecDeque::new(),
+            model: None,
+            rng: StdRng::from_entropy(),
+            last_score: None,
+        }))
+    }
+
+    fn train(&mut self, now: DateTime<Utc>) {
+        let points: Vec<Vec<f64>> = self.history.iter().cloned().collect();
+        let forest = IsolationForest::train(&points, self.config.trees, self.config.sample_size, &mut self.rng);
+        let mut scores: Vec<f64> = points.iter().map(|p| forest.score(p)).collect();
+        scores.sort_by(f64::total_cmp);
+
+        let n = points.len() as f64;
+        let mean: Vec<f64> = (0..FEATURES.len()).map(|f| points.iter().map(|p| p[f]).sum::<f64>() / n).collect();
+        let stddev = (0..FEATURES.len())
+            .map(|f| (points.iter().map(|p| (p[f] - mean[f]).powi(2)).sum::<f64>() / n).sqrt())
+            .collect();
+        self.model = Some(Model { forest, scores, mean, stddev, trained_at: now, windows: points.len() });
+    }
+
+    // Scores a closed window against the model; Some if it is an anomaly
+    fn score(&mut self, start: i64, stats: &WindowStats, features: &[f64]) -> Option<Anomaly> {
+        let model = self.model.as_ref()?;
+        let raw = model.forest.score(features);
+        // Share of training windows that were easier to explain than this one
+        let score = model.scores.partition_point(|&s| s < raw) as f64 / model.scores.len() as f64;
+        self.last_score = Some(score);
+        if score < self.config.threshold || raw < self.config.min_isolation_score { return None; }
+
+        // Name the features furthest from their usual values
+        let mut deviations: Vec<(usize, f64)> = (0..FEATURES.len())
+            .map(|f| (f, (features[f] - model.mean[f]).abs() / model.stddev[f].max(1e-9)))
+            .collect();
+        deviations.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let unusual: Vec<String> = deviations.iter().take(2)
+            .map(|&(f, _)| format!("{} {:.2} (usual {:.2})", FEATURES[f], features[f], model.mean[f]))
+            .collect();
+
+        let window_time = Utc.timestamp_opt(start, 0).single().unwrap_or_default().to_rfc3339();
+        let event = stats.sample.clone()
+            .unwrap_or_else(|| empty_window_event(&self.service, start, self.config.window_secs));
+        Some(Anomaly {
+            timestamp: Utc::now().to_rfc3339(),
+            score,
+            event,
+            reason: format!(
+                "Unusual {}s window at {} (isolation score {:.2}): {}",
+                self.config.window_secs, window_time, raw, unusual.join(", "),
+            ),
+            algorithm: "IsolationForest".to_string(),
+        })
+    }
+}
+
+impl Detector for IsolationForestDetector {
+    fn observe(&mut self, event: &LogEvent) {
+        if self.service.is_empty() {
+            self.service = event.service.clone();
+        }
+        // Late events are turned away before they get here
+        let Some(stats) = self.windows.get(event.event_timestamp()) else { return };
+        stats.events += 1;
+        match event.level.as_str() {
+            "ERROR" => stats.errors += 1,
+            "WARN" => stats.warnings += 1,
+            _ => {}
+        }
+        if let Some((id, _)) = drain::lookup(&event.service, &event.message) {
+            stats.templates.insert(id);
+        }
+        let length = event.message.len() as f64;
+        stats.length_sum += length;
+        stats.length_sq_sum += length * length;
+        if event.level == "ERROR" || stats.sample.as_ref().is_none_or(|s| s.level != "ERROR") {
+            stats.sample = Some(event.clone());
+        }
+    }
+
+    fn evaluate(&mut self, events: &EventWindow) -> Vec<Anomaly> {
+        let now = Utc::now();
+        let window_secs = self.config.window_secs;
+
+        let mut anomalies = Vec::new();
+        // After a long gap, only the windows that would still be in the history matter
+        for (start, stats) in self.windows.close(events.watermark, self.config.history_windows) {
+            let features = stats.features(window_secs);
+            anomalies.extend(self.score(start, &stats, &features));
+
+            self.history.push_back(features);
+            if self.history.len() > self.config.history_windows {
+                self.history.pop_front();
+            }
+        }
+
+        let due = match &self.model {
+            None => self.history.len() >= self.config.min_training_windows,
+            Some(model) => (now - model.trained_at).num_seconds() >= self.config.retrain_interval,
+        };
+        if due {
+            self.train(now);
+        }
+        anomalies
+    }
+
+    fn state(&self) -> serde_json::Value {
+        json!({
+            "config": self.config,
+            "features": FEATURES,
+            "history_windows": self.history.len(),
+            "open_windows": self.windows.open_windows(),
+            "trained_at": self.model.as_ref().map(|m| m.trained_at.to_rfc3339()),
+            "trained_windows": self.model.as_ref().map(|m| m.windows),
+            "last_score": self.last_score,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Points clustered around the origin, roughly normal in each of three features
+    fn cluster(rng: &mut StdRng, n: usize) -> Vec<Vec<f64>> {
+        (0..n).map(|_| (0..3).map(|_| (0..4).map(|_| rng.gen_range(-1.0..1.0)).sum()).collect()).collect()
+    }
+
+    fn forest() -> IsolationForest {
+        let mut rng = StdRng::seed_from_u64(7);
+        let points = cluster(&mut rng, 1000);
+        IsolationForest::train(&points, 100, 256, &mut rng)
+    }
+
+    #[test]
+    fn average_path_matches_the_bst_formula() {
+        assert_eq!(average_path(0), 0.0);
+        assert_eq!(average_path(1), 0.0);
+        assert_eq!(average_path(2), 1.0);
+        // 2(ln(n - 1) + γ) - 2(n - 1)/n
+        assert!((average_path(3) - 1.2073).abs() < 1e-3);
+        assert!((average_path(256) - 10.2448).abs() < 1e-3);
+    }
+
+    #[test]
+    fn an_obvious_outlier_scores_near_one() {
+        let forest = forest();
+        assert!(forest.score(&[20.0, -20.0, 20.0]) > 0.9);
+        // Far out in one feature only is still easy to isolate
+        assert!(forest.score(&[0.0, 12.0, 0.0]) > 0.9);
+    }
+
+    #[test]
+    fn points_like_the_training_data_score_at_most_a_half() {
+        let forest = forest();
+        assert!(forest.score(&[0.0, 0.0, 0.0]) <= 0.5);
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut scores: Vec<f64> = cluster(&mut rng, 200).iter().map(|p| forest.score(p)).collect();
+        scores.sort_by(f64::total_cmp);
+        assert!(scores[scores.len() / 2] <= 0.5, "median score {}", scores[scores.len() / 2]);
+    }
+
+    #[test]
+    fn an_untrained_forest_scores_zero() {
+        let forest = IsolationForest::train(&[vec![1.0]], 0, 256, &mut StdRng::seed_from_u64(1));
+        assert_eq!(forest.score(&[5.0]), 0.0);
+    }
+}
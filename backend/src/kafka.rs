@@ -2,28 +2,85 @@ use rdkafka::config::ClientConfig;
 use rdkafka::consumer::{Consumer, StreamConsumer};
 use rdkafka::Message;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use actix_web::web;
+use crate::indexer::BulkIndexer;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LogEvent {
-    pub timestamp: String,
+    // When the event happened at the source; producers that predate the split send `timestamp`
+    #[serde(alias = "timestamp")]
+    pub event_time: String,
+    // When the pipeline first received the event
+    #[serde(default = "now_rfc3339")]
+    pub ingest_time: String,
     pub level: String,
     pub message: String,
     pub service: String,
     pub trace_id: Option<String>,
+    // Structured fields extracted at the source (e.g. by agent parsers)
+    #[serde(default)]
+    pub fields: HashMap<String, serde_json::Value>,
 }
 
-pub async fn start_consumer() {
-    let consumer: StreamConsumer = ClientConfig::new()
-        .set("group.id", "ml-monitoring")
-        .set("bootstrap.servers", "localhost:9092")
-        .set("auto.offset.reset", "latest")
+impl LogEvent {
+    // Parsed event time, falling back to ingest time for unparseable source timestamps
+    pub fn event_timestamp(&self) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(&self.event_time)
+            .or_else(|_| DateTime::parse_from_rfc3339(&self.ingest_time))
+            .map(|ts| ts.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now())
+    }
+}
+
+fn now_rfc3339() -> String {
+    Utc::now().to_rfc3339()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KafkaConfig {
+    // Off for deployments that only take logs from agents
+    pub enabled: bool,
+    // Comma-separated host:port list, passed to librdkafka as `bootstrap.servers`
+    pub brokers: String,
+    pub group_id: String,
+    pub topic: String,
+    // Where a new consumer group starts reading: earliest or latest
+    pub auto_offset_reset: String,
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            brokers: "localhost:9092".to_string(),
+            group_id: "ml-monitoring".to_string(),
+            topic: "logs".to_string(),
+            auto_offset_reset: "latest".to_string(),
+        }
+    }
+}
+
+pub async fn start_consumer(config: KafkaConfig, indexer: web::Data<BulkIndexer>) {
+    let consumer: StreamConsumer = match ClientConfig::new()
+        .set("group.id", &config.group_id)
+        .set("bootstrap.servers", &config.brokers)
+        .set("auto.offset.reset", &config.auto_offset_reset)
         .create()
-        .expect("Consumer creation failed");
+    {
+        Ok(consumer) => consumer,
+        Err(e) => {
+            eprintln!("Kafka consumer creation failed: {}", e);
+            return;
+        }
+    };
 
-    consumer
-        .subscribe(&["logs"])
-        .expect("Can't subscribe to topic");
+    if let Err(e) = consumer.subscribe(&[&config.topic]) {
+        eprintln!("Can't subscribe to Kafka topic {}: {}", config.topic, e);
+        return;
+    }
 
     loop {
         match consumer.recv().await {
@@ -31,7 +88,7 @@ pub async fn start_consumer() {
                 if let Some(payload) = message.payload() {
                     if let Ok(log_str) = std::str::from_utf8(payload) {
                         if let Ok(log_event) = serde_json::from_str::<LogEvent>(log_str) {
-                            process_log_event(log_event).await;
+                            process_log_event(&indexer, log_event).await;
                         }
                     }
                 }
@@ -41,9 +98,13 @@ pub async fn start_consumer() {
     }
 }
 
-async fn process_log_event(event: LogEvent) {
-    // Store in OpenSearch
-    crate::opensearch::index_log(&event).await;
+async fn process_log_event(indexer: &BulkIndexer, event: LogEvent) {
+    crate::stats::record(&event);
+    
+    // Queue for the log store; waits while the indexer is backed up, which pauses consumption
+    if let Err(e) = indexer.submit(&event).await {
+        eprintln!("Failed to queue log for indexing: {}", e);
+    }
     
     // Send to ML pipeline
     crate::ml::analyze_event(&event).await;
@@ -0,0 +1,741 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use crate::store::{AggregateQuery, Bucket, BulkDocument, BulkError, BulkOutcome, Cursor, GroupBy, LogFilter, LogRecord, LogStore, SearchQuery, SearchResults, SortOrder, StoredLog};
+
+// How often logs past `retention_days` are removed
+const RETENTION_CHECK: std::time::Duration = std::time::Duration::from_secs(3600);
+// Fields indexed as exact `field:value` terms, besides `fields.*`
+const KEYWORD_FIELDS: [&str; 3] = ["level", "service", "trace_id"];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalStoreConfig {
+    // Directory holding the segment files; created on first write
+    pub path: String,
+    // Logs per segment. A full segment is sealed and its inverted index saved next to it.
+    pub segment_size: usize,
+    // Days of logs kept, by event time; 0 keeps them forever
+    pub retention_days: u32,
+}
+
+impl Default for LocalStoreConfig {
+    fn default() -> Self {
+        Self {
+            path: "backend_state/logs".to_string(),
+            segment_size: 50_000,
+            retention_days: 30,
+        }
+    }
+}
+
+// Where a log sits in its segment's data file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DocEntry {
+    offset: u64,
+    len: u32,
+    // Epoch milliseconds
+    event_time: i64,
+    // Breaks sort ties between logs from the same millisecond
+    id: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct SegmentIndex {
+    docs: Vec<DocEntry>,
+    // Term to ascending document numbers. Message words are lowercased; keyword fields
+    // are kept as `field:value`, case and all, like OpenSearch keywords.
+    postings: HashMap<String, Vec<u32>>,
+}
+
+impl SegmentIndex {
+    fn add(&mut self, entry: DocEntry, source: &serde_json::Value) {
+        let doc = self.docs.len() as u32;
+        self.docs.push(entry);
+        for term in terms(source) {
+            self.postings.entry(term).or_default().push(doc);
+        }
+    }
+}
+
+// `NNNNNNNNNN.log` holds one `{"_id", "_source"}` line per log, `NNNNNNNNNN.idx` the
+// index of a sealed segment. The open segment's index is rebuilt from its data at startup.
+struct Segment {
+    id: u64,
+    index: SegmentIndex,
+    // Length of the data file
+    len: u64,
+    sealed: bool,
+}
+
+impl Segment {
+    fn time_bounds(&self) -> Option<(i64, i64)> {
+        let times = self.index.docs.iter().map(|d| d.event_time);
+        Some((times.clone().min()?, times.max()?))
+    }
+}
+
+#[derive(Default)]
+struct State {
+    // Oldest first; only the last one can be open for writes
+    segments: Vec<Segment>,
+    writer: Option<File>,
+}
+
+// Embedded store: append-only segment files with a per-segment inverted index, all
+// indexes held in memory. Meant for small deployments and CI, not for the 50k events/s path.
+pub struct LocalStore {
+    config: LocalStoreConfig,
+    dir: PathBuf,
+    state: RwLock<State>,
+}
+
+impl LocalStore {
+    pub fn open(config: &LocalStoreConfig) -> Result<Self, String> {
+        let dir = PathBuf::from(&config.path);
+        let mut state = State::default();
+
+        if dir.exists() {
+            let mut ids: Vec<u64> = fs::read_dir(&dir)
+                .map_err(|e| format!("failed to read {}: {}", dir.display(), e))?
+                .filter_map(|entry| {
+                    let name = entry.ok()?.file_name().into_string().ok()?;
+                    name.strip_suffix(".log")?.parse().ok()
+                })
+                .collect();
+            ids.sort_unstable();
+
+            for (i, &id) in ids.iter().enumerate() {
+                let last = i + 1 == ids.len();
+                let segment = load_segment(&dir, id, last, config.segment_size)?;
+                state.segments.push(segment);
+            }
+            if let Some(segment) = state.segments.last().filter(|s| !s.sealed) {
+                state.writer = Some(open_writer(&dir, segment.id)?);
+            }
+        }
+
+        let logs: usize = state.segments.iter().map(|s| s.index.docs.len()).sum();
+        println!("Local log store at {}: {} logs in {} segments", dir.display(), logs, state.segments.len());
+        Ok(Self { config: config.clone(), dir, state: RwLock::new(state) })
+    }
+
+    fn append(&self, docs: &[BulkDocument]) -> Result<(), String> {
+        let mut state = self.state.write().unwrap();
+        let State { segments, writer } = &mut *state;
+
+        if segments.last().is_none_or(|s| s.sealed) {
+            fs::create_dir_all(&self.dir).map_err(|e| format!("failed to create {}: {}", self.dir.display(), e))?;
+            let id = segments.last().map_or(1, |s| s.id + 1);
+            *writer = Some(open_writer(&self.dir, id)?);
+            segments.push(Segment { id, index: SegmentIndex::default(), len: 0, sealed: false });
+        }
+        let segment = segments.last_mut().expect("an open segment was just ensured");
+        let file = writer.as_mut().expect("the open segment has a writer");
+
+        let mut buf = Vec::new();
+        let mut entries = Vec::with_capacity(docs.len());
+        for doc in docs {
+            let offset = segment.len + buf.len() as u64;
+            serde_json::to_writer(&mut buf, &StoredLog { id: doc.id.clone(), source: doc.source.clone() })
+                .map_err(|e| e.to_string())?;
+            let len = (segment.len + buf.len() as u64 - offset) as u32;
+            buf.push(b'\n');
+            entries.push(DocEntry { offset, len, event_time: doc.event_time.timestamp_millis(), id: doc.id.clone() });
+        }
+
+        // A failed write is cut off again so a retry does not store a partial batch twice
+        if let Err(e) = file.write_all(&buf).and_then(|_| file.sync_data()) {
+            let _ = file.set_len(segment.len);
+            return Err(format!("failed to write segment {}: {}", segment.id, e));
+        }
+        segment.len += buf.len() as u64;
+        for (doc, entry) in docs.iter().zip(entries) {
+            segment.index.add(entry, &doc.source);
+        }
+
+        if segment.index.docs.len() >= self.config.segment_size {
+            save_index(&self.dir, segment)?;
+            segment.sealed = true;
+            *writer = None;
+        }
+        Ok(())
+    }
+
+    // Matching document numbers of each segment
+    fn matches(state: &State, filter: &LogFilter, start: Option<i64>, end: Option<i64>) -> Vec<(usize, Vec<u32>)> {
+        state.segments.iter().enumerate().map(|(i, segment)| {
+            let mask = filter_mask(filter, start, end, &segment.index);
+            let docs = (0..segment.index.docs.len() as u32).filter(|&doc| mask[doc as usize]).collect();
+            (i, docs)
+        }).collect()
+    }
+
+    fn read_logs(&self, state: &State, wanted: &[(usize, u32)]) -> Result<Vec<StoredLog>, String> {
+        let mut files: HashMap<usize, File> = HashMap::new();
+        wanted.iter().map(|&(i, doc)| {
+            let segment = &state.segments[i];
+            let entry = &segment.index.docs[doc as usize];
+            let file = match files.entry(i) {
+                Entry::Occupied(file) => file.into_mut(),
+                Entry::Vacant(slot) => slot.insert(
+                    File::open(data_path(&self.dir, segment.id)).map_err(|e| format!("failed to open segment {}: {}", segment.id, e))?,
+                ),
+            };
+            let mut buf = vec![0; entry.len as usize];
+            file.seek(SeekFrom::Start(entry.offset))
+                .and_then(|_| file.read_exact(&mut buf))
+                .map_err(|e| format!("failed to read segment {}: {}", segment.id, e))?;
+            serde_json::from_slice(&buf).map_err(|e| format!("corrupt log in segment {}: {}", segment.id, e))
+        }).collect()
+    }
+
+    fn delete(&self, start: i64, end: i64) -> Result<u64, String> {
+        let mut state = self.state.write().unwrap();
+        let State { segments, writer } = &mut *state;
+        let mut deleted = 0u64;
+        let mut kept = Vec::with_capacity(segments.len());
+
+        for mut segment in segments.drain(..) {
+            let Some((min, max)) = segment.time_bounds() else {
+                kept.push(segment);
+                continue;
+            };
+            if max < start || min >= end {
+                kept.push(segment);
+                continue;
+            }
+            if !segment.sealed {
+                *writer = None;
+            }
+
+            if min >= start && max < end {
+                // Entirely in range: drop the files
+                deleted += segment.index.docs.len() as u64;
+                let _ = fs::remove_file(index_path(&self.dir, segment.id));
+                fs::remove_file(data_path(&self.dir, segment.id))
+                    .map_err(|e| format!("failed to remove segment {}: {}", segment.id, e))?;
+                continue;
+            }
+
+            // Partly in range: rewrite the segment without the deleted logs
+            let before = segment.index.docs.len();
+            segment = rewrite_segment(&self.dir, &segment, |t| t < start || t >= end)?;
+            deleted += (before - segment.index.docs.len()) as u64;
+            kept.push(segment);
+        }
+
+        if let Some(segment) = kept.last().filter(|s| !s.sealed) {
+            if writer.is_none() {
+                *writer = Some(open_writer(&self.dir, segment.id)?);
+            }
+        }
+        *segments = kept;
+        Ok(deleted)
+    }
+}
+
+#[async_trait]
+impl LogStore for LocalStore {
+    // Enforces retention for as long as the backend runs
+    async fn prepare(&self) {
+        let days = self.config.retention_days;
+        if days == 0 {
+            return;
+        }
+        let mut interval = tokio::time::interval(RETENTION_CHECK);
+        loop {
+            interval.tick().await;
+            let cutoff = Utc::now() - chrono::Duration::days(days as i64);
+            match self.delete_range(DateTime::<Utc>::MIN_UTC, cutoff).await {
+                Ok(0) => {}
+                Ok(deleted) => println!("Removed {} logs older than {} days", deleted, days),
+                Err(e) => eprintln!("Local store retention failed: {}", e),
+            }
+        }
+    }
+
+    async fn bulk_index(&self, docs: &[BulkDocument]) -> Result<Vec<BulkOutcome>, BulkError> {
+        self.append(docs).map_err(BulkError::Retryable)?;
+        Ok(docs.iter().map(|_| BulkOutcome::Stored).collect())
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<SearchResults, String> {
+        let state = self.state.read().unwrap();
+        let start = query.start.map(|t| t.timestamp_millis());
+        let end = query.end.map(|t| t.timestamp_millis());
+        let mut hits: Vec<(Cursor, usize, u32)> = Self::matches(&state, &query.filter, start, end).into_iter()
+            .flat_map(|(i, docs)| {
+                let segment = &state.segments[i];
+                docs.into_iter().map(move |doc| {
+                    let entry = &segment.index.docs[doc as usize];
+                    (Cursor { event_time: entry.event_time, id: entry.id.clone() }, i, doc)
+                })
+            })
+            .collect();
+        let total = hits.len() as u64;
+
+        match query.sort {
+            SortOrder::Desc => hits.sort_unstable_by(|a, b| b.0.cmp(&a.0)),
+            SortOrder::Asc => hits.sort_unstable_by(|a, b| a.0.cmp(&b.0)),
+        }
+        let skip = match &query.after {
+            Some(after) => hits.partition_point(|(position, _, _)| match query.sort {
+                SortOrder::Desc => position >= after,
+                SortOrder::Asc => position <= after,
+            }),
+            None => 0,
+        };
+        let page: Vec<(Cursor, usize, u32)> = hits.into_iter().skip(skip).take(query.size).collect();
+
+        let next_cursor = page.last().filter(|_| page.len() == query.size).map(|(position, _, _)| position.clone());
+        let wanted: Vec<(usize, u32)> = page.iter().map(|&(_, i, doc)| (i, doc)).collect();
+        Ok(SearchResults {
+            total,
+            logs: self.read_logs(&state, &wanted)?.into_iter().map(LogRecord::from).collect(),
+            next_cursor,
+        })
+    }
+
+    async fn aggregate(&self, query: &AggregateQuery) -> Result<Vec<Bucket>, String> {
+        let (start, end) = (query.start.timestamp_millis(), query.end.timestamp_millis());
+        let state = self.state.read().unwrap();
+        let matches = Self::matches(&state, &query.filter, Some(start), Some(end));
+
+        match &query.group_by {
+            GroupBy::Field { field, size } => {
+                let prefix = format!("{}:", field);
+                let mut counts: HashMap<&str, u64> = HashMap::new();
+                for (i, docs) in &matches {
+                    let segment = &state.segments[*i];
+                    let mut mask = vec![false; segment.index.docs.len()];
+                    for &doc in docs {
+                        mask[doc as usize] = true;
+                    }
+                    for (term, postings) in &segment.index.postings {
+                        if let Some(value) = term.strip_prefix(&prefix) {
+                            let count = postings.iter().filter(|&&doc| mask[doc as usize]).count() as u64;
+                            if count > 0 {
+                                *counts.entry(value).or_default() += count;
+                            }
+                        }
+                    }
+                }
+                let mut buckets: Vec<Bucket> = counts.into_iter()
+                    .map(|(key, count)| Bucket { key: key.to_string(), count })
+                    .collect();
+                buckets.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+                buckets.truncate(*size);
+                Ok(buckets)
+            }
+            GroupBy::Interval(width) => {
+                let width = width.num_milliseconds();
+                let first = start.div_euclid(width) * width;
+                let mut counts = vec![0u64; ((end - 1 - first).div_euclid(width) + 1).max(0) as usize];
+                for (i, docs) in &matches {
+                    let segment = &state.segments[*i];
+                    for &doc in docs {
+                        let t = segment.index.docs[doc as usize].event_time;
+                        counts[((t - first) / width) as usize] += 1;
+                    }
+                }
+                Ok(counts.into_iter().enumerate().map(|(n, count)| Bucket {
+                    key: DateTime::from_timestamp_millis(first + n as i64 * width)
+                        .map(|ts| ts.to_rfc3339())
+                        .unwrap_or_default(),
+                    count,
+                }).collect())
+            }
+        }
+    }
+
+    async fn delete_range(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<u64, String> {
+        self.delete(start.timestamp_millis(), end.timestamp_millis())
+    }
+}
+
+fn data_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:010}.log", id))
+}
+
+fn index_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:010}.idx", id))
+}
+
+fn open_writer(dir: &Path, id: u64) -> Result<File, String> {
+    let path = data_path(dir, id);
+    OpenOptions::new().create(true).append(true).open(&path)
+        .map_err(|e| format!("failed to open {}: {}", path.display(), e))
+}
+
+// Written to a temporary file first so a crash never leaves a half-written index behind
+fn save_index(dir: &Path, segment: &Segment) -> Result<(), String> {
+    let path = index_path(dir, segment.id);
+    let tmp = path.with_extension("idx.tmp");
+    let data = serde_json::to_vec(&segment.index).map_err(|e| e.to_string())?;
+    fs::write(&tmp, data)
+        .and_then(|_| fs::rename(&tmp, &path))
+        .map_err(|e| format!("failed to write {}: {}", path.display(), e))
+}
+
+fn load_segment(dir: &Path, id: u64, last: bool, segment_size: usize) -> Result<Segment, String> {
+    let path = data_path(dir, id);
+    let len = fs::metadata(&path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?.len();
+
+    // A sealed segment's saved index is trusted as long as it covers the whole data file
+    if let Ok(data) = fs::read(index_path(dir, id)) {
+        if let Ok(index) = serde_json::from_slice::<SegmentIndex>(&data) {
+            let covered = index.docs.last().map_or(0, |d| d.offset + d.len as u64 + 1);
+            if covered == len {
+                return Ok(Segment { id, index, len, sealed: true });
+            }
+        }
+    }
+
+    let mut segment = scan_segment(dir, id)?;
+    if !last || segment.index.docs.len() >= segment_size {
+        save_index(dir, &segment)?;
+        segment.sealed = true;
+    }
+    Ok(segment)
+}
+
+// Rebuilds a segment's index from its data file. A partly written last line (from a crash
+// mid-write) is cut off.
+fn scan_segment(dir: &Path, id: u64) -> Result<Segment, String> {
+    let path = data_path(dir, id);
+    let data = fs::read(&path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let mut index = SegmentIndex::default();
+    let mut offset = 0usize;
+
+    while offset < data.len() {
+        let Some(end) = data[offset..].iter().position(|&b| b == b'\n').map(|n| offset + n) else {
+            break;
+        };
+        let Ok(log) = serde_json::from_slice::<StoredLog>(&data[offset..end]) else {
+            break;
+        };
+        let entry = DocEntry {
+            offset: offset as u64,
+            len: (end - offset) as u32,
+            event_time: event_time(&log.source),
+            id: log.id.clone(),
+        };
+        index.add(entry, &log.source);
+        offset = end + 1;
+    }
+
+    if offset < data.len() {
+        eprintln!("Discarding {} unreadable bytes at the end of {}", data.len() - offset, path.display());
+        OpenOptions::new().write(true).open(&path)
+            .and_then(|file| file.set_len(offset as u64))
+            .map_err(|e| format!("failed to truncate {}: {}", path.display(), e))?;
+    }
+    Ok(Segment { id, index, len: offset as u64, sealed: false })
+}
+
+// Copies the logs whose event time passes `keep` into a fresh data file that replaces the old one
+fn rewrite_segment(dir: &Path, segment: &Segment, keep: impl Fn(i64) -> bool) -> Result<Segment, String> {
+    let path = data_path(dir, segment.id);
+    let data = fs::read(&path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let mut kept = Vec::with_capacity(data.len());
+    for entry in segment.index.docs.iter().filter(|d| keep(d.event_time)) {
+        let start = entry.offset as usize;
+        kept.extend_from_slice(&data[start..start + entry.len as usize]);
+        kept.push(b'\n');
+    }
+
+    let tmp = path.with_extension("log.tmp");
+    fs::write(&tmp, kept)
+        .and_then(|_| fs::rename(&tmp, &path))
+        .map_err(|e| format!("failed to rewrite {}: {}", path.display(), e))?;
+
+    let mut rewritten = scan_segment(dir, segment.id)?;
+    if segment.sealed {
+        save_index(dir, &rewritten)?;
+        rewritten.sealed = true;
+    }
+    Ok(rewritten)
+}
+
+fn event_time(source: &serde_json::Value) -> i64 {
+    source["event_time"].as_str()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map_or(0, |t| t.timestamp_millis())
+}
+
+fn terms(source: &serde_json::Value) -> HashSet<String> {
+    let mut terms: HashSet<String> = source["message"].as_str().map(words).unwrap_or_default().into_iter().collect();
+    for field in KEYWORD_FIELDS {
+        if let Some(value) = keyword(&source[field]) {
+            terms.insert(format!("{}:{}", field, value));
+        }
+    }
+    if let Some(fields) = source["fields"].as_object() {
+        for (name, value) in fields {
+            if let Some(value) = keyword(value) {
+                terms.insert(format!("fields.{}:{}", name, value));
+            }
+        }
+    }
+    terms
+}
+
+fn keyword(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        serde_json::Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+// Lowercased alphanumeric runs, roughly what the standard analyzer makes of a message
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// Logs that pass the filter and fall in [start, end)
+fn filter_mask(filter: &LogFilter, start: Option<i64>, end: Option<i64>, index: &SegmentIndex) -> Vec<bool> {
+    let mut mask: Vec<bool> = index.docs.iter()
+        .map(|d| start.is_none_or(|start| d.event_time >= start) && end.is_none_or(|end| d.event_time < end))
+        .collect();
+    if !filter.services.is_empty() {
+        mask = and(mask, &any_term(index, filter.services.iter().map(|s| format!("service:{}", s))));
+    }
+    if !filter.levels.is_empty() {
+        mask = and(mask, &any_term(index, filter.levels.iter().map(|l| format!("level:{}", l))));
+    }
+    if let Some(trace_id) = &filter.trace_id {
+        mask = and(mask, &any_term(index, [format!("trace_id:{}", trace_id)]));
+    }
+    for (name, value) in &filter.fields {
+        mask = and(mask, &any_term(index, [format!("fields.{}:{}", name, value)]));
+    }
+    for word in filter.text.as_deref().map(words).unwrap_or_default() {
+        mask = and(mask, &any_term(index, [word]));
+    }
+    mask
+}
+
+// Documents holding at least one of the terms
+fn any_term(index: &SegmentIndex, terms: impl IntoIterator<Item = String>) -> Vec<bool> {
+    let mut mask = vec![false; index.docs.len()];
+    for term in terms {
+        for &doc in index.postings.get(&term).into_iter().flatten() {
+            mask[doc as usize] = true;
+        }
+    }
+    mask
+}
+
+fn and(mut acc: Vec<bool>, mask: &[bool]) -> Vec<bool> {
+    for (a, m) in acc.iter_mut().zip(mask) {
+        *a &= m;
+    }
+    acc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+    use serde_json::json;
+
+    // A fresh directory under the system temp dir, removed again when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("monx-local-store-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            Self(path)
+        }
+
+        fn config(&self, segment_size: usize) -> LocalStoreConfig {
+            LocalStoreConfig { path: self.0.to_string_lossy().into_owned(), segment_size, retention_days: 0 }
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn at(minute: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 10, 17, 10, 0, 0).unwrap() + Duration::minutes(minute)
+    }
+
+    fn doc(id: &str, event_time: DateTime<Utc>) -> BulkDocument {
+        BulkDocument {
+            id: id.to_string(),
+            event_time,
+            source: json!({
+                "log_id": id,
+                "event_time": event_time.to_rfc3339(),
+                "level": "INFO",
+                "message": format!("request {} served", id),
+                "service": "api",
+                "fields": {}
+            }),
+        }
+    }
+
+    fn query(sort: SortOrder, size: usize, after: Option<Cursor>) -> SearchQuery {
+        SearchQuery { filter: LogFilter::default(), start: None, end: None, sort, size, after }
+    }
+
+    async fn ids(store: &LocalStore) -> Vec<String> {
+        let results = store.search(&query(SortOrder::Asc, 100, None)).await.unwrap();
+        results.logs.into_iter().map(|log| log.id).collect()
+    }
+
+    #[tokio::test]
+    async fn seals_full_segments_and_reloads_them() {
+        let dir = TempDir::new("reopen");
+        let store = LocalStore::open(&dir.config(3)).unwrap();
+        // A batch is never split, so the segment fills up across batches
+        store.append(&[doc("d0", at(0)), doc("d1", at(1))]).unwrap();
+        store.append(&[doc("d2", at(2))]).unwrap();
+        store.append(&[doc("d3", at(3)), doc("d4", at(4))]).unwrap();
+
+        assert!(index_path(&dir.0, 1).exists());
+        assert!(!index_path(&dir.0, 2).exists());
+        drop(store);
+
+        // Drop a term from the saved index to tell a reload from a rescan of the data
+        let mut saved: SegmentIndex = serde_json::from_slice(&fs::read(index_path(&dir.0, 1)).unwrap()).unwrap();
+        saved.postings.remove("served");
+        fs::write(index_path(&dir.0, 1), serde_json::to_vec(&saved).unwrap()).unwrap();
+
+        let store = LocalStore::open(&dir.config(3)).unwrap();
+        {
+            let state = store.state.read().unwrap();
+            let shape: Vec<(u64, usize, bool)> = state.segments.iter()
+                .map(|s| (s.id, s.index.docs.len(), s.sealed))
+                .collect();
+            assert_eq!(shape, [(1, 3, true), (2, 2, false)]);
+            assert!(!state.segments[0].index.postings.contains_key("served"));
+            assert_eq!(state.segments[1].index.postings["served"], [0, 1]);
+        }
+
+        // Writes continue in the open segment
+        store.append(&[doc("d5", at(5))]).unwrap();
+        assert_eq!(ids(&store).await, ["d0", "d1", "d2", "d3", "d4", "d5"]);
+        assert!(index_path(&dir.0, 2).exists());
+    }
+
+    #[tokio::test]
+    async fn cuts_off_a_partly_written_last_line() {
+        let dir = TempDir::new("crash");
+        let store = LocalStore::open(&dir.config(10)).unwrap();
+        store.append(&[doc("d0", at(0)), doc("d1", at(1))]).unwrap();
+        drop(store);
+
+        let path = data_path(&dir.0, 1);
+        let intact = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"_id":"d2","_source":{"mess"#).unwrap();
+        drop(file);
+
+        let store = LocalStore::open(&dir.config(10)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), intact);
+        store.append(&[doc("d3", at(3))]).unwrap();
+        assert_eq!(ids(&store).await, ["d0", "d1", "d3"]);
+    }
+
+    #[tokio::test]
+    async fn deletes_whole_and_partial_segments() {
+        let dir = TempDir::new("delete");
+        let store = LocalStore::open(&dir.config(3)).unwrap();
+        for i in 0..8 {
+            store.append(&[doc(&format!("d{}", i), at(i))]).unwrap();
+        }
+
+        // Segment 1 (d0-d2) lies wholly in range, segment 2 (d3-d5) partly
+        assert_eq!(store.delete_range(at(0), at(4)).await.unwrap(), 4);
+        assert!(!data_path(&dir.0, 1).exists());
+        assert!(!index_path(&dir.0, 1).exists());
+        assert_eq!(ids(&store).await, ["d4", "d5", "d6", "d7"]);
+
+        // Partly deleting the open segment keeps it writable
+        assert_eq!(store.delete_range(at(7), at(8)).await.unwrap(), 1);
+        store.append(&[doc("d8", at(8))]).unwrap();
+        assert_eq!(ids(&store).await, ["d4", "d5", "d6", "d8"]);
+        assert_eq!(store.delete_range(at(20), at(30)).await.unwrap(), 0);
+        drop(store);
+
+        let store = LocalStore::open(&dir.config(3)).unwrap();
+        assert_eq!(ids(&store).await, ["d4", "d5", "d6", "d8"]);
+        let text = SearchQuery {
+            filter: LogFilter { text: Some("request d5".to_string()), ..Default::default() },
+            ..query(SortOrder::Asc, 10, None)
+        };
+        assert_eq!(store.search(&text).await.unwrap().total, 1);
+    }
+
+    #[tokio::test]
+    async fn pages_with_search_after_in_both_orders() {
+        let dir = TempDir::new("paging");
+        let store = LocalStore::open(&dir.config(4)).unwrap();
+        // b and c share a millisecond and are told apart by ID
+        store.append(&[doc("c", at(1)), doc("a", at(0)), doc("b", at(1))]).unwrap();
+        store.append(&[doc("e", at(3)), doc("d", at(2))]).unwrap();
+
+        for (sort, expected) in [(SortOrder::Asc, ["a", "b", "c", "d", "e"]), (SortOrder::Desc, ["e", "d", "c", "b", "a"])] {
+            let mut seen = Vec::new();
+            let mut after = None;
+            loop {
+                let page = store.search(&query(sort, 2, after)).await.unwrap();
+                assert_eq!(page.total, 5);
+                seen.extend(page.logs.into_iter().map(|log| log.id));
+                after = page.next_cursor;
+                if after.is_none() {
+                    break;
+                }
+            }
+            assert_eq!(seen, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn buckets_intervals_on_aligned_edges() {
+        let dir = TempDir::new("aggregate");
+        let store = LocalStore::open(&dir.config(100)).unwrap();
+        let ms = Duration::milliseconds;
+        store.append(&[
+            doc("before-start", at(0) + ms(29_999)),
+            doc("start", at(0) + ms(30_000)),
+            doc("end-of-first", at(1) - ms(1)),
+            doc("start-of-second", at(1)),
+            doc("end-of-third", at(3) - ms(1)),
+            doc("at-end", at(3)),
+        ]).unwrap();
+
+        let aggregate = AggregateQuery {
+            filter: LogFilter::default(),
+            start: at(0) + ms(30_000),
+            end: at(3),
+            group_by: GroupBy::Interval(Duration::minutes(1)),
+        };
+        let buckets: Vec<(String, u64)> = store.aggregate(&aggregate).await.unwrap()
+            .into_iter()
+            .map(|b| (b.key, b.count))
+            .collect();
+        assert_eq!(buckets, [
+            (at(0).to_rfc3339(), 2),
+            (at(1).to_rfc3339(), 1),
+            (at(2).to_rfc3339(), 1),
+        ]);
+    }
+}
@@ -1,44 +1,152 @@
-use actix_web::{web, App, HttpServer, middleware::Logger};
+use actix_web::{web, App, HttpServer, middleware::{from_fn, Logger}};
 use actix_cors::Cors;
 
+mod config;
 mod kafka;
 mod opensearch;
 mod ml;
 mod websocket;
 mod api;
+mod auth;
+mod credentials;
+mod detectors;
+mod drain;
+mod indexer;
+mod isolation_forest;
+mod local_store;
+mod random_cut_forest;
+mod registry;
+mod stats;
+mod store;
+mod timeseries;
+mod tls;
+mod windows;
+
+// Reports every configuration problem found before the server starts
+fn exit_with(errors: &[String]) -> ! {
+    eprintln!("Invalid configuration:");
+    for error in errors {
+        eprintln!("  - {}", error);
+    }
+    std::process::exit(2);
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
     
+    let args = config::Args::parse(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, config::USAGE);
+        std::process::exit(2);
+    });
+    if args.help {
+        println!("{}", config::USAGE);
+        return Ok(());
+    }
+    let config = config::load(&args).unwrap_or_else(|errors| exit_with(&errors));
+    
+    let authenticator = auth::Authenticator::new(&config.auth, config.server.json_limit)
+        .unwrap_or_else(|e| exit_with(&[e]));
+    let authenticator = web::Data::new(authenticator);
+    let tls_acceptor = tls::build_acceptor(&config.tls).unwrap_or_else(|e| exit_with(&[e]));
+    let store = store::open(&config.storage, &config.opensearch).unwrap_or_else(|e| exit_with(&[e]));
+    let store: web::Data<dyn store::LogStore> = web::Data::from(store);
+    if args.check {
+        println!("Configuration is valid");
+        return Ok(());
+    }
+    credentials::init(config.credentials.clone())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    
+    // Set up the log store (OpenSearch templates and retention) in the background
+    let prepared = store.clone();
+    tokio::spawn(async move { prepared.prepare().await });
+    
+    // Batch logs into bulk writes to the store
+    let indexer = web::Data::new(indexer::BulkIndexer::start(store.clone(), config.indexer.clone()));
+    
+    // Detectors are built from this as services first send logs
+    ml::configure(config.ml.clone());
+    
     // Start Kafka consumer
-    tokio::spawn(kafka::start_consumer());
+    if config.kafka.enabled {
+        tokio::spawn(kafka::start_consumer(config.kafka.clone(), indexer.clone()));
+    }
     
     // Start ML anomaly detection
     tokio::spawn(ml::start_anomaly_detector());
     
-    HttpServer::new(|| {
+    // Track agent heartbeats and flag agents that stop reporting
+    tokio::spawn(registry::start_liveness_monitor(config.liveness.clone()));
+    
+    // Roll up and expire agent metric history
+    tokio::spawn(timeseries::start_retention_task(config.timeseries.clone()));
+    
+    // Expire old log counters behind /api/metrics
+    tokio::spawn(stats::start_retention_task(config.stats.clone()));
+    
+    let json_limit = config.server.json_limit;
+    let server = HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
             .allow_any_method()
             .allow_any_header();
             
         App::new()
+            // Agent batches can be large once decompressed
+            .app_data(web::JsonConfig::default().limit(json_limit))
+            .app_data(authenticator.clone())
+            .app_data(store.clone())
+            .app_data(indexer.clone())
             .wrap(Logger::default())
             .wrap(cors)
-            .route("/ws", web::get().to(websocket::websocket_handler))
+            .route("/ws", web::get().to(websocket::websocket_handler).wrap(from_fn(auth::require_viewer)).wrap(from_fn(auth::authenticate)))
+            // Enrollment is authenticated by the one-time token in the body. Registered ahead of
+            // the /api scope, whose routes all require an authenticated caller.
+            .route("/api/agents/enroll", web::post().to(api::enroll_agent))
             .service(
                 web::scope("/api")
-                    .route("/logs", web::get().to(api::get_logs))
-                    .route("/metrics", web::get().to(api::get_metrics))
-                    .route("/anomalies", web::get().to(api::get_anomalies))
-                    .route("/agents", web::get().to(api::get_agents))
-                    .route("/agents/register", web::post().to(api::register_agent))
-                    .route("/agents/metrics", web::post().to(api::receive_agent_metrics))
-                    .route("/agents/logs", web::post().to(api::receive_agent_logs))
+                    .wrap(from_fn(auth::authenticate))
+                    // Dashboard reads
+                    .route("/logs", web::get().to(api::get_logs).wrap(from_fn(auth::require_viewer)))
+                    .route("/logs/aggregate", web::get().to(api::get_log_aggregate).wrap(from_fn(auth::require_viewer)))
+                    .route("/metrics", web::get().to(api::get_metrics).wrap(from_fn(auth::require_viewer)))
+                    .route("/anomalies", web::get().to(api::get_anomalies).wrap(from_fn(auth::require_viewer)))
+                    .route("/detectors", web::get().to(api::get_detectors).wrap(from_fn(auth::require_viewer)))
+                    .route("/patterns", web::get().to(api::get_patterns).wrap(from_fn(auth::require_viewer)))
+                    .route("/agents", web::get().to(api::get_agents).wrap(from_fn(auth::require_viewer)))
+                    .route("/agents/{id}/metrics", web::get().to(api::get_agent_metrics).wrap(from_fn(auth::require_viewer)))
+                    .route("/services", web::get().to(api::get_services).wrap(from_fn(auth::require_viewer)))
+                    .route("/indexer", web::get().to(api::get_indexer_stats).wrap(from_fn(auth::require_viewer)))
+                    // Operations
+                    .route("/anomalies", web::delete().to(api::clear_anomalies).wrap(from_fn(auth::require_operator)))
+                    .route("/agents/{id}", web::delete().to(api::remove_agent).wrap(from_fn(auth::require_admin)))
+                    .route("/logs", web::delete().to(api::delete_logs).wrap(from_fn(auth::require_admin)))
+                    .route("/credentials", web::get().to(api::get_credentials).wrap(from_fn(auth::require_admin)))
+                    .route("/credentials/{agent_id}/rotate", web::post().to(api::request_credential_rotation).wrap(from_fn(auth::require_admin)))
+                    .route("/credentials/{agent_id}/revoke", web::post().to(api::revoke_credential).wrap(from_fn(auth::require_admin)))
+                    .route("/enrollment-tokens", web::get().to(api::get_enrollment_tokens).wrap(from_fn(auth::require_admin)))
+                    .route("/enrollment-tokens", web::post().to(api::create_enrollment_token).wrap(from_fn(auth::require_admin)))
+                    // Agent submissions
+                    .route("/agents/register", web::post().to(api::register_agent).wrap(from_fn(auth::require_agent)))
+                    .route("/agents/metrics", web::post().to(api::receive_agent_metrics).wrap(from_fn(auth::require_agent)))
+                    .route("/agents/logs", web::post().to(api::receive_agent_logs).wrap(from_fn(auth::require_agent)))
+                    .route("/agents/batch", web::post().to(api::receive_agent_batch).wrap(from_fn(auth::require_agent)))
+                    .route("/agents/rotate", web::post().to(api::rotate_agent_credential).wrap(from_fn(auth::require_agent)))
             )
     })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+    .on_connect(tls::capture_peer_certificate);
+    
+    let bind = config.server.bind.as_str();
+    let server = match tls_acceptor {
+        Some(acceptor) => {
+            println!("Serving HTTPS on {}", bind);
+            server.bind_openssl(bind, acceptor)?
+        }
+        None => {
+            println!("Serving HTTP on {}", bind);
+            server.bind(bind)?
+        }
+    };
+    server.run().await
 }
@@ -1,7 +1,11 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::{Mutex, LazyLock};
+use serde_yaml::{Mapping, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, LazyLock, OnceLock};
+use crate::detectors;
+use crate::drain::{self, TemplateConfig};
 use crate::kafka::LogEvent;
+use crate::windows::{EventLog, EventWindow, WindowConfig};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Anomaly {
@@ -12,144 +16,278 @@ pub struct Anomaly {
     pub algorithm: String,
 }
 
+// Detection runs every `interval` seconds over each service's events up to its watermark
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MlConfig {
+    pub interval: u64,
+    pub windows: WindowConfig,
+    // Anomalies kept for the API
+    pub max_anomalies: usize,
+    // Settings by detector name; every detector runs with its defaults unless `enabled: false`
+    pub detectors: BTreeMap<String, Value>,
+    // Per-service settings by detector name, merged over `detectors`
+    pub services: BTreeMap<String, BTreeMap<String, Value>>,
+    // Mining of log templates, shared by the detectors and GET /api/patterns
+    pub templates: TemplateConfig,
+}
+
+impl Default for MlConfig {
+    fn default() -> Self {
+        Self {
+            interval: 30,
+            windows: WindowConfig::default(),
+            max_anomalies: 100,
+            detectors: BTreeMap::new(),
+            services: BTreeMap::new(),
+            templates: TemplateConfig::default(),
+        }
+    }
+}
+
+// An anomaly detection algorithm, run separately for every service
+pub trait Detector: Send {
+    // Sees each of the service's events as it arrives, unless it is late
+    fn observe(&mut self, event: &LogEvent);
+
+    // Runs every detection interval. Events before the watermark are final; each detector
+    // keeps track of how far it has got.
+    fn evaluate(&mut self, events: &EventWindow) -> Vec<Anomaly>;
+
+    // Seconds of events before the watermark that `evaluate` reads
+    fn history_secs(&self) -> i64 {
+        0
+    }
+
+    // Settings and internal state, for GET /api/detectors
+    fn state(&self) -> serde_json::Value;
+}
+
+// Builds a detector from its merged settings (without `enabled`), or explains what is wrong with them
+pub type Factory = fn(Value) -> Result<Box<dyn Detector>, Vec<String>>;
+
+// Reads a detector's settings into its config type; unset fields keep their defaults
+pub fn settings<T: serde::de::DeserializeOwned>(settings: Value) -> Result<T, Vec<String>> {
+    serde_yaml::from_value(settings).map_err(|e| vec![e.to_string()])
+}
+
+// Stands in for the triggering event of an anomaly about a window with no logs
+pub fn empty_window_event(service: &str, start: i64, window_secs: i64) -> LogEvent {
+    let start = chrono::DateTime::from_timestamp(start, 0).unwrap_or_default().to_rfc3339();
+    LogEvent {
+        message: format!("No logs between {} and the next {} seconds", start, window_secs),
+        event_time: start,
+        ingest_time: chrono::Utc::now().to_rfc3339(),
+        level: "WARN".to_string(),
+        service: service.to_string(),
+        trace_id: None,
+        fields: Default::default(),
+    }
+}
+
+// A running detector and its registry name
+type NamedDetector = (&'static str, Box<dyn Detector>);
+
+// One service's events and detectors
+struct ServiceState {
+    events: EventLog,
+    detectors: Vec<NamedDetector>,
+}
+
+static CONFIG: OnceLock<MlConfig> = OnceLock::new();
 static ANOMALIES: LazyLock<Mutex<Vec<Anomaly>>> = LazyLock::new(|| Mutex::new(Vec::new()));
-static EVENT_BUFFER: LazyLock<Mutex<Vec<LogEvent>>> = LazyLock::new(|| Mutex::new(Vec::new()));
-static LOG_PATTERNS: LazyLock<Mutex<HashMap<String, u32>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+static SERVICES: LazyLock<Mutex<HashMap<String, ServiceState>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
 
-pub async fn start_anomaly_detector() {
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
-    
-    loop {
-        interval.tick().await;
-        
-        // Run multiple algorithms
-        detect_statistical_anomalies().await;
-        detect_frequency_anomalies().await;
-        detect_pattern_anomalies().await;
+pub fn config() -> &'static MlConfig {
+    CONFIG.get_or_init(MlConfig::default)
+}
+
+// The detector's settings for a service: its `detectors` entry with the service's entry merged
+// over it, and whether it is enabled
+fn detector_settings(config: &MlConfig, service: &str, name: &str) -> Result<(bool, Value), String> {
+    let mut merged = config.detectors.get(name).cloned().unwrap_or(Value::Null);
+    if let Some(overrides) = config.services.get(service).and_then(|s| s.get(name)) {
+        merge(&mut merged, overrides.clone());
     }
+    let mut merged = match merged {
+        Value::Null => Mapping::new(),
+        Value::Mapping(map) => map,
+        _ => return Err("settings must be a mapping".to_string()),
+    };
+    let enabled = match merged.remove("enabled") {
+        None => true,
+        Some(Value::Bool(enabled)) => enabled,
+        Some(_) => return Err("enabled must be true or false".to_string()),
+    };
+    Ok((enabled, Value::Mapping(merged)))
 }
 
-pub async fn analyze_event(event: &LogEvent) {
-    let mut buffer = EVENT_BUFFER.lock().unwrap();
-    buffer.push(event.clone());
-    
-    // Update pattern frequency
-    let pattern_key = extract_log_pattern(&event.message);
-    let mut patterns = LOG_PATTERNS.lock().unwrap();
-    *patterns.entry(pattern_key).or_insert(0) += 1;
-    
-    let len = buffer.len();
-    if len > 1000 {
-        buffer.drain(0..len - 1000);
-    }
-}
-
-// Simple statistical anomaly detection (without isolation forest for now)
-async fn detect_statistical_anomalies() {
-    let buffer = EVENT_BUFFER.lock().unwrap();
-    if buffer.len() < 10 { return; }
-    
-    // Simple heuristic: detect unusually long messages
-    let avg_length: f64 = buffer.iter().map(|e| e.message.len() as f64).sum::<f64>() / buffer.len() as f64;
-    let threshold = avg_length * 3.0; // 3x average length
-    
-    let mut anomalies = ANOMALIES.lock().unwrap();
-    for event in buffer.iter().rev().take(10) {
-        if event.message.len() as f64 > threshold && event.level == "ERROR" {
-            anomalies.push(Anomaly {
-                timestamp: chrono::Utc::now().to_rfc3339(),
-                score: 0.85,
-                event: event.clone(),
-                reason: format!("Unusually long error message ({} chars vs {} avg)", event.message.len(), avg_length as usize),
-                algorithm: "StatisticalAnalysis".to_string(),
-            });
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => { base.insert(key, value); }
+                }
+            }
         }
+        (base, overlay) => *base = overlay,
     }
 }
 
-// Random Cut Forest equivalent (frequency-based)
-async fn detect_frequency_anomalies() {
-    let buffer = EVENT_BUFFER.lock().unwrap();
-    if buffer.len() < 50 { return; }
-    
-    // Count error rates in time windows
-    let recent_errors = buffer.iter().rev().take(20)
-        .filter(|e| e.level == "ERROR").count();
-    
-    let historical_errors = buffer.iter().rev().skip(20).take(100)
-        .filter(|e| e.level == "ERROR").count();
-    
-    let recent_rate = recent_errors as f64 / 20.0;
-    let historical_rate = historical_errors as f64 / 100.0;
-    
-    // Anomaly if recent error rate > 3x historical
-    if recent_rate > historical_rate * 3.0 && recent_rate > 0.1 {
-        let mut anomalies = ANOMALIES.lock().unwrap();
-        if let Some(latest_error) = buffer.iter().rev().find(|e| e.level == "ERROR") {
-            anomalies.push(Anomaly {
-                timestamp: chrono::Utc::now().to_rfc3339(),
-                score: 0.92,
-                event: latest_error.clone(),
-                reason: format!("Error rate spike: {:.1}% vs {:.1}%", recent_rate * 100.0, historical_rate * 100.0),
-                algorithm: "RandomCutForest".to_string(),
-            });
+fn build_detectors(config: &MlConfig, service: &str) -> Result<Vec<NamedDetector>, Vec<String>> {
+    let mut built = Vec::new();
+    let mut errors = Vec::new();
+    for &(name, factory) in detectors::REGISTRY {
+        match detector_settings(config, service, name) {
+            Ok((false, _)) => {}
+            Ok((true, settings)) => match factory(settings) {
+                Ok(detector) => built.push((name, detector)),
+                Err(e) => errors.extend(e.into_iter().map(|e| format!("{}: {}", name, e))),
+            },
+            Err(e) => errors.push(format!("{}: {}", name, e)),
         }
     }
+    if errors.is_empty() { Ok(built) } else { Err(errors) }
 }
 
-// Log pattern embedding (transformer-like)
-async fn detect_pattern_anomalies() {
-    let patterns = LOG_PATTERNS.lock().unwrap();
-    let buffer = EVENT_BUFFER.lock().unwrap();
-    
-    // Find rare patterns (< 1% frequency)
-    let total_logs = buffer.len() as f64;
-    let rare_threshold = (total_logs * 0.01).max(1.0) as u32;
-    
-    for event in buffer.iter().rev().take(10) {
-        let pattern = extract_log_pattern(&event.message);
-        if let Some(&count) = patterns.get(&pattern) {
-            if count < rare_threshold && event.level == "ERROR" {
-                let mut anomalies = ANOMALIES.lock().unwrap();
-                anomalies.push(Anomaly {
-                    timestamp: chrono::Utc::now().to_rfc3339(),
-                    score: 0.78,
-                    event: event.clone(),
-                    reason: format!("Rare error pattern (seen {} times)", count),
-                    algorithm: "LogEmbedding".to_string(),
-                });
-            }
+// Checks the template settings and detector names, and builds every detector with the defaults
+// and with each service's settings
+pub fn validate(config: &MlConfig) -> Vec<String> {
+    let known = |name: &String| detectors::REGISTRY.iter().any(|(known, _)| known == name);
+    let mut errors = Vec::new();
+
+    let templates = &config.templates;
+    if templates.similarity <= 0.0 || templates.similarity > 1.0 {
+        errors.push("ml.templates.similarity must be in (0, 1]".to_string());
+    }
+    if templates.max_children == 0 || templates.max_templates == 0 {
+        errors.push("ml.templates.max_children and ml.templates.max_templates must be greater than 0".to_string());
+    }
+    let windows = &config.windows;
+    if windows.allowed_lateness_secs < 0 || windows.idle_timeout_secs < windows.allowed_lateness_secs {
+        errors.push("ml.windows.allowed_lateness_secs must be at least 0 and at most ml.windows.idle_timeout_secs".to_string());
+    }
+    if windows.max_events == 0 {
+        errors.push("ml.windows.max_events must be greater than 0".to_string());
+    }
+
+    for name in config.detectors.keys().filter(|name| !known(name)) {
+        errors.push(format!("ml.detectors.{}: unknown detector", name));
+    }
+    let defaults = build_detectors(config, "");
+    if let Err(e) = &defaults {
+        errors.extend(e.iter().map(|e| format!("ml.detectors.{}", e)));
+    }
+    for (service, overrides) in &config.services {
+        for name in overrides.keys().filter(|name| !known(name)) {
+            errors.push(format!("ml.services.{}.{}: unknown detector", service, name));
+        }
+        // Bad defaults would be reported again for every service
+        if defaults.is_err() { continue; }
+        if let Err(e) = build_detectors(config, service) {
+            errors.extend(e.into_iter().map(|e| format!("ml.services.{}.{}", service, e)));
         }
     }
+    errors
 }
 
-// Extract log pattern (simplified tokenization)
-fn extract_log_pattern(message: &str) -> String {
-    message
-        .split_whitespace()
-        .map(|word| {
-            if word.chars().any(|c| c.is_ascii_digit()) {
-                "<NUM>"
-            } else if word.contains('@') {
-                "<EMAIL>"
-            } else if word.starts_with('/') {
-                "<PATH>"
-            } else {
-                word
+// Called once at startup, before any events arrive
+pub fn configure(config: MlConfig) {
+    drain::configure(config.templates.clone());
+    let _ = CONFIG.set(config);
+}
+
+pub async fn start_anomaly_detector() {
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(config().interval));
+
+    loop {
+        interval.tick().await;
+
+        let now = chrono::Utc::now();
+        let mut found = Vec::new();
+        for service in SERVICES.lock().unwrap().values_mut() {
+            let watermark = service.events.watermark(&config().windows, now);
+            let view = service.events.view(watermark);
+            for (_, detector) in service.detectors.iter_mut() {
+                found.extend(detector.evaluate(&view));
             }
+
+            let history = service.detectors.iter().map(|(_, d)| d.history_secs()).max().unwrap_or(0);
+            service.events.prune(watermark - chrono::Duration::seconds(history));
+        }
+        ANOMALIES.lock().unwrap().extend(found);
+    }
+}
+
+pub async fn analyze_event(event: &LogEvent) {
+    let config = config();
+    drain::add(&event.service, &event.message, event.event_timestamp());
+
+    let mut services = SERVICES.lock().unwrap();
+    let service = services.entry(event.service.clone()).or_insert_with(|| ServiceState {
+        events: EventLog::default(),
+        // Settings were validated at startup
+        detectors: build_detectors(config, &event.service).unwrap_or_default(),
+    });
+
+    if !service.events.insert(event, &config.windows, chrono::Utc::now()) {
+        return;
+    }
+    for (_, detector) in service.detectors.iter_mut() {
+        detector.observe(event);
+    }
+}
+
+#[derive(Serialize)]
+pub struct ServiceDetectors {
+    service: String,
+    events: usize,
+    watermark: String,
+    // Events that arrived behind the watermark and were not analyzed
+    late_events: u64,
+    // Detector name to its settings and state
+    detectors: BTreeMap<&'static str, serde_json::Value>,
+}
+
+pub fn detector_states() -> Vec<ServiceDetectors> {
+    let now = chrono::Utc::now();
+    let services = SERVICES.lock().unwrap();
+    let mut states: Vec<ServiceDetectors> = services.iter()
+        .map(|(name, service)| ServiceDetectors {
+            service: name.clone(),
+            events: service.events.len(),
+            watermark: service.events.watermark(&config().windows, now).to_rfc3339(),
+            late_events: service.events.late_events,
+            detectors: service.detectors.iter().map(|(name, detector)| (*name, detector.state())).collect(),
         })
-        .collect::<Vec<_>>()
-        .join(" ")
+        .collect();
+    states.sort_by(|a, b| a.service.cmp(&b.service));
+    states
+}
+
+pub fn record_anomaly(anomaly: Anomaly) {
+    ANOMALIES.lock().unwrap().push(anomaly);
+}
+
+// Acknowledges everything detected so far; returns how many were cleared
+pub fn clear_anomalies() -> usize {
+    let mut anomalies = ANOMALIES.lock().unwrap();
+    let cleared = anomalies.len();
+    anomalies.clear();
+    cleared
 }
 
 pub fn get_anomalies() -> Vec<Anomaly> {
+    let max_anomalies = config().max_anomalies;
     let mut anomalies = ANOMALIES.lock().unwrap();
-    
-    // Keep only last 100 anomalies
+
+    // Keep only the most recent anomalies
     let len = anomalies.len();
-    if len > 100 {
-        anomalies.drain(0..len - 100);
+    if len > max_anomalies {
+        anomalies.drain(0..len - max_anomalies);
     }
-    
+
     anomalies.clone()
 }
@@ -1,67 +1,524 @@
-use opensearch::{OpenSearch, http::transport::Transport};
+use opensearch::auth::Credentials;
+use opensearch::cert::{Certificate, CertificateValidation};
+use opensearch::http::transport::{Connection, ConnectionPool, TransportBuilder};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use opensearch::http::headers::HeaderMap;
+use opensearch::http::request::JsonBody;
+use opensearch::http::Method;
+use opensearch::indices::IndicesPutIndexTemplateParts;
+use opensearch::params::Conflicts;
+use opensearch::{BulkParts, DeleteByQueryParts, OpenSearch, SearchParts};
+use serde::Deserialize;
 use serde_json::json;
-use crate::kafka::LogEvent;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use crate::store::{AggregateQuery, Bucket, BulkDocument, BulkError, BulkOutcome, Cursor, GroupBy, LogFilter, LogRecord, LogStore, SearchQuery, SearchResults, SortOrder, StoredLog};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenSearchConfig {
+    // Requests are spread round-robin over these; a comma-separated string is also accepted
+    #[serde(deserialize_with = "crate::config::string_list")]
+    pub nodes: Vec<String>,
+    // Basic auth; the password can instead be read from `password_file`
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub password_file: Option<String>,
+    // PEM CA bundle that node certificates must chain to; the system roots are used when unset
+    pub ca_file: Option<String>,
+    // Accept any node certificate, e.g. the demo certificates of the docker-compose cluster
+    pub insecure_skip_verify: bool,
+    // Seconds before a request is abandoned
+    pub timeout: u64,
+    // Logs are written to `{index_prefix}-YYYY-MM-DD` (daily) or `{index_prefix}-YYYY-MM` (monthly) by event time
+    pub index_prefix: String,
+    pub rollover: Rollover,
+    // Days logs are kept; 0 keeps indices forever. An index is deleted once even its newest
+    // logs are this old, so it lives up to a rollover period longer.
+    pub retention_days: u32,
+    pub shards: u32,
+    pub replicas: u32,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Rollover {
+    Daily,
+    Monthly,
+}
+
+impl Rollover {
+    // Longest stretch of event time one index covers
+    pub fn period_days(self) -> u32 {
+        match self {
+            Rollover::Daily => 1,
+            Rollover::Monthly => 31,
+        }
+    }
+}
+
+impl Default for OpenSearchConfig {
+    fn default() -> Self {
+        Self {
+            nodes: vec!["http://localhost:9200".to_string()],
+            username: None,
+            password: None,
+            password_file: None,
+            ca_file: None,
+            insecure_skip_verify: false,
+            timeout: 30,
+            index_prefix: "logs".to_string(),
+            rollover: Rollover::Daily,
+            retention_days: 30,
+            shards: 1,
+            replicas: 1,
+        }
+    }
+}
+
+// Hands out the configured nodes in turn
+#[derive(Debug, Clone)]
+struct RoundRobinPool {
+    connections: Vec<Connection>,
+    next: Arc<AtomicUsize>,
+}
+
+impl ConnectionPool for RoundRobinPool {
+    fn next(&self) -> Connection {
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.connections.len();
+        self.connections[i].clone()
+    }
+}
+
+// Throttling and server-side errors usually clear up on their own; 0 is an item without a status
+fn is_retryable(status: u16) -> bool {
+    status == 429 || status >= 500 || status == 0
+}
 
 pub struct OpenSearchClient {
     client: OpenSearch,
+    config: OpenSearchConfig,
 }
 
 impl OpenSearchClient {
-    pub fn new() -> Self {
-        let transport = Transport::single_node("http://localhost:9200").unwrap();
-        let client = OpenSearch::new(transport);
-        Self { client }
+    pub fn new(config: &OpenSearchConfig) -> Result<Self, String> {
+        let connections = config.nodes.iter()
+            .map(|node| {
+                url::Url::parse(node)
+                    .map(Connection::new)
+                    .map_err(|e| format!("invalid OpenSearch node {}: {}", node, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if connections.is_empty() {
+            return Err("opensearch.nodes must list at least one node".to_string());
+        }
+        let pool = RoundRobinPool { connections, next: Arc::new(AtomicUsize::new(0)) };
+
+        let mut builder = TransportBuilder::new(pool)
+            .timeout(std::time::Duration::from_secs(config.timeout));
+
+        let password = match &config.password_file {
+            Some(path) => Some(std::fs::read_to_string(path)
+                .map_err(|e| format!("failed to read {}: {}", path, e))?
+                .trim()
+                .to_string()),
+            None => config.password.clone(),
+        };
+        if let Some(username) = &config.username {
+            builder = builder.auth(Credentials::Basic(username.clone(), password.unwrap_or_default()));
+        }
+
+        if config.insecure_skip_verify {
+            println!("WARNING: OpenSearch certificates are not verified");
+            builder = builder.cert_validation(CertificateValidation::None);
+        } else if let Some(path) = &config.ca_file {
+            let pem = std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+            let ca = Certificate::from_pem(&pem)
+                .map_err(|e| format!("invalid CA certificate in {}: {}", path, e))?;
+            builder = builder.cert_validation(CertificateValidation::Full(ca));
+        }
+
+        let transport = builder.build()
+            .map_err(|e| format!("failed to build OpenSearch client: {}", e))?;
+        Ok(Self { client: OpenSearch::new(transport), config: config.clone() })
+    }
+
+    // Route by event time so late-arriving events land next to their neighbours
+    fn index_for(&self, event_time: DateTime<Utc>) -> String {
+        let suffix = match self.config.rollover {
+            Rollover::Daily => event_time.format("%Y-%m-%d"),
+            Rollover::Monthly => event_time.format("%Y-%m"),
+        };
+        format!("{}-{}", self.config.index_prefix, suffix)
+    }
+
+    fn index_pattern(&self) -> String {
+        format!("{}-*", self.config.index_prefix)
+    }
+
+    async fn install_index_management(&self) -> Result<(), String> {
+        println!("Connected to OpenSearch");
+        self.install_index_template().await?;
+        self.install_retention_policy().await
+    }
+
+    // Explicit mappings so `level`, `service` and string fields stay aggregatable keywords.
+    // Applies to indices created from now on; existing ones keep their mapping.
+    async fn install_index_template(&self) -> Result<(), String> {
+        let name = format!("{}-template", self.config.index_prefix);
+        let template = json!({
+            "index_patterns": [self.index_pattern()],
+            "priority": 100,
+            "template": {
+                "settings": {
+                    "number_of_shards": self.config.shards,
+                    "number_of_replicas": self.config.replicas
+                },
+                "mappings": {
+                    "dynamic_templates": [{
+                        "field_strings": {
+                            "path_match": "fields.*",
+                            "match_mapping_type": "string",
+                            "mapping": { "type": "keyword", "ignore_above": 1024 }
+                        }
+                    }],
+                    "properties": {
+                        "@timestamp": { "type": "date" },
+                        "event_time": { "type": "date" },
+                        "ingest_time": { "type": "date" },
+                        "log_id": { "type": "keyword" },
+                        "level": { "type": "keyword" },
+                        "service": { "type": "keyword" },
+                        "trace_id": { "type": "keyword" },
+                        "message": {
+                            "type": "text",
+                            "fields": { "raw": { "type": "keyword", "ignore_above": 2048 } }
+                        },
+                        "fields": { "type": "object" }
+                    }
+                }
+            }
+        });
+
+        let response = self.client.indices()
+            .put_index_template(IndicesPutIndexTemplateParts::Name(&name))
+            .body(template)
+            .send()
+            .await
+            .map_err(|e| format!("failed to install index template {}: {}", name, e))?;
+        check_response(response, &format!("install index template {}", name)).await?;
+        println!("Installed index template {} for {}", name, self.index_pattern());
+        Ok(())
+    }
+
+    // Hot -> delete, as an ISM policy attached to every log index. ISM only knows an index's
+    // creation time, and an index keeps receiving writes for a whole rollover period, so the
+    // period is added to the retention.
+    async fn install_retention_policy(&self) -> Result<(), String> {
+        let policy_id = format!("{}-retention", self.config.index_prefix);
+        let policy_path = format!("/_plugins/_ism/policies/{}", policy_id);
+        let pattern = self.index_pattern();
+
+        let existing = self.ism(Method::Get, &policy_path, None).await?;
+        if self.config.retention_days == 0 {
+            if existing.is_some() {
+                self.ism(Method::Post, &format!("/_plugins/_ism/remove/{}", pattern), None).await?;
+                self.ism(Method::Delete, &policy_path, None).await?;
+                println!("Removed retention policy {}; {} indices are kept forever", policy_id, pattern);
+            }
+            return Ok(());
+        }
+
+        // The description records the settings, so an unchanged policy is left alone
+        let max_age = self.config.retention_days + self.config.rollover.period_days();
+        let description = format!(
+            "Delete {} indices {} days after creation, keeping logs for {} days",
+            pattern, max_age, self.config.retention_days,
+        );
+        let current = existing.as_ref().and_then(|p| p.pointer("/policy/description")).and_then(|d| d.as_str());
+        if current == Some(description.as_str()) {
+            return Ok(());
+        }
+
+        let policy = json!({
+            "policy": {
+                "description": description,
+                "default_state": "hot",
+                "states": [
+                    {
+                        "name": "hot",
+                        "actions": [],
+                        "transitions": [{
+                            "state_name": "delete",
+                            "conditions": { "min_index_age": format!("{}d", max_age) }
+                        }]
+                    },
+                    {
+                        "name": "delete",
+                        "actions": [{ "delete": {} }],
+                        "transitions": []
+                    }
+                ],
+                "ism_template": [{ "index_patterns": [pattern], "priority": 100 }]
+            }
+        });
+        // Updates must name the version they replace
+        let path = match &existing {
+            Some(p) => format!(
+                "{}?if_seq_no={}&if_primary_term={}",
+                policy_path,
+                p["_seq_no"].as_u64().unwrap_or(0),
+                p["_primary_term"].as_u64().unwrap_or(0),
+            ),
+            None => policy_path,
+        };
+        self.ism(Method::Put, &path, Some(policy)).await?;
+
+        // `ism_template` only covers indices created later; attach the ones that already exist
+        let attach = json!({ "policy_id": policy_id });
+        if existing.is_some() {
+            self.ism(Method::Post, &format!("/_plugins/_ism/change_policy/{}", pattern), Some(attach.clone())).await?;
+        }
+        self.ism(Method::Post, &format!("/_plugins/_ism/add/{}", pattern), Some(attach)).await?;
+        println!("Installed retention policy {}: {}", policy_id, description);
+        Ok(())
+    }
+
+    // ISM has no typed API in the client. Returns None for a 404.
+    async fn ism(&self, method: Method, path: &str, body: Option<serde_json::Value>) -> Result<Option<serde_json::Value>, String> {
+        let action = format!("{:?} {}", method, path);
+        let response = self.client
+            .send(method, path, HeaderMap::new(), None::<&()>, body.map(JsonBody::new), None)
+            .await
+            .map_err(|e| format!("{} failed: {}", action, e))?;
+        if response.status_code().as_u16() == 404 {
+            return Ok(None);
+        }
+        check_response(response, &action).await.map(Some)
+    }
+
+    // Runs a search against every log index and returns the raw response
+    async fn search_body(&self, body: serde_json::Value, action: &str) -> Result<serde_json::Value, String> {
+        let index_pattern = self.index_pattern();
+        let response = self.client
+            .search(SearchParts::Index(&[&index_pattern]))
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| format!("OpenSearch {} failed: {}", action, e))?;
+        check_response(response, &format!("OpenSearch {}", action)).await
+    }
+
+    // Sends the documents in `body` and reports each one's outcome, in order
+    async fn send_bulk(&self, body: Vec<JsonBody<serde_json::Value>>, sent: usize) -> Result<Vec<BulkOutcome>, BulkError> {
+        let response = self.client
+            .bulk(BulkParts::None)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| BulkError::Retryable(e.to_string()))?;
+
+        let status = response.status_code();
+        if !status.is_success() {
+            let message = format!("bulk request returned {}", status);
+            return Err(if is_retryable(status.as_u16()) { BulkError::Retryable(message) } else { BulkError::Rejected(message) });
+        }
+        let body: serde_json::Value = response.json().await
+            .map_err(|e| BulkError::Retryable(format!("unreadable bulk response: {}", e)))?;
+
+        let items = body["items"].as_array().map(Vec::as_slice).unwrap_or_default();
+        if items.len() != sent {
+            return Err(BulkError::Retryable(format!("bulk response has {} items for {} documents", items.len(), sent)));
+        }
+        Ok(items.iter().map(|item| {
+            let result = &item["index"];
+            let status = result["status"].as_u64().unwrap_or(0) as u16;
+            if (200..300).contains(&status) {
+                return BulkOutcome::Stored;
+            }
+            let reason = format!(
+                "{} {}: {}",
+                status,
+                result.pointer("/error/type").and_then(|t| t.as_str()).unwrap_or("error"),
+                result.pointer("/error/reason").and_then(|r| r.as_str()).unwrap_or("unknown reason"),
+            );
+            if is_retryable(status) { BulkOutcome::Retry(reason) } else { BulkOutcome::Failed(reason) }
+        }).collect())
     }
 }
 
-static mut CLIENT: Option<OpenSearchClient> = None;
+#[async_trait]
+impl LogStore for OpenSearchClient {
+    // Waits for the cluster, then installs the index template and retention policy. Runs in
+    // the background; the backend serves requests meanwhile.
+    async fn prepare(&self) {
+        let mut delay = std::time::Duration::from_secs(1);
+        loop {
+            let result = match self.client.ping().send().await {
+                Ok(response) if response.status_code().is_success() => self.install_index_management().await,
+                Ok(response) => Err(format!("ping returned {}", response.status_code())),
+                Err(e) => Err(format!("unreachable: {}", e)),
+            };
+            match result {
+                Ok(()) => return,
+                Err(e) => eprintln!("OpenSearch setup failed, retrying in {}s: {}", delay.as_secs(), e),
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(std::time::Duration::from_secs(60));
+        }
+    }
+
+    // One `_bulk` request
+    async fn bulk_index(&self, docs: &[BulkDocument]) -> Result<Vec<BulkOutcome>, BulkError> {
+        // Logs already past retention would recreate indices the policy has deleted
+        let days = self.config.retention_days;
+        let cutoff = (days > 0).then(|| Utc::now() - chrono::Duration::days(days as i64));
+        let expired = |doc: &BulkDocument| cutoff.is_some_and(|cutoff| doc.event_time < cutoff);
 
-fn get_client() -> &'static OpenSearchClient {
-    unsafe {
-        CLIENT.get_or_insert_with(|| OpenSearchClient::new())
+        let mut body: Vec<JsonBody<serde_json::Value>> = Vec::with_capacity(docs.len() * 2);
+        for doc in docs.iter().filter(|doc| !expired(doc)) {
+            body.push(json!({"index": {"_index": self.index_for(doc.event_time), "_id": doc.id}}).into());
+            body.push(doc.source.clone().into());
+        }
+        let sent = body.len() / 2;
+        let mut outcomes = if sent == 0 { Vec::new() } else { self.send_bulk(body, sent).await? }.into_iter();
+        Ok(docs.iter().map(|doc| {
+            if expired(doc) {
+                BulkOutcome::Failed(format!("older than the {}-day retention", days))
+            } else {
+                outcomes.next().expect("one outcome per document sent")
+            }
+        }).collect())
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<SearchResults, String> {
+        let order = match query.sort {
+            SortOrder::Desc => "desc",
+            SortOrder::Asc => "asc",
+        };
+        let mut search_body = json!({
+            "query": filter_query(&query.filter, query.start, query.end),
+            "size": query.size,
+            "track_total_hits": true,
+            "sort": [
+                {"event_time": {"order": order, "unmapped_type": "date"}},
+                {"log_id": {"order": order, "unmapped_type": "keyword"}}
+            ]
+        });
+        if let Some(after) = &query.after {
+            search_body["search_after"] = json!([after.event_time, after.id]);
+        }
+        let body = self.search_body(search_body, "search").await?;
+
+        let hits = body.pointer("/hits/hits").and_then(|h| h.as_array()).cloned().unwrap_or_default();
+        let next_cursor = match hits.last() {
+            Some(last) if hits.len() == query.size => Some(Cursor {
+                event_time: last.pointer("/sort/0").and_then(|t| t.as_i64()).unwrap_or(0),
+                id: last.pointer("/sort/1").and_then(|id| id.as_str()).unwrap_or_default().to_string(),
+            }),
+            _ => None,
+        };
+        Ok(SearchResults {
+            total: body.pointer("/hits/total/value").and_then(|t| t.as_u64()).unwrap_or(hits.len() as u64),
+            logs: hits.into_iter()
+                .filter_map(|hit| serde_json::from_value::<StoredLog>(hit).ok())
+                .map(LogRecord::from)
+                .collect(),
+            next_cursor,
+        })
+    }
+
+    async fn aggregate(&self, query: &AggregateQuery) -> Result<Vec<Bucket>, String> {
+        let groups = match &query.group_by {
+            GroupBy::Field { field, size } => json!({ "terms": { "field": field, "size": size } }),
+            GroupBy::Interval(width) => json!({
+                "date_histogram": {
+                    "field": "event_time",
+                    "fixed_interval": format!("{}s", width.num_seconds()),
+                    "min_doc_count": 0,
+                    "extended_bounds": {
+                        "min": query.start.timestamp_millis(),
+                        "max": query.end.timestamp_millis() - 1
+                    }
+                }
+            }),
+        };
+        let search_body = json!({
+            "size": 0,
+            "query": filter_query(&query.filter, Some(query.start), Some(query.end)),
+            "aggs": { "groups": groups }
+        });
+        let body = self.search_body(search_body, "aggregation").await?;
+
+        let buckets = body.pointer("/aggregations/groups/buckets").and_then(|b| b.as_array()).cloned().unwrap_or_default();
+        Ok(buckets.iter().map(|bucket| {
+            let key = match (&query.group_by, &bucket["key"]) {
+                // Histogram keys are epoch milliseconds
+                (GroupBy::Interval(_), key) => key.as_i64()
+                    .and_then(DateTime::from_timestamp_millis)
+                    .map(|ts| ts.to_rfc3339())
+                    .unwrap_or_default(),
+                (_, serde_json::Value::String(key)) => key.clone(),
+                (_, key) => key.to_string(),
+            };
+            Bucket { key, count: bucket["doc_count"].as_u64().unwrap_or(0) }
+        }).collect())
+    }
+
+    async fn delete_range(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<u64, String> {
+        let index_pattern = self.index_pattern();
+        let response = self.client
+            .delete_by_query(DeleteByQueryParts::Index(&[&index_pattern]))
+            .conflicts(Conflicts::Proceed)
+            .refresh(true)
+            .body(json!({ "query": filter_query(&LogFilter::default(), Some(start), Some(end)) }))
+            .send()
+            .await
+            .map_err(|e| format!("OpenSearch delete failed: {}", e))?;
+        let body = check_response(response, "OpenSearch delete").await?;
+        Ok(body["deleted"].as_u64().unwrap_or(0))
     }
 }
 
-pub async fn index_log(event: &LogEvent) {
-    let client = get_client();
-    let index_name = format!("logs-{}", chrono::Utc::now().format("%Y-%m"));
-    
-    let doc = json!({
-        "timestamp": event.timestamp,
-        "level": event.level,
-        "message": event.message,
-        "service": event.service,
-        "trace_id": event.trace_id,
-        "@timestamp": chrono::Utc::now().to_rfc3339()
-    });
-
-    let _ = client.client
-        .index(opensearch::IndexParts::IndexId(&index_name, &uuid::Uuid::new_v4().to_string()))
-        .body(doc)
-        .send()
-        .await;
+// Only term, range and match clauses built from typed values, so nothing a caller sends
+// is parsed as query syntax
+fn filter_query(filter: &LogFilter, start: Option<DateTime<Utc>>, end: Option<DateTime<Utc>>) -> serde_json::Value {
+    let mut clauses = Vec::new();
+    if start.is_some() || end.is_some() {
+        let mut range = serde_json::Map::new();
+        if let Some(start) = start {
+            range.insert("gte".to_string(), json!(start.to_rfc3339()));
+        }
+        if let Some(end) = end {
+            range.insert("lt".to_string(), json!(end.to_rfc3339()));
+        }
+        clauses.push(json!({ "range": { "event_time": range } }));
+    }
+    if !filter.services.is_empty() {
+        clauses.push(json!({ "terms": { "service": filter.services } }));
+    }
+    if !filter.levels.is_empty() {
+        clauses.push(json!({ "terms": { "level": filter.levels } }));
+    }
+    if let Some(trace_id) = &filter.trace_id {
+        clauses.push(json!({ "term": { "trace_id": trace_id } }));
+    }
+    for (name, value) in &filter.fields {
+        clauses.push(json!({ "term": { format!("fields.{}", name): value } }));
+    }
+    let must: Vec<serde_json::Value> = filter.text.iter()
+        .map(|text| json!({ "match": { "message": { "query": text, "operator": "and" } } }))
+        .collect();
+    json!({ "bool": { "filter": clauses, "must": must } })
 }
 
-pub async fn search_logs(query: &str, from: usize, size: usize) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
-    let client = get_client();
-    let index_pattern = "logs-*";
-    
-    let search_body = json!({
-        "query": {
-            "query_string": {
-                "query": query
-            }
-        },
-        "from": from,
-        "size": size,
-        "sort": [{"@timestamp": {"order": "desc"}}]
-    });
-
-    let response = client.client
-        .search(opensearch::SearchParts::Index(&[index_pattern]))
-        .body(search_body)
-        .send()
-        .await?;
-
-    Ok(response.json().await?)
+async fn check_response(response: opensearch::http::response::Response, action: &str) -> Result<serde_json::Value, String> {
+    let status = response.status_code();
+    let body: serde_json::Value = response.json().await.unwrap_or_default();
+    if status.is_success() {
+        return Ok(body);
+    }
+    let reason = body.pointer("/error/reason").and_then(|r| r.as_str()).unwrap_or("unknown error");
+    Err(format!("{} returned {}: {}", action, status, reason))
 }
@@ -0,0 +1,712 @@
+use chrono::Utc;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::VecDeque;
+use crate::kafka::LogEvent;
+use crate::ml::{empty_window_event, settings, Anomaly, Detector};
+use crate::windows::{EventWindow, TumblingWindows};
+
+// Robust Random Cut Forest (Guha et al., 2016) over a stream of points. Each tree holds a
+// sample of recent points; a point's anomaly score is its codisplacement, roughly how many
+// sampled points would move closer to the root if it were removed.
+pub struct RandomCutForest {
+    trees: Vec<Tree>,
+    sample_size: usize,
+    // Points seen after a tree is full are sampled at this rate, so samples reach about
+    // `sample_size / replace_rate` points back
+    replace_rate: f64,
+    rng: StdRng,
+}
+
+#[derive(Default)]
+struct Tree {
+    nodes: Vec<Node>,
+    // Reusable slots in `nodes`
+    free: Vec<usize>,
+    root: Option<usize>,
+    // The sampled points, each also in a leaf
+    sample: Vec<Vec<f64>>,
+}
+
+struct Node {
+    parent: Option<usize>,
+    // Points below this node, counting duplicates
+    mass: usize,
+    kind: NodeKind,
+}
+
+enum NodeKind {
+    Leaf { point: Vec<f64> },
+    // Points with `point[dim] <= value` are on the left. `min` and `max` bound everything below.
+    Cut { dim: usize, value: f64, left: usize, right: usize, min: Vec<f64>, max: Vec<f64> },
+}
+
+impl RandomCutForest {
+    pub fn new(trees: usize, sample_size: usize, replace_rate: f64) -> Self {
+        Self {
+            trees: (0..trees).map(|_| Tree::default()).collect(),
+            sample_size,
+            replace_rate,
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    // Scores the point against the current samples, then lets each tree decide whether to keep it
+    pub fn update(&mut self, point: Vec<f64>) -> f64 {
+        let mut total = 0.0;
+        for tree in self.trees.iter_mut() {
+            let leaf = tree.insert(&point, &mut self.rng);
+            total += tree.codisplacement(leaf);
+
+            let keep = tree.sample.len() < self.sample_size || self.rng.gen_bool(self.replace_rate);
+            if !keep {
+                tree.delete(&point);
+                continue;
+            }
+            if tree.sample.len() >= self.sample_size {
+                let evicted = tree.sample.swap_remove(self.rng.gen_range(0..tree.sample.len()));
+                tree.delete(&evicted);
+            }
+            tree.sample.push(point.clone());
+        }
+        total / self.trees.len().max(1) as f64
+    }
+
+    // Points held by the first tree; every tree holds the same number
+    pub fn sampled(&self) -> usize {
+        self.trees.first().map_or(0, |tree| tree.sample.len())
+    }
+}
+
+impl Tree {
+    fn alloc(&mut self, node: Node) -> usize {
+        match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn bounds(&self, index: usize) -> (&[f64], &[f64]) {
+        match &self.nodes[index].kind {
+            NodeKind::Leaf { point } => (point, point),
+            NodeKind::Cut { min, max, .. } => (min, max),
+        }
+    }
+
+    // Points a child at `child` instead of `old`, or makes it the root
+    fn replace_child(&mut self, parent: Option<usize>, old: usize, child: usize) {
+        self.nodes[child].parent = parent;
+        match parent {
+            None => self.root = Some(child),
+            Some(parent) => {
+                if let NodeKind::Cut { left, right, .. } = &mut self.nodes[parent].kind {
+                    if *left == old { *left = child } else { *right = child }
+                }
+            }
+        }
+    }
+
+    // Adds the point and returns its leaf
+    fn insert(&mut self, point: &[f64], rng: &mut StdRng) -> usize {
+        let Some(mut index) = self.root else {
+            let leaf = self.alloc(Node { parent: None, mass: 1, kind: NodeKind::Leaf { point: point.to_vec() } });
+            self.root = Some(leaf);
+            return leaf;
+        };
+
+        loop {
+            let (min, max) = self.bounds(index);
+            let new_min: Vec<f64> = min.iter().zip(point).map(|(a, b)| a.min(*b)).collect();
+            let new_max: Vec<f64> = max.iter().zip(point).map(|(a, b)| a.max(*b)).collect();
+            let span: f64 = new_min.iter().zip(&new_max).map(|(a, b)| b - a).sum();
+
+            // A random cut across the box grown to include the point, with each dimension
+            // chosen in proportion to its extent. If it falls outside the node's own box, it
+            // separates the point from everything below.
+            let separated = if span > 0.0 {
+                let mut offset = rng.gen_range(0.0..span);
+                let extents = new_min.iter().zip(&new_max).map(|(a, b)| b - a);
+                let mut dim = 0;
+                for (d, extent) in extents.enumerate().filter(|(_, extent)| *extent > 0.0) {
+                    dim = d;
+                    if offset < extent { break; }
+                    offset -= extent;
+                }
+                // Rounding must not push the cut onto the upper edge
+                let value = (new_min[dim] + offset).min(new_max[dim]);
+                let value = if value < new_max[dim] { value } else { new_min[dim] };
+                (value < min[dim] || value >= max[dim]).then_some((dim, value, value < min[dim]))
+            } else {
+                None
+            };
+
+            if let Some((dim, value, point_left)) = separated {
+                let parent = self.nodes[index].parent;
+                let mass = self.nodes[index].mass + 1;
+                let leaf = self.alloc(Node { parent: None, mass: 1, kind: NodeKind::Leaf { point: point.to_vec() } });
+                let (left, right) = if point_left { (leaf, index) } else { (index, leaf) };
+                let cut = self.alloc(Node {
+                    parent,
+                    mass,
+                    kind: NodeKind::Cut { dim, value, left, right, min: new_min, max: new_max },
+                });
+                self.replace_child(parent, index, cut);
+                self.nodes[index].parent = Some(cut);
+                self.nodes[leaf].parent = Some(cut);
+                return leaf;
+            }
+
+            // The point belongs below this node, which grows to cover it
+            let node = &mut self.nodes[index];
+            node.mass += 1;
+            match &mut node.kind {
+                // Only an identical point can end up here
+                NodeKind::Leaf { .. } => return index,
+                NodeKind::Cut { dim, value, left, right, min, max } => {
+                    *min = new_min;
+                    *max = new_max;
+                    index = if point[*dim] <= *value { *left } else { *right };
+                }
+            }
+        }
+    }
+
+    fn find(&self, point: &[f64]) -> Option<usize> {
+        let mut index = self.root?;
+        loop {
+            match &self.nodes[index].kind {
+                NodeKind::Leaf { point: leaf } => return (leaf.as_slice() == point).then_some(index),
+                NodeKind::Cut { dim, value, left, right, .. } => {
+                    index = if point[*dim] <= *value { *left } else { *right };
+                }
+            }
+        }
+    }
+
+    fn delete(&mut self, point: &[f64]) {
+        let Some(leaf) = self.find(point) else { return };
+        self.nodes[leaf].mass -= 1;
+        if self.nodes[leaf].mass > 0 {
+            // A duplicate remains, so no box changes
+            let mut parent = self.nodes[leaf].parent;
+            while let Some(index) = parent {
+                self.nodes[index].mass -= 1;
+                parent = self.nodes[index].parent;
+            }
+            return;
+        }
+
+        self.free.push(leaf);
+        let Some(cut) = self.nodes[leaf].parent else {
+            self.root = None;
+            return;
+        };
+        let sibling = match &self.nodes[cut].kind {
+            NodeKind::Cut { left, right, .. } => if *left == leaf { *right } else { *left },
+            NodeKind::Leaf { .. } => unreachable!("leaves have no children"),
+        };
+        let grandparent = self.nodes[cut].parent;
+        self.replace_child(grandparent, cut, sibling);
+        self.free.push(cut);
+
+        // Shrink the boxes above to what is left
+        let mut parent = grandparent;
+        while let Some(index) = parent {
+            let (left, right) = match &self.nodes[index].kind {
+                NodeKind::Cut { left, right, .. } => (*left, *right),
+                NodeKind::Leaf { .. } => unreachable!("leaves have no children"),
+            };
+            let (left_min, left_max) = self.bounds(left);
+            let (right_min, right_max) = self.bounds(right);
+            let new_min: Vec<f64> = left_min.iter().zip(right_min).map(|(a, b)| a.min(*b)).collect();
+            let new_max: Vec<f64> = left_max.iter().zip(right_max).map(|(a, b)| a.max(*b)).collect();
+            let node = &mut self.nodes[index];
+            node.mass -= 1;
+            if let NodeKind::Cut { min, max, .. } = &mut node.kind {
+                *min = new_min;
+                *max = new_max;
+            }
+            parent = node.parent;
+        }
+    }
+
+    // The largest ratio, over the leaf's ancestors, of the points a subtree on the leaf's
+    // path would displace to the points in it
+    fn codisplacement(&self, leaf: usize) -> f64 {
+        let mut index = leaf;
+        let mut result: f64 = 0.0;
+        while let Some(parent) = self.nodes[index].parent {
+            let sibling = match &self.nodes[parent].kind {
+                NodeKind::Cut { left, right, .. } => if *left == index { *right } else { *left },
+                NodeKind::Leaf { .. } => unreachable!("leaves have no children"),
+            };
+            result = result.max(self.nodes[sibling].mass as f64 / self.nodes[index].mass as f64);
+            index = parent;
+        }
+        result
+    }
+}
+
+// Follows each service's log volume and error rate in fixed event time windows, each taken as
+// its deviation from the usual value at that time of day. A point is the last `shingle_size`
+// deviations plus their average over the last `trend_windows`, so the forest sees both a few
+// minutes of shape, for spikes and abrupt shifts, and the last hour's level, for slow drift.
+// The daily baselines learn over days, so they absorb neither the daily rhythm nor a drift
+// within a day. Nothing is scored for the first two days, while they fill in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RandomCutForestConfig {
+    // Seconds per window
+    pub window_secs: i64,
+    // Consecutive windows per point
+    pub shingle_size: usize,
+    pub trees: usize,
+    // Points kept by each tree
+    pub sample_size: usize,
+    // Windows that a tree's sample spans on average once full; a day of minutes by default
+    pub sample_horizon: usize,
+    // Windows averaged into the long-horizon part of each point; an hour by default
+    pub trend_windows: usize,
+    // Parts of the day with their own baseline; hours by default
+    pub daily_slots: usize,
+    // Days that each baseline roughly averages over
+    pub baseline_days: u32,
+    // Windows scored before anything is flagged
+    pub warmup_windows: usize,
+    // Scores this many standard deviations above the recent mean are anomalies
+    pub threshold: f64,
+}
+
+impl Default for RandomCutForestConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 60,
+            shingle_size: 4,
+            trees: 30,
+            sample_size: 128,
+            sample_horizon: 1440,
+            trend_windows: 60,
+            daily_slots: 24,
+            baseline_days: 7,
+            warmup_windows: 60,
+            threshold: 3.0,
+        }
+    }
+}
+
+// Smoothing for the running score
+const DECAY: f64 = 0.01;
+
+const SECS_PER_DAY: i64 = 86_400;
+
+// Exponentially weighted mean and variance; a plain average until there are 1 / DECAY values
+#[derive(Default, Serialize)]
+struct Running {
+    mean: f64,
+    variance: f64,
+    #[serde(skip)]
+    count: u64,
+}
+
+impl Running {
+    fn update(&mut self, value: f64) {
+        self.count += 1;
+        let weight = (1.0 / self.count as f64).max(DECAY);
+        let delta = value - self.mean;
+        self.mean += weight * delta;
+        self.variance = (1.0 - weight) * (self.variance + weight * delta * delta);
+    }
+
+    fn stddev(&self) -> f64 {
+        self.variance.sqrt()
+    }
+}
+
+// One part of the day in a series' daily profile
+#[derive(Default, Serialize)]
+struct Slot {
+    // Usual value
+    level: f64,
+    // Usual squared difference from the interpolated level
+    spread: f64,
+    // Days averaged into each
+    level_days: u32,
+    spread_days: u32,
+    // The day in progress
+    #[serde(skip)]
+    sum: f64,
+    #[serde(skip)]
+    squares: f64,
+    #[serde(skip)]
+    windows: u32,
+}
+
+// A series' usual value through the day. Each slot learns once a day, from its average that
+// day, so whatever happens within a day is measured against the days before it.
+#[derive(Serialize)]
+struct Daily {
+    slots: Vec<Slot>,
+    #[serde(skip)]
+    current: Option<usize>,
+    #[serde(skip)]
+    days: u32,
+}
+
+impl Daily {
+    fn new(slots: usize, days: u32) -> Self {
+        Self { slots: (0..slots).map(|_| Slot::default()).collect(), current: None, days }
+    }
+
+    // Interpolated between the levels of the slots on either side, so it follows the rhythm
+    // within a slot instead of stepping at its edges. None until both have a day behind them.
+    fn usual(&self, secs: i64) -> Option<f64> {
+        let count = self.slots.len();
+        let position = secs as f64 * count as f64 / SECS_PER_DAY as f64 - 0.5;
+        let after_weight = position - position.floor();
+        let before = (position.floor() as i64).rem_euclid(count as i64) as usize;
+        let (before, after) = (&self.slots[before], &self.slots[(before + 1) % count]);
+        (before.level_days > 0 && after.level_days > 0)
+            .then_some(before.level * (1.0 - after_weight) + after.level * after_weight)
+    }
+
+    // Folds the day's windows into the slot: a plain average over the first `days` days,
+    // then exponentially weighted
+    fn learn(&mut self, slot: usize) {
+        let days = self.days;
+        let slot = &mut self.slots[slot];
+        let windows = std::mem::take(&mut slot.windows) as f64;
+        let (sum, squares) = (std::mem::take(&mut slot.sum), std::mem::take(&mut slot.squares));
+        if windows == 0.0 { return; }
+        slot.level_days += 1;
+        slot.level += (sum / windows - slot.level) / slot.level_days.min(days) as f64;
+        // NaN when the day had no level to measure against
+        if squares.is_finite() {
+            slot.spread_days += 1;
+            slot.spread += (squares / windows - slot.spread) / slot.spread_days.min(days) as f64;
+        }
+    }
+
+    // How many standard deviations the value at `secs` into the day is from the usual; None
+    // while the profile is still filling in
+    fn deviation(&mut self, secs: i64, value: f64) -> Option<f64> {
+        let slot = secs as usize * self.slots.len() / SECS_PER_DAY as usize;
+        if let Some(previous) = self.current.filter(|&previous| previous != slot) {
+            self.learn(previous);
+        }
+        self.current = Some(slot);
+
+        let usual = self.usual(secs);
+        let slot = &mut self.slots[slot];
+        let difference = usual.map_or(f64::NAN, |usual| value - usual);
+        let deviation = (slot.spread_days > 0 && usual.is_some()).then(|| difference / slot.spread.sqrt().max(1e-3));
+        slot.sum += value;
+        slot.squares += difference * difference;
+        slot.windows += 1;
+        deviation
+    }
+}
+
+#[derive(Default)]
+struct WindowCounts {
+    events: usize,
+    errors: usize,
+    // Reported with anomalies: the latest error, or the latest event if there were none
+    sample: Option<LogEvent>,
+}
+
+pub struct RandomCutForestDetector {
+    config: RandomCutForestConfig,
+    service: String,
+    forest: RandomCutForest,
+    windows: TumblingWindows<WindowCounts>,
+    volume: Daily,
+    error_rate: Daily,
+    score: Running,
+    // Standardized [volume, error rate] deviations of the latest windows
+    recent: VecDeque<[f64; 2]>,
+    windows_seen: usize,
+    // An unusual window stays in the shingle for a while; it is reported once
+    quiet_until: usize,
+    last_score: Option<f64>,
+}
+
+impl RandomCutForestDetector {
+    pub fn build(value: serde_yaml::Value) -> Result<Box<dyn Detector>, Vec<String>> {
+        let config: RandomCutForestConfig = settings(value)?;
+        let mut errors = Vec::new();
+        if config.window_secs <= 0 || config.shingle_size == 0 || config.trees == 0 {
+            errors.push("window_secs, shingle_size and trees must be greater than 0".to_string());
+        }
+        if config.sample_size < 2 || config.sample_horizon < config.sample_size {
+            errors.push("sample_size must be at least 2 and at most sample_horizon".to_string());
+        }
+        if config.threshold <= 0.0 {
+            errors.push("threshold must be greater than 0".to_string());
+        }
+        if config.trend_windows < config.shingle_size {
+            errors.push("trend_windows must be at least shingle_size".to_string());
+        }
+        if config.daily_slots == 0 || config.baseline_days == 0 {
+            errors.push("daily_slots and baseline_days must be greater than 0".to_string());
+        } else if config.window_secs > SECS_PER_DAY / config.daily_slots as i64 {
+            errors.push("window_secs must fit in one of the daily_slots".to_string());
+        }
+        if !errors.is_empty() { return Err(errors); }
+        Ok(Box::new(Self::new(config)))
+    }
+
+    fn new(config: RandomCutForestConfig) -> Self {
+        let replace_rate = config.sample_size as f64 / config.sample_horizon as f64;
+        Self {
+            forest: RandomCutForest::new(config.trees, config.sample_size, replace_rate),
+            windows: TumblingWindows::new(config.window_secs),
+            volume: Daily::new(config.daily_slots, config.baseline_days),
+            error_rate: Daily::new(config.daily_slots, config.baseline_days),
+            config,
+            service: String::new(),
+            score: Running::default(),
+            recent: VecDeque::new(),
+            windows_seen: 0,
+            quiet_until: 0,
+            last_score: None,
+        }
+    }
+
+    // Feeds a closed window to the forest; Some if it is an anomaly
+    fn close(&mut self, start: i64, counts: WindowCounts) -> Option<Anomaly> {
+        let volume = counts.events as f64;
+        let error_rate = counts.errors as f64 / counts.events.max(1) as f64;
+        let secs = start.rem_euclid(SECS_PER_DAY);
+        let usual_volume = self.volume.usual(secs).unwrap_or_default();
+        let usual_error_rate = self.error_rate.usual(secs).unwrap_or_default();
+        let volume_deviation = self.volume.deviation(secs, volume);
+        let error_rate_deviation = self.error_rate.deviation(secs, error_rate);
+        let (Some(volume_deviation), Some(error_rate_deviation)) = (volume_deviation, error_rate_deviation) else {
+            return None;
+        };
+        self.windows_seen += 1;
+
+        self.recent.push_back([volume_deviation, error_rate_deviation]);
+        if self.recent.len() > self.config.trend_windows {
+            self.recent.pop_front();
+        }
+        if self.recent.len() < self.config.shingle_size { return None; }
+
+        let shingle = self.recent.range(self.recent.len() - self.config.shingle_size..);
+        let mut point: Vec<f64> = shingle.flatten().copied().collect();
+        // Scaled up from an average so a sustained shift weighs as much as a spike of its size
+        // times the square root of its length, which is how far it stands out from noise
+        for series in 0..2 {
+            let sum: f64 = self.recent.iter().map(|values| values[series]).sum();
+            point.push(sum / (self.recent.len() as f64).sqrt());
+        }
+        let score = self.forest.update(point);
+        self.last_score = Some(score);
+
+        // Judge against scores so far before this one joins them
+        let (mean, stddev) = (self.score.mean, self.score.stddev());
+        self.score.update(score);
+        if self.windows_seen < self.config.warmup_windows.max(self.quiet_until)
+            || score <= mean + self.config.threshold * stddev
+        {
+            return None;
+        }
+        self.quiet_until = self.windows_seen + self.config.shingle_size;
+
+        Some(Anomaly {
+            timestamp: Utc::now().to_rfc3339(),
+            // Near 1 for scores far above the usual ones
+            score: score / (score + mean),
+            event: counts.sample.unwrap_or_else(|| empty_window_event(&self.service, start, self.config.window_secs)),
+            reason: format!(
+                "Unusual log volume or error rate: {} logs at {:.1}% errors in {}s (usual {:.0} at {:.1}% at this time of day), codisplacement {:.1} vs {:.1} typical",
+                counts.events, error_rate * 100.0, self.config.window_secs,
+                usual_volume, usual_error_rate * 100.0, score, mean,
+            ),
+            algorithm: "RandomCutForest".to_string(),
+        })
+    }
+}
+
+impl Detector for RandomCutForestDetector {
+    fn observe(&mut self, event: &LogEvent) {
+        if self.service.is_empty() {
+            self.service = event.service.clone();
+        }
+        // Late events are turned away before they get here
+        let Some(counts) = self.windows.get(event.event_timestamp()) else { return };
+        counts.events += 1;
+        if event.level == "ERROR" {
+            counts.errors += 1;
+        }
+        if event.level == "ERROR" || counts.sample.as_ref().is_none_or(|s| s.level != "ERROR") {
+            counts.sample = Some(event.clone());
+        }
+    }
+
+    fn evaluate(&mut self, events: &EventWindow) -> Vec<Anomaly> {
+        // After a long gap, the samples only need the windows they would still hold
+        let closed = self.windows.close(events.watermark, self.config.sample_horizon);
+        closed.into_iter().filter_map(|(start, counts)| self.close(start, counts)).collect()
+    }
+
+    fn state(&self) -> serde_json::Value {
+        json!({
+            "config": self.config,
+            "windows_seen": self.windows_seen,
+            "sampled_points": self.forest.sampled(),
+            "open_windows": self.windows.open_windows(),
+            "volume": self.volume,
+            "error_rate": self.error_rate,
+            "score": self.score,
+            "last_score": self.last_score,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks the subtree at `index` and returns its mass
+    fn check(tree: &Tree, index: usize, parent: Option<usize>) -> usize {
+        let node = &tree.nodes[index];
+        assert_eq!(node.parent, parent, "parent of node {index}");
+        match &node.kind {
+            NodeKind::Leaf { .. } => {
+                assert!(node.mass > 0, "empty leaf {index}");
+                node.mass
+            }
+            NodeKind::Cut { dim, value, left, right, min, max } => {
+                let mass = check(tree, *left, Some(index)) + check(tree, *right, Some(index));
+                assert_eq!(node.mass, mass, "mass of node {index}");
+
+                // The box is exactly the union of the children's, and the cut falls inside it
+                let (left_min, left_max) = tree.bounds(*left);
+                let (right_min, right_max) = tree.bounds(*right);
+                for d in 0..min.len() {
+                    assert_eq!(min[d], left_min[d].min(right_min[d]), "min of node {index}");
+                    assert_eq!(max[d], left_max[d].max(right_max[d]), "max of node {index}");
+                }
+                assert!(left_max[*dim] <= *value && right_min[*dim] > *value, "cut of node {index}");
+                mass
+            }
+        }
+    }
+
+    fn check_tree(tree: &Tree, points: usize) {
+        match tree.root {
+            None => assert_eq!(points, 0),
+            Some(root) => assert_eq!(check(tree, root, None), points),
+        }
+    }
+
+    #[test]
+    fn masses_and_boxes_stay_consistent_through_inserts_and_deletes() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut tree = Tree::default();
+        // Coarse values, so some points are duplicates
+        let points: Vec<Vec<f64>> = (0..300)
+            .map(|_| (0..3).map(|_| rng.gen_range(0..20) as f64).collect())
+            .collect();
+        for (n, point) in points.iter().enumerate() {
+            tree.insert(point, &mut rng);
+            check_tree(&tree, n + 1);
+        }
+
+        let mut remaining = points.clone();
+        while !remaining.is_empty() {
+            let point = remaining.swap_remove(rng.gen_range(0..remaining.len()));
+            tree.delete(&point);
+            check_tree(&tree, remaining.len());
+            for point in &remaining {
+                assert!(tree.find(point).is_some(), "lost {point:?}");
+            }
+        }
+        assert_eq!(tree.root, None);
+    }
+
+    #[test]
+    fn deleting_a_missing_point_changes_nothing() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let mut tree = Tree::default();
+        tree.insert(&[1.0, 2.0], &mut rng);
+        tree.insert(&[3.0, 4.0], &mut rng);
+        tree.delete(&[5.0, 6.0]);
+        check_tree(&tree, 2);
+    }
+
+    #[test]
+    fn an_isolated_spike_has_a_high_codisplacement() {
+        let mut forest = RandomCutForest::new(30, 64, 0.1);
+        forest.rng = StdRng::seed_from_u64(3);
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut normal = 0.0;
+        for n in 0..500 {
+            let score = forest.update(vec![100.0 + rng.gen_range(-5.0..5.0), 0.02 + rng.gen_range(-0.01..0.01)]);
+            if n >= 400 { normal += score / 100.0; }
+        }
+        let spike = forest.update(vec![400.0, 0.5]);
+        // Cut off from the whole sample near the root, so it displaces nearly all of it
+        assert!(spike > 32.0, "spike scored {spike}");
+        assert!(spike > 5.0 * normal, "spike scored {spike} against {normal}");
+    }
+
+    const WINDOWS_PER_DAY: i64 = 1440;
+
+    // Runs minute windows through a detector, `volume(minute)` logs each give or take ten at
+    // one to three percent errors, and returns the minutes flagged
+    fn flagged(minutes: i64, volume: impl Fn(i64) -> f64) -> Vec<i64> {
+        let mut detector = RandomCutForestDetector::new(RandomCutForestConfig::default());
+        detector.forest.rng = StdRng::seed_from_u64(11);
+        let mut rng = StdRng::seed_from_u64(12);
+        let mut flagged = Vec::new();
+        for minute in 0..minutes {
+            let events = (volume(minute) + rng.gen_range(-10.0..10.0)).round() as usize;
+            let errors = (events as f64 * rng.gen_range(0.01..0.03)).round() as usize;
+            let counts = WindowCounts { events, errors, sample: None };
+            if detector.close(minute * 60, counts).is_some() {
+                flagged.push(minute);
+            }
+        }
+        flagged
+    }
+
+    // Busy by day and quiet at night
+    fn daily(minute: i64) -> f64 {
+        let phase = (minute % WINDOWS_PER_DAY) as f64 / WINDOWS_PER_DAY as f64;
+        200.0 + 150.0 * (phase * std::f64::consts::TAU).sin()
+    }
+
+    #[test]
+    fn a_repeating_daily_pattern_is_not_flagged() {
+        // Two days fill the profile, then two are scored. Noise alone crosses the threshold now
+        // and then; a rhythm taken for anomalies would add hundreds of windows.
+        let flat = flagged(4 * WINDOWS_PER_DAY, |_| 200.0);
+        let rhythm = flagged(4 * WINDOWS_PER_DAY, daily);
+        assert!(!flat.is_empty());
+        assert!(rhythm.len() <= flat.len() * 3 / 2, "flagged {rhythm:?} against {flat:?} without the rhythm");
+    }
+
+    #[test]
+    fn a_slow_ramp_is_flagged() {
+        // Six hours into scoring, volume starts climbing by half a log a minute, far too slowly
+        // to show within a shingle
+        let start = 2 * WINDOWS_PER_DAY + 360;
+        let end = start + 180;
+        let ramp = |minute: i64| daily(minute) + (minute - start).max(0) as f64 / 2.0;
+        let during = |flagged: Vec<i64>| flagged.into_iter().filter(|minute| (start..end).contains(minute)).collect::<Vec<_>>();
+        let usual = during(flagged(end, daily));
+        let ramped = during(flagged(end, ramp));
+        assert!(ramped.first().is_some_and(|minute| minute - start < 60), "flagged {ramped:?}");
+        assert!(ramped.len() > usual.len(), "flagged {ramped:?} against {usual:?} without the ramp");
+    }
+}
@@ -0,0 +1,231 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Mutex, LazyLock};
+use crate::kafka::LogEvent;
+use crate::ml::Anomaly;
+
+// Report interval assumed for agents that never told us theirs
+const DEFAULT_REPORT_INTERVAL: u64 = 5;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AgentState {
+    Healthy,
+    Stale,
+    Offline,
+}
+
+// Reported by the agent at registration
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct HostInfo {
+    pub hostname: Option<String>,
+    pub os: Option<String>,
+    pub os_version: Option<String>,
+    pub kernel_version: Option<String>,
+    pub agent_version: Option<String>,
+}
+
+// One running agent instance, keyed by its persisted instance ID.
+// `name` is the service it monitors and is shared by replicas.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Agent {
+    pub id: String,
+    pub name: String,
+    pub host: HostInfo,
+    pub labels: HashMap<String, String>,
+    pub registered_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub capabilities: HashMap<String, bool>,
+    // Seconds between reports, as configured on the agent
+    pub report_interval: u64,
+    pub state: AgentState,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ServiceSummary {
+    pub service: String,
+    pub instances: usize,
+    pub healthy: usize,
+    pub stale: usize,
+    pub offline: usize,
+    pub agent_ids: Vec<String>,
+}
+
+pub struct Registration {
+    pub id: String,
+    pub name: String,
+    pub capabilities: HashMap<String, bool>,
+    pub report_interval: Option<u64>,
+    pub host: HostInfo,
+    pub labels: HashMap<String, String>,
+}
+
+// An agent is stale after `stale_after` missed report intervals and offline after `offline_after`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LivenessConfig {
+    pub stale_after: f64,
+    pub offline_after: f64,
+    pub check_interval_secs: u64,
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        Self {
+            stale_after: 3.0,
+            offline_after: 10.0,
+            check_interval_secs: 1,
+        }
+    }
+}
+
+static AGENTS: LazyLock<Mutex<HashMap<String, Agent>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Registers an instance, or refreshes its details when the same instance
+// registers again (e.g. after an agent restart). Returns true for a re-registration.
+pub fn register(registration: Registration) -> bool {
+    let now = Utc::now();
+    let mut agents = AGENTS.lock().unwrap();
+    let previous = agents.get(&registration.id);
+    let reregistered = previous.is_some();
+    let registered_at = previous.map(|agent| agent.registered_at).unwrap_or(now);
+
+    if let Some(previous) = previous {
+        if previous.name != registration.name {
+            println!("Agent {} moved from service {} to {}", registration.id, previous.name, registration.name);
+        }
+    }
+
+    agents.insert(registration.id.clone(), Agent {
+        id: registration.id,
+        name: registration.name,
+        host: registration.host,
+        labels: registration.labels,
+        registered_at,
+        last_seen: now,
+        capabilities: registration.capabilities,
+        report_interval: registration.report_interval.unwrap_or(DEFAULT_REPORT_INTERVAL).max(1),
+        state: AgentState::Healthy,
+    });
+    reregistered
+}
+
+// Records that an agent just reported. Agents we have no record of (e.g. after a
+// backend restart) are re-created with default settings so they stay visible.
+pub fn touch(id: &str, name: &str) {
+    let now = Utc::now();
+    let mut agents = AGENTS.lock().unwrap();
+    match agents.get_mut(id) {
+        Some(agent) => {
+            if agent.state != AgentState::Healthy {
+                println!("Agent {} ({}) is reporting again", agent.name, id);
+            }
+            agent.last_seen = now;
+            agent.state = AgentState::Healthy;
+        }
+        None => {
+            agents.insert(id.to_string(), Agent {
+                id: id.to_string(),
+                name: name.to_string(),
+                host: HostInfo::default(),
+                labels: HashMap::new(),
+                registered_at: now,
+                last_seen: now,
+                capabilities: HashMap::new(),
+                report_interval: DEFAULT_REPORT_INTERVAL,
+                state: AgentState::Healthy,
+            });
+        }
+    }
+}
+
+// Forgets a decommissioned instance. It reappears if it reports again.
+pub fn remove(id: &str) -> Option<Agent> {
+    AGENTS.lock().unwrap().remove(id)
+}
+
+pub fn list() -> Vec<Agent> {
+    AGENTS.lock().unwrap().values().cloned().collect()
+}
+
+// Instances grouped by the service name they report under
+pub fn services() -> Vec<ServiceSummary> {
+    let agents = AGENTS.lock().unwrap();
+    let mut by_service: HashMap<&str, ServiceSummary> = HashMap::new();
+    for agent in agents.values() {
+        let summary = by_service.entry(&agent.name).or_insert_with(|| ServiceSummary {
+            service: agent.name.clone(),
+            instances: 0,
+            healthy: 0,
+            stale: 0,
+            offline: 0,
+            agent_ids: Vec::new(),
+        });
+        summary.instances += 1;
+        match agent.state {
+            AgentState::Healthy => summary.healthy += 1,
+            AgentState::Stale => summary.stale += 1,
+            AgentState::Offline => summary.offline += 1,
+        }
+        summary.agent_ids.push(agent.id.clone());
+    }
+
+    let mut services: Vec<ServiceSummary> = by_service.into_values().collect();
+    services.sort_by(|a, b| a.service.cmp(&b.service));
+    services
+}
+
+pub async fn start_liveness_monitor(config: LivenessConfig) {
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(config.check_interval_secs.max(1)));
+
+    loop {
+        interval.tick().await;
+        check_liveness(&config);
+    }
+}
+
+fn check_liveness(config: &LivenessConfig) {
+    let now = Utc::now();
+    let mut went_offline = Vec::new();
+
+    {
+        let mut agents = AGENTS.lock().unwrap();
+        for agent in agents.values_mut() {
+            let silent_secs = (now - agent.last_seen).num_milliseconds() as f64 / 1000.0;
+            let missed_intervals = silent_secs / agent.report_interval as f64;
+
+            let state = if missed_intervals >= config.offline_after {
+                AgentState::Offline
+            } else if missed_intervals >= config.stale_after {
+                AgentState::Stale
+            } else {
+                AgentState::Healthy
+            };
+
+            if state == AgentState::Offline && agent.state != AgentState::Offline {
+                went_offline.push((agent.id.clone(), agent.name.clone(), silent_secs));
+            }
+            agent.state = state;
+        }
+    }
+
+    for (id, name, silent_secs) in went_offline {
+        println!("Agent {} ({}) went offline", name, id);
+        crate::ml::record_anomaly(Anomaly {
+            timestamp: now.to_rfc3339(),
+            score: 1.0,
+            event: LogEvent {
+                event_time: now.to_rfc3339(),
+                ingest_time: now.to_rfc3339(),
+                level: "ERROR".to_string(),
+                message: format!("Agent {} ({}) went offline (no report for {:.0}s)", name, id, silent_secs),
+                service: name.clone(),
+                trace_id: None,
+                fields: HashMap::from([("agent_id".to_string(), serde_json::Value::from(id.clone()))]),
+            },
+            reason: format!("No report from agent {} ({}) for {:.0}s", name, id, silent_secs),
+            algorithm: "AgentLiveness".to_string(),
+        });
+    }
+}
@@ -0,0 +1,209 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, LazyLock};
+use crate::kafka::LogEvent;
+
+// Structured fields that carry a request duration in milliseconds, in order of preference
+const RESPONSE_TIME_FIELDS: &[&str] = &["response_time_ms", "response_time", "duration_ms", "latency_ms", "elapsed_ms"];
+
+// Log counters are kept per service in fixed-width event-time buckets. Durations are in seconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsConfig {
+    pub bucket_width: u64,
+    pub retention: u64,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            bucket_width: 60,
+            retention: 7 * 86400,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Bucket {
+    total: u64,
+    errors: u64,
+    warnings: u64,
+    response_time_sum: f64,
+    response_time_count: u64,
+}
+
+impl Bucket {
+    fn add(&mut self, other: &Bucket) {
+        self.total += other.total;
+        self.errors += other.errors;
+        self.warnings += other.warnings;
+        self.response_time_sum += other.response_time_sum;
+        self.response_time_count += other.response_time_count;
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ServiceMetrics {
+    pub service: String,
+    pub total_logs: u64,
+    pub error_count: u64,
+    pub warning_count: u64,
+    pub error_rate: f64,
+    pub logs_per_minute: f64,
+    // Only present when the service logs a response time field
+    pub avg_response_time: Option<f64>,
+    pub anomalies_detected: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MetricsSummary {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub total_logs: u64,
+    pub error_count: u64,
+    pub warning_count: u64,
+    pub error_rate: f64,
+    pub avg_response_time: Option<f64>,
+    pub active_services: usize,
+    pub anomalies_detected: usize,
+    pub services: Vec<ServiceMetrics>,
+}
+
+// Bucket start -> counters
+type ServiceBuckets = BTreeMap<DateTime<Utc>, Bucket>;
+
+static CONFIG: LazyLock<Mutex<StatsConfig>> = LazyLock::new(|| Mutex::new(StatsConfig::default()));
+static BUCKETS: LazyLock<Mutex<HashMap<String, ServiceBuckets>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub fn record(event: &LogEvent) {
+    let width = Duration::seconds(CONFIG.lock().unwrap().bucket_width as i64);
+    let start = bucket_start(event.event_timestamp(), width);
+
+    let mut buckets = BUCKETS.lock().unwrap();
+    let bucket = buckets.entry(event.service.clone()).or_default().entry(start).or_default();
+    bucket.total += 1;
+    if is_error(&event.level) {
+        bucket.errors += 1;
+    } else if is_warning(&event.level) {
+        bucket.warnings += 1;
+    }
+    if let Some(response_time) = response_time(event) {
+        bucket.response_time_sum += response_time;
+        bucket.response_time_count += 1;
+    }
+}
+
+// Totals for [from, to), optionally limited to some services. Buckets that only
+// partly overlap the range are counted in full.
+pub fn summary(from: DateTime<Utc>, to: DateTime<Utc>, services: &[String]) -> MetricsSummary {
+    let width = Duration::seconds(CONFIG.lock().unwrap().bucket_width as i64);
+    let range_start = bucket_start(from, width);
+    let wanted = |service: &str| services.is_empty() || services.iter().any(|s| s == service);
+
+    let mut per_service: Vec<(String, Bucket)> = {
+        let buckets = BUCKETS.lock().unwrap();
+        buckets
+            .iter()
+            .filter(|(service, _)| wanted(service))
+            .filter_map(|(service, series)| {
+                let mut total = Bucket::default();
+                for bucket in series.range(range_start..to).map(|(_, b)| b) {
+                    total.add(bucket);
+                }
+                (total.total > 0).then(|| (service.clone(), total))
+            })
+            .collect()
+    };
+    per_service.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut anomalies_by_service: HashMap<String, usize> = HashMap::new();
+    for anomaly in crate::ml::get_anomalies() {
+        let in_range = DateTime::parse_from_rfc3339(&anomaly.timestamp)
+            .map(|ts| ts.with_timezone(&Utc))
+            .is_ok_and(|ts| ts >= from && ts < to);
+        if in_range && wanted(&anomaly.event.service) {
+            *anomalies_by_service.entry(anomaly.event.service).or_insert(0) += 1;
+        }
+    }
+
+    let minutes = ((to - from).num_milliseconds() as f64 / 60_000.0).max(1.0 / 60.0);
+    let mut overall = Bucket::default();
+    let services = per_service
+        .into_iter()
+        .map(|(service, bucket)| {
+            overall.add(&bucket);
+            ServiceMetrics {
+                anomalies_detected: anomalies_by_service.get(&service).copied().unwrap_or(0),
+                service,
+                total_logs: bucket.total,
+                error_count: bucket.errors,
+                warning_count: bucket.warnings,
+                error_rate: ratio(bucket.errors, bucket.total),
+                logs_per_minute: bucket.total as f64 / minutes,
+                avg_response_time: avg_response_time(&bucket),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    MetricsSummary {
+        from,
+        to,
+        total_logs: overall.total,
+        error_count: overall.errors,
+        warning_count: overall.warnings,
+        error_rate: ratio(overall.errors, overall.total),
+        avg_response_time: avg_response_time(&overall),
+        active_services: services.len(),
+        anomalies_detected: anomalies_by_service.values().sum(),
+        services,
+    }
+}
+
+// Drops buckets older than the retention window
+pub async fn start_retention_task(config: StatsConfig) {
+    *CONFIG.lock().unwrap() = config.clone();
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+
+    loop {
+        interval.tick().await;
+        let cutoff = Utc::now() - Duration::seconds(config.retention as i64);
+        let mut buckets = BUCKETS.lock().unwrap();
+        for series in buckets.values_mut() {
+            *series = series.split_off(&cutoff);
+        }
+        buckets.retain(|_, series| !series.is_empty());
+    }
+}
+
+fn bucket_start(ts: DateTime<Utc>, width: Duration) -> DateTime<Utc> {
+    let width_ms = width.num_milliseconds().max(1);
+    let ms = ts.timestamp_millis();
+    DateTime::from_timestamp_millis(ms - ms.rem_euclid(width_ms)).unwrap_or(ts)
+}
+
+fn is_error(level: &str) -> bool {
+    matches!(
+        level.trim().to_ascii_uppercase().as_str(),
+        "ERROR" | "ERR" | "FATAL" | "CRITICAL" | "CRIT" | "ALERT" | "EMERG" | "EMERGENCY" | "PANIC" | "SEVERE"
+    )
+}
+
+fn is_warning(level: &str) -> bool {
+    matches!(level.trim().to_ascii_uppercase().as_str(), "WARN" | "WARNING")
+}
+
+fn response_time(event: &LogEvent) -> Option<f64> {
+    RESPONSE_TIME_FIELDS.iter().find_map(|field| {
+        let value = event.fields.get(*field)?;
+        value.as_f64().or_else(|| value.as_str()?.parse().ok())
+    })
+}
+
+fn avg_response_time(bucket: &Bucket) -> Option<f64> {
+    (bucket.response_time_count > 0).then(|| bucket.response_time_sum / bucket.response_time_count as f64)
+}
+
+fn ratio(part: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { part as f64 / total as f64 }
+}
@@ -0,0 +1,220 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::kafka::LogEvent;
+use crate::local_store::{LocalStore, LocalStoreConfig};
+use crate::opensearch::{OpenSearchClient, OpenSearchConfig};
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreBackend {
+    #[default]
+    OpenSearch,
+    // Embedded on-disk store for small deployments and CI; needs no external services
+    Local,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StoreBackend,
+    // Only used by the local backend; OpenSearch has its own section
+    pub local: LocalStoreConfig,
+}
+
+// A log ready to be stored. The ID is fixed up front so a retried document overwrites
+// itself instead of being stored twice.
+pub struct BulkDocument {
+    pub id: String,
+    pub event_time: DateTime<Utc>,
+    pub source: serde_json::Value,
+}
+
+impl BulkDocument {
+    pub fn new(event: &LogEvent) -> Self {
+        let event_time = event.event_timestamp();
+        let id = uuid::Uuid::new_v4().to_string();
+        Self {
+            source: json!({
+                // Sort tiebreaker, since sorting on `_id` is deprecated
+                "log_id": id,
+                "event_time": event_time.to_rfc3339(),
+                "ingest_time": event.ingest_time,
+                "level": event.level,
+                "message": event.message,
+                "service": event.service,
+                "trace_id": event.trace_id,
+                "fields": event.fields,
+                "@timestamp": event_time.to_rfc3339()
+            }),
+            id,
+            event_time,
+        }
+    }
+}
+
+pub enum BulkOutcome {
+    Stored,
+    // Rejected for now (e.g. 429 or a busy shard); worth sending again
+    Retry(String),
+    // Rejected for good, e.g. a mapping conflict
+    Failed(String),
+}
+
+pub enum BulkError {
+    // The request as a whole failed in a way that may pass on retry
+    Retryable(String),
+    Rejected(String),
+}
+
+// Conditions a log must meet; empty lists and None match everything
+#[derive(Debug, Default)]
+pub struct LogFilter {
+    // Any of these services / levels
+    pub services: Vec<String>,
+    pub levels: Vec<String>,
+    pub trace_id: Option<String>,
+    // Every word must appear in the message
+    pub text: Option<String>,
+    // Exact values of structured fields, by name without the `fields.` prefix
+    pub fields: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SortOrder {
+    // Newest first
+    #[default]
+    Desc,
+    Asc,
+}
+
+// Position after the last log of a page: its event time (epoch milliseconds) and ID,
+// which breaks ties between logs from the same millisecond
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cursor {
+    pub event_time: i64,
+    pub id: String,
+}
+
+impl Cursor {
+    // Opaque to clients, which hand it back unchanged
+    pub fn encode(&self) -> String {
+        format!("{}_{}", self.event_time, self.id)
+    }
+
+    pub fn decode(value: &str) -> Option<Self> {
+        let (event_time, id) = value.split_once('_')?;
+        Some(Self { event_time: event_time.parse().ok()?, id: id.to_string() })
+    }
+}
+
+pub struct SearchQuery {
+    pub filter: LogFilter,
+    // Event time range, end exclusive
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+    pub sort: SortOrder,
+    pub size: usize,
+    // Continue after this position instead of starting from the first match
+    pub after: Option<Cursor>,
+}
+
+pub struct SearchResults {
+    // Matches of the whole query, regardless of the cursor
+    pub total: u64,
+    pub logs: Vec<LogRecord>,
+    // Set when the page is full and there may be more
+    pub next_cursor: Option<Cursor>,
+}
+
+// A log as stored: its ID and the document built by `BulkDocument::new`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredLog {
+    #[serde(rename = "_id")]
+    pub id: String,
+    #[serde(rename = "_source")]
+    pub source: serde_json::Value,
+}
+
+// A log as returned by searches
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogRecord {
+    pub id: String,
+    pub event_time: String,
+    pub ingest_time: String,
+    pub level: String,
+    pub service: String,
+    pub message: String,
+    pub trace_id: Option<String>,
+    pub fields: HashMap<String, serde_json::Value>,
+}
+
+impl From<StoredLog> for LogRecord {
+    fn from(log: StoredLog) -> Self {
+        let mut record: LogRecord = serde_json::from_value(log.source).unwrap_or_default();
+        record.id = log.id;
+        record
+    }
+}
+
+pub struct AggregateQuery {
+    pub filter: LogFilter,
+    // Event time range, end exclusive
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub group_by: GroupBy,
+}
+
+pub enum GroupBy {
+    // The `size` most frequent values of a keyword field (level, service, trace_id or fields.*)
+    Field { field: String, size: usize },
+    // Event time buckets of this width, aligned to the epoch; empty buckets are included
+    Interval(chrono::Duration),
+}
+
+#[derive(Debug, Serialize)]
+pub struct Bucket {
+    pub key: String,
+    pub count: u64,
+}
+
+// Where logs are kept. Shared with handlers as `web::Data<dyn LogStore>` and with the bulk indexer.
+#[async_trait]
+pub trait LogStore: Send + Sync {
+    // Background setup and upkeep, spawned once at startup
+    async fn prepare(&self);
+
+    // A single log, written straight away. Ingestion goes through the bulk indexer instead.
+    #[allow(dead_code)]
+    async fn index(&self, doc: &BulkDocument) -> Result<(), String> {
+        match self.bulk_index(std::slice::from_ref(doc)).await {
+            Ok(outcomes) => match outcomes.into_iter().next() {
+                Some(BulkOutcome::Stored) => Ok(()),
+                Some(BulkOutcome::Retry(e) | BulkOutcome::Failed(e)) => Err(e),
+                None => Err("store returned no outcome".to_string()),
+            },
+            Err(BulkError::Retryable(e) | BulkError::Rejected(e)) => Err(e),
+        }
+    }
+
+    // Reports the outcome of each document, in order
+    async fn bulk_index(&self, docs: &[BulkDocument]) -> Result<Vec<BulkOutcome>, BulkError>;
+
+    async fn search(&self, query: &SearchQuery) -> Result<SearchResults, String>;
+
+    async fn aggregate(&self, query: &AggregateQuery) -> Result<Vec<Bucket>, String>;
+
+    // Removes logs with an event time in [start, end) and returns how many there were
+    async fn delete_range(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<u64, String>;
+}
+
+pub fn open(storage: &StorageConfig, opensearch: &OpenSearchConfig) -> Result<Arc<dyn LogStore>, String> {
+    Ok(match storage.backend {
+        StoreBackend::OpenSearch => Arc::new(OpenSearchClient::new(opensearch)?),
+        StoreBackend::Local => Arc::new(LocalStore::open(&storage.local)?),
+    })
+}
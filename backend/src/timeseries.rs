@@ -0,0 +1,343 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Mutex, LazyLock};
+use crate::api::AgentMetrics;
+
+// Raw samples are kept for `raw_retention`, then folded into fixed-width
+// rollups that are kept for `rollup_retention`. Durations are in seconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub raw_retention: u64,
+    pub rollup_width: u64,
+    pub rollup_retention: u64,
+    // Upper bound on points per series in one query response
+    pub max_points: usize,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            raw_retention: 6 * 3600,
+            rollup_width: 60,
+            rollup_retention: 7 * 86400,
+            max_points: 2000,
+        }
+    }
+}
+
+struct Sample {
+    timestamp: DateTime<Utc>,
+    values: HashMap<String, f64>,
+}
+
+// Aggregate of the raw values of one field over one rollup interval
+#[derive(Debug, Clone, Copy)]
+struct Rollup {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+    p95: f64,
+}
+
+#[derive(Default)]
+struct AgentSeries {
+    raw: VecDeque<Sample>,
+    // Rollup start -> field -> aggregate
+    rollups: BTreeMap<DateTime<Utc>, HashMap<String, Rollup>>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct Point {
+    pub timestamp: DateTime<Utc>,
+    pub count: u64,
+    pub avg: f64,
+    pub min: f64,
+    pub max: f64,
+    pub p95: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Series {
+    pub field: String,
+    pub points: Vec<Point>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SeriesQueryResult {
+    pub agent_id: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub step_secs: f64,
+    // "raw" when answered from raw samples, "rollup" when part of the range was already rolled up
+    pub source: &'static str,
+    pub series: Vec<Series>,
+}
+
+pub struct SeriesQuery {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub step: Option<Duration>,
+    // Exact field names, `prefix.*` wildcards or the groups cpu, memory, disk and network
+    pub fields: Vec<String>,
+}
+
+static CONFIG: LazyLock<Mutex<RetentionConfig>> = LazyLock::new(|| Mutex::new(RetentionConfig::default()));
+static SERIES: LazyLock<Mutex<HashMap<String, AgentSeries>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub fn record(agent_id: &str, metrics: &AgentMetrics) {
+    let timestamp = DateTime::parse_from_rfc3339(&metrics.timestamp)
+        .map(|ts| ts.with_timezone(&Utc))
+        .unwrap_or_else(|_| Utc::now());
+    let sample = Sample { timestamp, values: flatten(metrics) };
+
+    let mut series = SERIES.lock().unwrap();
+    let raw = &mut series.entry(agent_id.to_string()).or_default().raw;
+    // Spooled uploads can arrive late; keep samples ordered by their own timestamp
+    let pos = raw.partition_point(|s| s.timestamp <= timestamp);
+    raw.insert(pos, sample);
+}
+
+// Field name -> value for everything we chart from one metrics report
+fn flatten(metrics: &AgentMetrics) -> HashMap<String, f64> {
+    let mut values = HashMap::new();
+    values.insert("cpu_usage".to_string(), metrics.cpu_usage as f64);
+    values.insert("memory_usage".to_string(), metrics.memory_usage);
+    values.insert("network_rx".to_string(), metrics.network_rx as f64);
+    values.insert("network_tx".to_string(), metrics.network_tx as f64);
+
+    for disk in &metrics.disks {
+        let prefix = format!("disk.{}", disk.mount_point);
+        values.insert(format!("{}.usage_percent", prefix), disk.usage_percent);
+        values.insert(format!("{}.used_bytes", prefix), disk.used_bytes as f64);
+        values.insert(format!("{}.available_bytes", prefix), disk.available_bytes as f64);
+        if let Some(inodes_used) = disk.inodes_used {
+            values.insert(format!("{}.inodes_used", prefix), inodes_used as f64);
+        }
+    }
+    // Agents that predate per-disk stats only send usage percentages
+    if metrics.disks.is_empty() {
+        for (mount, usage) in &metrics.disk_usage {
+            values.insert(format!("disk.{}.usage_percent", mount), *usage);
+        }
+    }
+
+    let mut rx_rate = 0.0;
+    let mut tx_rate = 0.0;
+    for net in &metrics.networks {
+        let prefix = format!("network.{}", net.interface);
+        values.insert(format!("{}.rx_bytes_per_sec", prefix), net.rx_bytes_per_sec);
+        values.insert(format!("{}.tx_bytes_per_sec", prefix), net.tx_bytes_per_sec);
+        values.insert(format!("{}.rx_packets_per_sec", prefix), net.rx_packets_per_sec);
+        values.insert(format!("{}.tx_packets_per_sec", prefix), net.tx_packets_per_sec);
+        values.insert(format!("{}.rx_errors", prefix), net.rx_errors as f64);
+        values.insert(format!("{}.tx_errors", prefix), net.tx_errors as f64);
+        rx_rate += net.rx_bytes_per_sec;
+        tx_rate += net.tx_bytes_per_sec;
+    }
+    if !metrics.networks.is_empty() {
+        values.insert("network_rx_bytes_per_sec".to_string(), rx_rate);
+        values.insert("network_tx_bytes_per_sec".to_string(), tx_rate);
+    }
+
+    values
+}
+
+pub fn query(agent_id: &str, query: &SeriesQuery) -> Option<SeriesQueryResult> {
+    let config = CONFIG.lock().unwrap().clone();
+    let series = SERIES.lock().unwrap();
+    let agent = series.get(agent_id)?;
+
+    let range = query.to - query.from;
+    // Default to roughly 300 points, never finer than one second or more than max_points
+    let min_step = Duration::milliseconds((range.num_milliseconds() / config.max_points.max(1) as i64).max(1000));
+    let step = query.step.unwrap_or_else(|| Duration::milliseconds(range.num_milliseconds() / 300)).max(min_step);
+    let step_ms = step.num_milliseconds();
+    let bucket_of = |ts: DateTime<Utc>| {
+        let ms = ts.timestamp_millis();
+        DateTime::from_timestamp_millis(ms - ms.rem_euclid(step_ms)).unwrap_or(ts)
+    };
+
+    // Raw values per field and bucket, plus rollups for the part of the range already compacted
+    let mut raw_buckets: HashMap<&str, BTreeMap<DateTime<Utc>, Vec<f64>>> = HashMap::new();
+    let start = agent.raw.partition_point(|s| s.timestamp < query.from);
+    for sample in agent.raw.range(start..).take_while(|s| s.timestamp <= query.to) {
+        for (field, value) in &sample.values {
+            if matches_fields(field, &query.fields) {
+                raw_buckets.entry(field).or_default()
+                    .entry(bucket_of(sample.timestamp)).or_default()
+                    .push(*value);
+            }
+        }
+    }
+
+    let mut rollup_buckets: HashMap<&str, BTreeMap<DateTime<Utc>, Vec<Rollup>>> = HashMap::new();
+    for (start, fields) in agent.rollups.range(query.from..=query.to) {
+        for (field, rollup) in fields {
+            if matches_fields(field, &query.fields) {
+                rollup_buckets.entry(field).or_default()
+                    .entry(bucket_of(*start)).or_default()
+                    .push(*rollup);
+            }
+        }
+    }
+    let source = if rollup_buckets.is_empty() { "raw" } else { "rollup" };
+
+    let mut field_names: Vec<&str> = raw_buckets.keys().chain(rollup_buckets.keys()).copied().collect();
+    field_names.sort();
+    field_names.dedup();
+
+    let series = field_names
+        .into_iter()
+        .map(|field| {
+            let mut points: BTreeMap<DateTime<Utc>, Point> = BTreeMap::new();
+            if let Some(buckets) = rollup_buckets.get(field) {
+                for (bucket, rollups) in buckets {
+                    points.insert(*bucket, merge_rollups(*bucket, rollups));
+                }
+            }
+            if let Some(buckets) = raw_buckets.get(field) {
+                for (bucket, values) in buckets {
+                    let point = summarize(*bucket, values);
+                    points.entry(*bucket)
+                        .and_modify(|existing| *existing = combine(existing, &point))
+                        .or_insert(point);
+                }
+            }
+            Series { field: field.to_string(), points: points.into_values().collect() }
+        })
+        .collect();
+
+    Some(SeriesQueryResult {
+        agent_id: agent_id.to_string(),
+        from: query.from,
+        to: query.to,
+        step_secs: step_ms as f64 / 1000.0,
+        source,
+        series,
+    })
+}
+
+fn matches_fields(field: &str, wanted: &[String]) -> bool {
+    if wanted.is_empty() {
+        return true;
+    }
+    wanted.iter().any(|w| match w.as_str() {
+        "cpu" => field == "cpu_usage",
+        "memory" => field == "memory_usage",
+        "disk" => field.starts_with("disk."),
+        "network" => field.starts_with("network"),
+        w => match w.strip_suffix('*') {
+            Some(prefix) => field.starts_with(prefix),
+            None => field == w,
+        },
+    })
+}
+
+fn summarize(timestamp: DateTime<Utc>, values: &[f64]) -> Point {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    Point {
+        timestamp,
+        count: sorted.len() as u64,
+        avg: sorted.iter().sum::<f64>() / sorted.len() as f64,
+        min: sorted[0],
+        max: sorted[sorted.len() - 1],
+        p95: percentile(&sorted, 0.95),
+    }
+}
+
+// Nearest-rank percentile over sorted values
+fn percentile(sorted: &[f64], q: f64) -> f64 {
+    let rank = ((q * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len());
+    sorted[rank - 1]
+}
+
+// Rolled-up data no longer has the raw values, so the p95 of a merged bucket is
+// approximated by the largest p95 of its parts
+fn merge_rollups(timestamp: DateTime<Utc>, rollups: &[Rollup]) -> Point {
+    let count: u64 = rollups.iter().map(|r| r.count).sum();
+    Point {
+        timestamp,
+        count,
+        avg: rollups.iter().map(|r| r.sum).sum::<f64>() / count.max(1) as f64,
+        min: rollups.iter().map(|r| r.min).fold(f64::INFINITY, f64::min),
+        max: rollups.iter().map(|r| r.max).fold(f64::NEG_INFINITY, f64::max),
+        p95: rollups.iter().map(|r| r.p95).fold(f64::NEG_INFINITY, f64::max),
+    }
+}
+
+fn combine(a: &Point, b: &Point) -> Point {
+    let count = a.count + b.count;
+    Point {
+        timestamp: a.timestamp,
+        count,
+        avg: (a.avg * a.count as f64 + b.avg * b.count as f64) / count.max(1) as f64,
+        min: a.min.min(b.min),
+        max: a.max.max(b.max),
+        p95: a.p95.max(b.p95),
+    }
+}
+
+// Periodically folds expired raw samples into rollups and drops expired rollups
+pub async fn start_retention_task(config: RetentionConfig) {
+    *CONFIG.lock().unwrap() = config;
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+
+    loop {
+        interval.tick().await;
+        apply_retention();
+    }
+}
+
+fn apply_retention() {
+    let config = CONFIG.lock().unwrap().clone();
+    let now = Utc::now();
+    let raw_cutoff = now - Duration::seconds(config.raw_retention as i64);
+    let rollup_cutoff = now - Duration::seconds(config.rollup_retention as i64);
+    let width_ms = (config.rollup_width as i64 * 1000).max(1000);
+
+    let mut series = SERIES.lock().unwrap();
+    for agent in series.values_mut() {
+        let mut expired: BTreeMap<DateTime<Utc>, HashMap<String, Vec<f64>>> = BTreeMap::new();
+        while agent.raw.front().is_some_and(|s| s.timestamp < raw_cutoff) {
+            let sample = agent.raw.pop_front().unwrap();
+            let ms = sample.timestamp.timestamp_millis();
+            let start = DateTime::from_timestamp_millis(ms - ms.rem_euclid(width_ms)).unwrap_or(sample.timestamp);
+            let fields = expired.entry(start).or_default();
+            for (field, value) in sample.values {
+                fields.entry(field).or_default().push(value);
+            }
+        }
+
+        for (start, fields) in expired {
+            let bucket = agent.rollups.entry(start).or_default();
+            for (field, values) in fields {
+                let point = summarize(start, &values);
+                let rollup = Rollup {
+                    count: point.count,
+                    sum: point.avg * point.count as f64,
+                    min: point.min,
+                    max: point.max,
+                    p95: point.p95,
+                };
+                // A rollup interval can straddle two retention passes
+                bucket.entry(field)
+                    .and_modify(|existing| {
+                        existing.count += rollup.count;
+                        existing.sum += rollup.sum;
+                        existing.min = existing.min.min(rollup.min);
+                        existing.max = existing.max.max(rollup.max);
+                        existing.p95 = existing.p95.max(rollup.p95);
+                    })
+                    .or_insert(rollup);
+            }
+        }
+
+        agent.rollups = agent.rollups.split_off(&rollup_cutoff);
+    }
+
+    series.retain(|_, agent| !agent.raw.is_empty() || !agent.rollups.is_empty());
+}
@@ -0,0 +1,97 @@
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use openssl::nid::Nid;
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::{X509NameRef, X509};
+use serde::Deserialize;
+use std::any::Any;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    // PEM certificate chain and private key for the listener; TLS is off unless both are set
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    // CA used to verify client certificates; clients are not asked for one when unset
+    pub client_ca_file: Option<String>,
+    // Reject connections without a valid client certificate instead of just not trusting them
+    pub require_client_cert: bool,
+}
+
+// A client certificate that verified against the configured CA
+#[derive(Debug, Clone)]
+pub struct PeerCertificate {
+    pub subject: String,
+    pub common_name: Option<String>,
+}
+
+// None when TLS is not configured. Expects a config that passed `Config::validate`.
+pub fn build_acceptor(config: &TlsConfig) -> Result<Option<SslAcceptorBuilder>, String> {
+    let (Some(cert_file), Some(key_file)) = (&config.cert_file, &config.key_file) else {
+        return Ok(None);
+    };
+
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())
+        .map_err(|e| format!("failed to initialise TLS: {}", e))?;
+    builder.set_certificate_chain_file(cert_file)
+        .map_err(|e| format!("failed to load certificate {}: {}", cert_file, e))?;
+    builder.set_private_key_file(key_file, SslFiletype::PEM)
+        .map_err(|e| format!("failed to load private key {}: {}", key_file, e))?;
+    builder.check_private_key()
+        .map_err(|e| format!("{} does not match {}: {}", key_file, cert_file, e))?;
+
+    if let Some(ca_file) = &config.client_ca_file {
+        builder.set_ca_file(ca_file)
+            .map_err(|e| format!("failed to load client CA {}: {}", ca_file, e))?;
+        let ca_names = openssl::x509::X509Name::load_client_ca_file(ca_file)
+            .map_err(|e| format!("failed to read client CA names from {}: {}", ca_file, e))?;
+        builder.set_client_ca_list(ca_names);
+
+        let mut mode = SslVerifyMode::PEER;
+        if config.require_client_cert {
+            mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+        }
+        builder.set_verify(mode);
+    }
+
+    Ok(Some(builder))
+}
+
+// Connection hook that makes a verified client certificate available to every
+// request on the connection via `HttpRequest::conn_data::<PeerCertificate>()`
+pub fn capture_peer_certificate(connection: &dyn Any, data: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+    let ssl = stream.ssl();
+    // Only certificates that chained to the client CA identify anyone
+    if ssl.verify_result() != openssl::x509::X509VerifyResult::OK {
+        return;
+    }
+    if let Some(cert) = ssl.peer_certificate() {
+        data.insert(peer_certificate(&cert));
+    }
+}
+
+fn peer_certificate(cert: &X509) -> PeerCertificate {
+    let name = cert.subject_name();
+    PeerCertificate {
+        subject: format_name(name),
+        common_name: name.entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|entry| entry.data().to_string().ok()),
+    }
+}
+
+// "CN=agent,O=monx" rendering for error messages
+fn format_name(name: &X509NameRef) -> String {
+    name.entries()
+        .filter_map(|entry| {
+            let key = entry.object().nid().short_name().ok()?;
+            let value = entry.data().to_string().ok()?;
+            Some(format!("{}={}", key, value))
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
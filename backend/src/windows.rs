@@ -0,0 +1,260 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use std::collections::{BTreeMap, VecDeque};
+use crate::kafka::LogEvent;
+
+// Detectors see each service's events by event time. A service's watermark trails its newest
+// event by `allowed_lateness_secs`; events that arrive behind it are too late to be analyzed.
+// A quiet service's watermark follows the clock, at most `idle_timeout_secs` behind, so its
+// windows still close.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    pub allowed_lateness_secs: i64,
+    pub idle_timeout_secs: i64,
+    // Events kept per service whatever the detectors' time ranges, oldest dropped first
+    pub max_events: usize,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            allowed_lateness_secs: 60,
+            idle_timeout_secs: 300,
+            max_events: 100_000,
+        }
+    }
+}
+
+// A service's recent events, ordered by event time
+#[derive(Default)]
+pub struct EventLog {
+    events: VecDeque<LogEvent>,
+    newest: Option<DateTime<Utc>>,
+    pub late_events: u64,
+}
+
+impl EventLog {
+    pub fn watermark(&self, config: &WindowConfig, now: DateTime<Utc>) -> DateTime<Utc> {
+        let idle = now - Duration::seconds(config.idle_timeout_secs);
+        // Clocks running ahead must not make every other event late
+        match self.newest {
+            Some(newest) => (newest.min(now) - Duration::seconds(config.allowed_lateness_secs)).max(idle),
+            None => idle,
+        }
+    }
+
+    // False if the event is behind the watermark
+    pub fn insert(&mut self, event: &LogEvent, config: &WindowConfig, now: DateTime<Utc>) -> bool {
+        let time = event.event_timestamp();
+        if time < self.watermark(config, now) {
+            self.late_events += 1;
+            return false;
+        }
+        self.newest = Some(self.newest.map_or(time, |newest| newest.max(time)));
+        let pos = self.events.partition_point(|e| e.event_timestamp() <= time);
+        self.events.insert(pos, event.clone());
+        if self.events.len() > config.max_events {
+            self.events.pop_front();
+        }
+        true
+    }
+
+    // Drops events from before `start`
+    pub fn prune(&mut self, start: DateTime<Utc>) {
+        let end = self.events.partition_point(|e| e.event_timestamp() < start);
+        self.events.drain(..end);
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn view(&mut self, watermark: DateTime<Utc>) -> EventWindow<'_> {
+        EventWindow { events: self.events.make_contiguous(), watermark }
+    }
+}
+
+// What a detector sees of a service when it runs: events up to the watermark are final,
+// while later ones may still be joined by late arrivals
+pub struct EventWindow<'a> {
+    events: &'a [LogEvent],
+    pub watermark: DateTime<Utc>,
+}
+
+impl EventWindow<'_> {
+    // Events with an event time in [start, end)
+    pub fn between(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> &[LogEvent] {
+        let from = self.events.partition_point(|e| e.event_timestamp() < start);
+        let to = self.events.partition_point(|e| e.event_timestamp() < end);
+        &self.events[from..to.max(from)]
+    }
+
+    // The sliding window of this length ending at the watermark
+    pub fn last(&self, length: Duration) -> &[LogEvent] {
+        self.between(self.watermark - length, self.watermark)
+    }
+}
+
+// Fixed, non-overlapping event time windows that a detector summarizes as events arrive
+// and reads back once the watermark has passed them
+pub struct TumblingWindows<T> {
+    size: i64,
+    // Open windows by start time (epoch seconds)
+    open: BTreeMap<i64, T>,
+    // Start of the oldest window not yet closed
+    next: Option<i64>,
+}
+
+impl<T: Default> TumblingWindows<T> {
+    pub fn new(size_secs: i64) -> Self {
+        Self { size: size_secs, open: BTreeMap::new(), next: None }
+    }
+
+    fn start(&self, time: DateTime<Utc>) -> i64 {
+        time.timestamp().div_euclid(self.size) * self.size
+    }
+
+    // The window the time falls in, or None if it has already closed. Before the first close
+    // every window is open, since the event log has already turned away events behind the
+    // watermark; the first event seen need not be the earliest.
+    pub fn get(&mut self, time: DateTime<Utc>) -> Option<&mut T> {
+        let start = self.start(time);
+        if self.next.is_some_and(|next| start < next) {
+            return None;
+        }
+        Some(self.open.entry(start).or_default())
+    }
+
+    // Closes every window that ends by the watermark, oldest first, with empty windows for
+    // gaps. Only the last `max_windows` are returned after a long gap.
+    pub fn close(&mut self, watermark: DateTime<Utc>, max_windows: usize) -> Vec<(i64, T)> {
+        let Some(mut next) = self.next.or_else(|| self.open.keys().next().copied()) else { return Vec::new() };
+        let end = watermark.timestamp().div_euclid(self.size) * self.size;
+        next = next.max(end - self.size * max_windows as i64);
+
+        let mut closed = Vec::new();
+        while next < end {
+            closed.push((next, self.open.remove(&next).unwrap_or_default()));
+            next += self.size;
+        }
+        self.next = Some(next);
+        self.open.retain(|&start, _| start >= next);
+        closed
+    }
+
+    pub fn open_windows(&self) -> usize {
+        self.open.len()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::collections::HashMap;
+
+    // On a minute boundary
+    const BASE: i64 = 1_699_999_980;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(BASE + secs, 0).unwrap()
+    }
+
+    // Closed windows as (start, value), with starts relative to BASE
+    fn close(windows: &mut TumblingWindows<u32>, watermark: i64, max_windows: usize) -> Vec<(i64, u32)> {
+        windows.close(at(watermark), max_windows).into_iter().map(|(start, value)| (start - BASE, value)).collect()
+    }
+
+    fn event(secs: i64) -> LogEvent {
+        LogEvent {
+            event_time: at(secs).to_rfc3339(),
+            ingest_time: at(secs).to_rfc3339(),
+            level: "INFO".to_string(),
+            message: format!("event at {}", secs),
+            service: "api".to_string(),
+            trace_id: None,
+            fields: HashMap::new(),
+        }
+    }
+
+    fn times(log: &mut EventLog) -> Vec<i64> {
+        let view = log.view(at(0));
+        view.events.iter().map(|e| e.event_timestamp().timestamp() - BASE).collect()
+    }
+
+    #[test]
+    fn watermark_trails_the_newest_event_or_follows_the_clock() {
+        let config = WindowConfig::default();
+        let mut log = EventLog::default();
+        assert_eq!(log.watermark(&config, at(1000)), at(700));
+
+        assert!(log.insert(&event(900), &config, at(1000)));
+        assert_eq!(log.watermark(&config, at(1000)), at(840));
+        // Idle for longer than the timeout
+        assert_eq!(log.watermark(&config, at(2000)), at(1700));
+        // A clock ahead of ours counts as now
+        assert!(log.insert(&event(5000), &config, at(1000)));
+        assert_eq!(log.watermark(&config, at(1000)), at(940));
+    }
+
+    #[test]
+    fn rejects_events_behind_the_watermark() {
+        let config = WindowConfig::default();
+        let mut log = EventLog::default();
+        assert!(log.insert(&event(1000), &config, at(1000)));
+        assert!(log.insert(&event(940), &config, at(1000)));
+        assert!(!log.insert(&event(939), &config, at(1000)));
+        assert!(log.insert(&event(990), &config, at(1000)));
+
+        assert_eq!(log.late_events, 1);
+        assert_eq!(times(&mut log), [940, 990, 1000]);
+    }
+
+    #[test]
+    fn keeps_at_most_max_events() {
+        let config = WindowConfig { max_events: 2, ..WindowConfig::default() };
+        let mut log = EventLog::default();
+        for secs in [10, 30, 20] {
+            log.insert(&event(secs), &config, at(30));
+        }
+        assert_eq!(times(&mut log), [20, 30]);
+    }
+
+    #[test]
+    fn keeps_an_earlier_event_that_arrives_second() {
+        let mut windows = TumblingWindows::<u32>::new(60);
+        *windows.get(at(130)).unwrap() += 1;
+        // Within the allowed lateness, so the log accepted it
+        *windows.get(at(100)).unwrap() += 1;
+
+        assert_eq!(close(&mut windows, 200, 10), [(60, 1), (120, 1)]);
+    }
+
+    #[test]
+    fn closed_windows_take_no_more_events() {
+        let mut windows = TumblingWindows::<u32>::new(60);
+        *windows.get(at(0)).unwrap() += 1;
+        assert_eq!(close(&mut windows, 60, 10), [(0, 1)]);
+        assert!(windows.get(at(59)).is_none());
+        assert!(windows.get(at(60)).is_some());
+        assert!(close(&mut windows, 119, 10).is_empty());
+        assert_eq!(windows.open_windows(), 1);
+    }
+
+    #[test]
+    fn fills_gaps_with_empty_windows_up_to_max_windows() {
+        let mut windows = TumblingWindows::<u32>::new(60);
+        assert!(close(&mut windows, 600, 10).is_empty());
+
+        *windows.get(at(0)).unwrap() += 1;
+        *windows.get(at(150)).unwrap() += 2;
+        assert_eq!(close(&mut windows, 200, 10), [(0, 1), (60, 0), (120, 2)]);
+
+        // After a long gap only the newest windows are returned
+        *windows.get(at(180)).unwrap() += 3;
+        assert_eq!(close(&mut windows, 900, 3), [(720, 0), (780, 0), (840, 0)]);
+        assert_eq!(windows.open_windows(), 0);
+    }
+}
@@ -6,6 +6,13 @@ import AnomaliesPanel from './components/AnomaliesPanel';
 import LogsTable from './components/LogsTable';
 import axios from 'axios';
 
+// API token (JWT with at least the viewer role). Set it with
+// localStorage.setItem('monx_token', '<token>') or REACT_APP_API_TOKEN at build time.
+const apiToken = localStorage.getItem('monx_token') || process.env.REACT_APP_API_TOKEN || '';
+if (apiToken) {
+  axios.defaults.headers.common['Authorization'] = `Bearer ${apiToken}`;
+}
+
 const theme = createTheme({
   palette: {
     mode: 'dark',
@@ -26,7 +33,9 @@ function App() {
     fetchLogs();
 
     // Setup WebSocket for real-time updates
-    const ws = new WebSocket('ws://localhost:8080/ws');
+    // Browsers cannot set headers on WebSocket requests, so the token goes in the query string
+    const wsQuery = apiToken ? `?access_token=${encodeURIComponent(apiToken)}` : '';
+    const ws = new WebSocket(`ws://localhost:8080/ws${wsQuery}`);
     ws.onmessage = (event) => {
       const data = JSON.parse(event.data);
       if (data.type === 'anomalies') {
@@ -67,7 +76,7 @@ function App() {
   const fetchLogs = async () => {
     try {
       const response = await axios.get('/api/logs?size=100');
-      setLogs(response.data.data?.hits?.hits || []);
+      setLogs(response.data.data?.logs || []);
     } catch (error) {
       console.error('Error fetching logs:', error);
     }
@@ -46,28 +46,24 @@ const LogsTable = ({ logs }) => {
         </TableHead>
         <TableBody>
           {logs.slice(0, 50).map((log, index) => {
-            const source = log._source || {};
             return (
-              <TableRow key={index} hover>
+              <TableRow key={log.id || index} hover>
                 <TableCell>
                   <Typography variant="caption">
-                    {source.timestamp ? 
-                      new Date(source.timestamp).toLocaleString() : 
-                      new Date(source['@timestamp']).toLocaleString()
-                    }
+                    {new Date(log.event_time).toLocaleString()}
                   </Typography>
                 </TableCell>
                 <TableCell>
                   <Chip
-                    label={source.level || 'INFO'}
+                    label={log.level || 'INFO'}
                     size="small"
-                    color={getLevelColor(source.level)}
+                    color={getLevelColor(log.level)}
                     variant="outlined"
                   />
                 </TableCell>
                 <TableCell>
                   <Typography variant="body2">
-                    {source.service || 'unknown'}
+                    {log.service || 'unknown'}
                   </Typography>
                 </TableCell>
                 <TableCell>
@@ -80,12 +76,12 @@ const LogsTable = ({ logs }) => {
                       whiteSpace: 'nowrap'
                     }}
                   >
-                    {source.message || 'No message'}
+                    {log.message || 'No message'}
                   </Typography>
                 </TableCell>
                 <TableCell>
                   <Typography variant="caption" color="textSecondary">
-                    {source.trace_id || '-'}
+                    {log.trace_id || '-'}
                   </Typography>
                 </TableCell>
               </TableRow>